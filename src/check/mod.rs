@@ -0,0 +1,169 @@
+// Check verifies that intake can run with its configuration without starting it:
+// the source accepts replication connections and is set up for logical decoding,
+// and every directory and destination intake writes to accepts writes. Checks are
+// independent so the report lists every problem at once.
+
+use crate::config::Config;
+use crate::events::output;
+use crate::source;
+use crate::storage::{self, Expeditor};
+use std::fmt;
+use std::path::Path;
+use uuid::Uuid;
+
+// Outcome of a check, with what was verified when it passed or why it failed.
+pub(crate) struct Outcome {
+    pub name: String,
+    pub result: Result<String, String>,
+}
+
+impl Outcome {
+    pub(crate) fn new(name: &str, result: Result<String, String>) -> Outcome {
+        Outcome {
+            name: name.to_string(),
+            result,
+        }
+    }
+}
+
+pub(crate) struct Report {
+    pub outcomes: Vec<Outcome>,
+}
+
+impl Report {
+    pub(crate) fn passed(&self) -> bool {
+        self.outcomes.iter().all(|outcome| outcome.result.is_ok())
+    }
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let width = self
+            .outcomes
+            .iter()
+            .map(|outcome| outcome.name.len())
+            .max()
+            .unwrap_or(0);
+
+        for outcome in self.outcomes.iter() {
+            let (status, detail) = match outcome.result.as_ref() {
+                Ok(detail) => ("PASS", detail),
+                Err(reason) => ("FAIL", reason),
+            };
+            writeln!(
+                f,
+                "{}  {:width$}  {}",
+                status,
+                outcome.name,
+                detail,
+                width = width
+            )?;
+        }
+
+        Ok(())
+    }
+}
+
+pub(crate) async fn run(config: &Config) -> Report {
+    let mut outcomes = source::check(&config.source).await;
+
+    outcomes.push(Outcome::new(
+        "output.directory",
+        directory(&config.output.directory),
+    ));
+
+    if let Some(settings) = config.storage.as_ref() {
+        outcomes.push(Outcome::new(
+            "storage.queue.directory",
+            directory(&settings.queue().directory),
+        ));
+
+        let result = match storage::initialize(Some(settings)).await {
+            Ok(Some(expeditor)) => probe(expeditor.as_ref()).await,
+            Ok(None) => Err("storage is not configured".into()),
+            Err(e) => Err(e.to_string()),
+        };
+        outcomes.push(Outcome::new("storage.destination", result));
+    }
+
+    if let Some(lake) = config.lake.as_ref() {
+        outcomes.push(Outcome::new("lake.journal", directory(lake.journal())));
+    }
+
+    Report { outcomes }
+}
+
+// Create the directory if needed and write a file to it.
+fn directory(path: &Path) -> Result<String, String> {
+    let probe = path.join(format!(".intake-check-{}", Uuid::new_v4()));
+
+    std::fs::create_dir_all(path)
+        .and_then(|_| output::write(&probe, b"intake"))
+        .and_then(|_| std::fs::remove_file(&probe))
+        .map(|_| format!("{:?} is writable", path))
+        .map_err(|e| format!("{:?} is not writable: {}", path, e))
+}
+
+// Write, read and delete an object at the destination.
+async fn probe(expeditor: &dyn Expeditor) -> Result<String, String> {
+    let key = format!(".intake-check-{}", Uuid::new_v4());
+    let content = b"intake".to_vec();
+
+    expeditor
+        .put(&key, content.clone())
+        .await
+        .map_err(|e| format!("could not write to {}: {}", expeditor.location(), e))?;
+
+    let read = expeditor.get(&key).await;
+    let deleted = expeditor.delete(&key).await;
+
+    match read {
+        Ok(Some(read)) if read == content => {}
+        Ok(_) => {
+            return Err(format!(
+                "{} returned a different object",
+                expeditor.location()
+            ))
+        }
+        Err(e) => {
+            return Err(format!(
+                "could not read from {}: {}",
+                expeditor.location(),
+                e
+            ))
+        }
+    }
+
+    deleted.map_err(|e| format!("could not delete from {}: {}", expeditor.location(), e))?;
+    Ok(format!("{} is writable", expeditor.location()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Outcome, Report};
+
+    #[tokio::test]
+    async fn writable_directories_and_destinations() {
+        let root = std::env::temp_dir().join(format!("intake-check-{}", uuid::Uuid::new_v4()));
+
+        let report = Report {
+            outcomes: vec![
+                Outcome::new("directory", super::directory(&root.join("output"))),
+                Outcome::new(
+                    "destination",
+                    super::probe(crate::storage::local(&root).as_ref()).await,
+                ),
+            ],
+        };
+        assert!(report.passed(), "{}", report);
+        assert_eq!(std::fs::read_dir(root.join("output")).unwrap().count(), 0);
+
+        let failed = Report {
+            outcomes: vec![Outcome::new("wal_level", Err("replica".into()))],
+        };
+        assert!(!failed.passed());
+        assert_eq!(failed.to_string(), "FAIL  wal_level  replica\n");
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,509 @@
+// Compaction rewrites the small files written for a table into larger ones. Tables
+// with little traffic close many small segments, which slows down engines that
+// read the output prefix directly.
+//
+// Files are found through the manifests and grouped by table, directory and schema
+// so a compacted file belongs to the same partition as its inputs and can be read
+// with the same schema. Each group is rewritten into files of about `target_bytes`,
+// uploaded with the Expeditor, or to the output directory when no storage is
+// configured, then published in a new manifest whose entries list the files they
+// replace. Inputs are removed once they've been replaced for longer than `retention`,
+// which gives loaders that already read the previous manifests time to finish.
+//
+//   compaction:
+//     target_bytes: 134217728
+//     min_age: 300       # seconds since a file was closed before it's compacted
+//     retention: 3600    # seconds replaced files are kept before they're removed
+
+use crate::config::Config;
+use crate::events::manifest::{self, Entry};
+use crate::events::output::{self, Output};
+use crate::events::provenance;
+use crate::storage::{self, checksum, Expeditor};
+use arrow::datatypes::SchemaRef;
+use arrow::record_batch::RecordBatch;
+use chrono::{DateTime, Duration, Utc};
+use parquet::format::KeyValue;
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+use std::sync::Arc;
+use thiserror::Error;
+use uuid::Uuid;
+
+#[derive(Error, Debug)]
+pub(crate) enum Error {
+    #[error("configuration error: `{0}`")]
+    ConfigError(String),
+
+    #[error("storage error: `{0}`")]
+    StorageError(String),
+
+    #[error("format error: `{0}`")]
+    FormatError(String),
+
+    #[error("file error: `{0}`")]
+    FileError(String),
+}
+
+impl From<storage::Error> for Error {
+    fn from(e: storage::Error) -> Self {
+        Error::StorageError(e.to_string())
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        Error::FileError(e.to_string())
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(e: serde_json::Error) -> Self {
+        Error::FormatError(e.to_string())
+    }
+}
+
+impl From<parquet::errors::ParquetError> for Error {
+    fn from(e: parquet::errors::ParquetError) -> Self {
+        Error::FormatError(e.to_string())
+    }
+}
+
+impl From<arrow::error::ArrowError> for Error {
+    fn from(e: arrow::error::ArrowError) -> Self {
+        Error::FormatError(e.to_string())
+    }
+}
+
+pub(crate) struct Compaction {
+    expeditor: Arc<dyn Expeditor>,
+    output: Output,
+    // Key prefix of the manifests.
+    manifests: String,
+    target_bytes: u64,
+    min_age: Duration,
+    retention: Duration,
+}
+
+// What a compaction did.
+#[derive(Debug, Default)]
+pub(crate) struct Summary {
+    pub written: usize,
+    pub replaced: usize,
+    pub removed: usize,
+}
+
+// Return a new Compaction for the whole configuration, as it needs to know where
+// files are written and where they're shipped.
+pub(crate) async fn new(config: &Config) -> Result<Compaction, Error> {
+    // Lake tables reference the files by their location and would lose the inputs.
+    if config.lake.is_some() {
+        return Err(Error::ConfigError(
+            "compaction can't rewrite files that are registered in a lake".into(),
+        ));
+    }
+
+    let output = output::new(&config.output);
+    let expeditor = match storage::initialize(config.storage.as_ref()).await? {
+        Some(expeditor) => expeditor,
+        None => storage::local(output.directory()),
+    };
+
+    let manifest = manifest::new(output.directory(), &config.output.manifest);
+    let manifests = output
+        .relative(manifest.directory())
+        .to_string_lossy()
+        .to_string();
+
+    Ok(Compaction {
+        expeditor,
+        manifests,
+        output,
+        target_bytes: config.compaction.target_bytes,
+        min_age: Duration::seconds(config.compaction.min_age as i64),
+        retention: Duration::seconds(config.compaction.retention as i64),
+    })
+}
+
+impl Compaction {
+    pub(crate) async fn run(&self) -> Result<Summary, Error> {
+        let mut summary = Summary::default();
+        let now = Utc::now();
+        let entries = self.entries().await?;
+
+        let mut replaced: HashMap<&str, DateTime<Utc>> = HashMap::new();
+        for entry in entries.iter() {
+            for path in entry.replaces.iter() {
+                replaced.insert(path.as_str(), closed_at(entry));
+            }
+        }
+
+        for (path, at) in replaced.iter() {
+            if now - *at >= self.retention && self.expeditor.exists(path).await? {
+                self.expeditor.delete(path).await?;
+                summary.removed += 1;
+            }
+        }
+
+        let mut groups: BTreeMap<(&str, &str, &str), Vec<&Entry>> = BTreeMap::new();
+        for entry in entries.iter() {
+            if replaced.contains_key(entry.path.as_str())
+                || entry.bytes >= self.target_bytes
+                || now - closed_at(entry) < self.min_age
+            {
+                continue;
+            }
+
+            let directory = entry
+                .path
+                .rsplit_once('/')
+                .map(|(dir, _)| dir)
+                .unwrap_or("");
+            groups
+                .entry((
+                    entry.table.as_str(),
+                    directory,
+                    entry.schema_fingerprint.as_str(),
+                ))
+                .or_default()
+                .push(entry);
+        }
+
+        let mut written = Vec::new();
+        for ((table, directory, _), mut group) in groups.into_iter() {
+            group.sort_by_key(|entry| closed_at(entry));
+
+            for files in bins(&group, self.target_bytes) {
+                match self.compact(directory, &files).await {
+                    Ok(entry) => {
+                        summary.written += 1;
+                        summary.replaced += files.len();
+                        written.push(entry);
+                    }
+                    Err(e) => log::warn!(
+                        "Could not compact {} files of {}: {}",
+                        files.len(),
+                        table,
+                        e
+                    ),
+                }
+            }
+        }
+
+        if !written.is_empty() {
+            let mut content = Vec::new();
+            for entry in written.iter() {
+                serde_json::to_writer(&mut content, entry)?;
+                content.push(b'\n');
+            }
+
+            let key = format!(
+                "{}/manifest-{}-{}.jsonl",
+                self.manifests,
+                now.format("%Y%m%dT%H%M%SZ"),
+                Uuid::new_v4().as_hyphenated()
+            );
+            self.expeditor.put(&key, content).await?;
+        }
+
+        Ok(summary)
+    }
+
+    // Return the entries of every manifest.
+    async fn entries(&self) -> Result<Vec<Entry>, Error> {
+        let mut entries = Vec::new();
+
+        for key in self.expeditor.list(&format!("{}/", self.manifests)).await? {
+            if !key.ends_with(".jsonl") {
+                continue;
+            }
+
+            let content = match self.expeditor.get(&key).await? {
+                Some(content) => content,
+                None => continue,
+            };
+
+            for line in content.split(|byte| *byte == b'\n') {
+                if line.is_empty() {
+                    continue;
+                }
+
+                match serde_json::from_slice(line) {
+                    Ok(entry) => entries.push(entry),
+                    Err(e) => log::warn!("Ignoring invalid entry in {}: {}", key, e),
+                }
+            }
+        }
+
+        Ok(entries)
+    }
+
+    // Rewrite the files into a single file in directory and return its manifest entry.
+    async fn compact(&self, directory: &str, files: &[&Entry]) -> Result<Entry, Error> {
+        use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+
+        let mut schema: Option<SchemaRef> = None;
+        let mut metadata: Vec<Vec<KeyValue>> = Vec::new();
+        let mut batches: Vec<RecordBatch> = Vec::new();
+
+        for file in files.iter() {
+            let content = self.expeditor.get(&file.path).await?.ok_or_else(|| {
+                Error::StorageError(format!("{} is not available yet", file.path))
+            })?;
+
+            let builder = ParquetRecordBatchReaderBuilder::try_new(bytes::Bytes::from(content))?;
+            metadata.push(
+                builder
+                    .metadata()
+                    .file_metadata()
+                    .key_value_metadata()
+                    .cloned()
+                    .unwrap_or_default(),
+            );
+            schema.get_or_insert_with(|| builder.schema().clone());
+
+            for batch in builder.build()? {
+                batches.push(batch?);
+            }
+        }
+
+        let schema = schema.ok_or_else(|| Error::FormatError("nothing to compact".into()))?;
+        let key = match directory {
+            "" => format!("compacted-{}.parquet", Uuid::new_v4()),
+            directory => format!("{}/compacted-{}.parquet", directory, Uuid::new_v4()),
+        };
+
+        let temporary = output::temporary(&self.output.directory().join(&key));
+        let written = write(&temporary, schema, merge(&metadata), &batches);
+        let result = match written {
+            Ok((rows, checksum)) => self
+                .expeditor
+                .upload(&temporary, &key, &checksum)
+                .await
+                .map(|_| (rows, checksum))
+                .map_err(Error::from),
+            Err(e) => Err(e),
+        };
+        let bytes = std::fs::metadata(&temporary).map(|m| m.len());
+        let _ = std::fs::remove_file(&temporary);
+        let (rows, checksum) = result?;
+
+        let start = files
+            .iter()
+            .filter_map(|file| provenance::parse_lsn(&file.lsn_start))
+            .min();
+        let end = files
+            .iter()
+            .filter_map(|file| provenance::parse_lsn(&file.lsn_end))
+            .max();
+
+        Ok(Entry {
+            path: key,
+            table: files[0].table.clone(),
+            rows,
+            bytes: bytes?,
+            lsn_start: provenance::lsn(start.unwrap_or_default()),
+            lsn_end: provenance::lsn(end.unwrap_or_default()),
+            checksum: format!("sha256:{}", checksum.sha256_hex()),
+            crc32c: checksum.crc32c_hex(),
+            schema_fingerprint: files[0].schema_fingerprint.clone(),
+            closed_at: Utc::now().to_rfc3339(),
+            replaces: files.iter().map(|file| file.path.clone()).collect(),
+        })
+    }
+}
+
+// Split files in consecutive groups of about `target` bytes. Groups of a single
+// file are left out as there's nothing to compact.
+fn bins<'a>(files: &[&'a Entry], target: u64) -> Vec<Vec<&'a Entry>> {
+    let mut bins = Vec::new();
+    let mut current: Vec<&'a Entry> = Vec::new();
+    let mut bytes = 0;
+
+    for file in files.iter() {
+        if !current.is_empty() && bytes + file.bytes > target {
+            bins.push(std::mem::take(&mut current));
+            bytes = 0;
+        }
+
+        bytes += file.bytes;
+        current.push(*file);
+    }
+    bins.push(current);
+
+    bins.retain(|bin| bin.len() > 1);
+    bins
+}
+
+// Write the batches as a parquet file at path and return its rows and checksum.
+fn write(
+    path: &Path,
+    schema: SchemaRef,
+    metadata: Vec<KeyValue>,
+    batches: &[RecordBatch],
+) -> Result<(i64, checksum::Checksum), Error> {
+    use parquet::arrow::{arrow_writer::ArrowWriterOptions, ArrowWriter};
+    use parquet::file::properties::WriterProperties;
+
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+
+    let mut file = checksum::Writer::new(std::fs::File::create(path)?);
+    let options = ArrowWriterOptions::new()
+        .with_properties(
+            WriterProperties::builder()
+                .set_key_value_metadata(Some(metadata))
+                .build(),
+        )
+        .with_skip_arrow_metadata(true);
+
+    let mut writer = ArrowWriter::try_new_with_options(&mut file, schema, options)?;
+    for batch in batches.iter() {
+        writer.write(batch)?;
+    }
+    let closed = writer.close()?;
+    file.get_ref().sync_all()?;
+
+    Ok((closed.num_rows, file.checksum()))
+}
+
+// Merge the provenance of the inputs: ranges cover every input, row counts are
+// summed and everything else is taken from the first input.
+fn merge(inputs: &[Vec<KeyValue>]) -> Vec<KeyValue> {
+    let mut merged: BTreeMap<String, String> = BTreeMap::new();
+
+    for input in inputs.iter() {
+        for kv in input.iter() {
+            let value = match kv.value.as_ref() {
+                Some(value) => value.clone(),
+                None => continue,
+            };
+
+            let current = match merged.get(&kv.key) {
+                Some(current) => current,
+                None => {
+                    merged.insert(kv.key.clone(), value);
+                    continue;
+                }
+            };
+
+            let value = match kv.key.as_str() {
+                "intake.lsn.min" | "intake.lsn.max" => {
+                    let (current, new) = (
+                        provenance::parse_lsn(current),
+                        provenance::parse_lsn(&value),
+                    );
+                    match (current, new, kv.key.ends_with("min")) {
+                        (Some(current), Some(new), true) => provenance::lsn(current.min(new)),
+                        (Some(current), Some(new), false) => provenance::lsn(current.max(new)),
+                        _ => continue,
+                    }
+                }
+                "intake.commit_timestamp.min" => current.clone().min(value),
+                "intake.commit_timestamp.max" => current.clone().max(value),
+                key if key.starts_with("intake.rows.") => {
+                    let sum =
+                        current.parse::<u64>().unwrap_or(0) + value.parse::<u64>().unwrap_or(0);
+                    sum.to_string()
+                }
+                _ => continue,
+            };
+            merged.insert(kv.key.clone(), value);
+        }
+    }
+
+    merged.insert("intake.compaction.inputs".into(), inputs.len().to_string());
+    merged
+        .into_iter()
+        .map(|(key, value)| KeyValue::new(key, value))
+        .collect()
+}
+
+// Parse an LSN formatted the way PostgreSQL does, `16/B374D848`.
+fn closed_at(entry: &Entry) -> DateTime<Utc> {
+    DateTime::parse_from_rfc3339(&entry.closed_at)
+        .map(|at| at.with_timezone(&Utc))
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::events::manifest::Entry;
+    use arrow::array::Int64Array;
+    use arrow::datatypes::{DataType, Field, Schema};
+    use arrow::record_batch::RecordBatch;
+    use std::sync::Arc;
+
+    fn entry(root: &std::path::Path, path: &str, ids: Vec<i64>) -> Entry {
+        let schema = Arc::new(Schema::new(vec![Field::new("id", DataType::Int64, true)]));
+        let batch =
+            RecordBatch::try_new(schema.clone(), vec![Arc::new(Int64Array::from(ids))]).unwrap();
+        let metadata = vec![
+            parquet::format::KeyValue::new("intake.lsn.min".into(), "0/10".to_string()),
+            parquet::format::KeyValue::new("intake.rows.insert".into(), "2".to_string()),
+        ];
+        let (rows, _) = super::write(&root.join(path), schema, metadata, &[batch]).unwrap();
+
+        Entry {
+            path: path.into(),
+            table: "public.users".into(),
+            rows,
+            bytes: std::fs::metadata(root.join(path)).unwrap().len(),
+            lsn_start: "0/10".into(),
+            lsn_end: "0/20".into(),
+            checksum: String::new(),
+            crc32c: String::new(),
+            schema_fingerprint: "fingerprint".into(),
+            closed_at: "2022-03-07T09:30:00+00:00".into(),
+            replaces: Vec::new(),
+        }
+    }
+
+    #[tokio::test]
+    async fn compacts_and_removes_small_files() {
+        let root = std::env::temp_dir().join(format!("intake-compaction-{}", uuid::Uuid::new_v4()));
+        let entries = vec![
+            entry(&root, "public/users/a.parquet", vec![1, 2]),
+            entry(&root, "public/users/b.parquet", vec![3, 4]),
+        ];
+
+        let mut manifest = Vec::new();
+        for entry in entries.iter() {
+            serde_json::to_writer(&mut manifest, entry).unwrap();
+            manifest.push(b'\n');
+        }
+        std::fs::create_dir_all(root.join("_manifests")).unwrap();
+        std::fs::write(root.join("_manifests/manifest-a.jsonl"), manifest).unwrap();
+
+        let config = crate::config::parse(&format!(
+            "source:\n  driver: postgresql\n  url: postgres://localhost\n  state: state\n\
+            output:\n  directory: {}\ncompaction:\n  retention: 0",
+            root.to_string_lossy()
+        ))
+        .unwrap();
+        let compaction = super::new(&config).await.unwrap();
+
+        let summary = compaction.run().await.unwrap();
+        assert_eq!(
+            (summary.written, summary.replaced, summary.removed),
+            (1, 2, 0)
+        );
+
+        let entries = compaction.entries().await.unwrap();
+        let compacted = entries
+            .iter()
+            .find(|entry| !entry.replaces.is_empty())
+            .unwrap();
+        assert_eq!(compacted.rows, 4);
+        assert_eq!(compacted.lsn_end, "0/20");
+        assert!(root.join(&compacted.path).exists());
+
+        // Replaced files are removed on the next run, and aren't compacted again.
+        let summary = compaction.run().await.unwrap();
+        assert_eq!((summary.written, summary.removed), (0, 2));
+        assert!(!root.join("public/users/a.parquet").exists());
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,164 @@
+// Find the line and the column of the key at path in the configuration, ie.
+// `output.partitions.keys[1].time`. Errors raised after a block was read, or by the
+// block itself like an unknown key, only know the path of the key.
+//
+// Only block style is followed, a path into a flow mapping like `{ a: 1 }` points to
+// the key of the mapping.
+pub(crate) fn locate(content: &str, path: &str) -> Option<(usize, usize)> {
+    let lines = lines(content);
+    let mut found = None;
+    // Index of the first line of the current block, and the indentation of its parent.
+    let mut start = 0;
+    let mut parent = None;
+
+    for segment in segments(path) {
+        let block = lines[start..].iter().enumerate().take_while(|(i, line)| {
+            parent.map_or(true, |parent| {
+                line.indent > parent && (*i == 0 || line.dash.map_or(true, |dash| dash > parent))
+            })
+        });
+
+        let (index, line) = match segment {
+            Segment::Key(key) => {
+                let mut block = block.filter(|(_, line)| !line.text.is_empty()).peekable();
+                let indent = block.peek()?.1.indent;
+                block.find(|(_, line)| line.indent == indent && line.key() == Some(key))?
+            }
+            Segment::Index(index) => {
+                let mut items = block.filter(|(_, line)| line.dash.is_some()).peekable();
+                let dash = items.peek()?.1.dash;
+                items.filter(|(_, line)| line.dash == dash).nth(index)?
+            }
+        };
+
+        found = Some((line.number, line.indent + 1));
+        match segment {
+            Segment::Key(_) => {
+                start += index + 1;
+                parent = Some(line.indent);
+            }
+            Segment::Index(_) => {
+                start += index;
+                parent = line.dash;
+            }
+        }
+    }
+
+    found
+}
+
+struct Line<'a> {
+    number: usize,
+    // Column of the text, after the dashes of the sequences it starts items of.
+    indent: usize,
+    // Column of the last dash when the line starts an item of a sequence.
+    dash: Option<usize>,
+    text: &'a str,
+}
+
+impl<'a> Line<'a> {
+    fn key(&self) -> Option<&'a str> {
+        let (key, rest) = self.text.split_once(':')?;
+        (rest.is_empty() || rest.starts_with(char::is_whitespace))
+            .then(|| key.trim_end().trim_matches(|c| c == '"' || c == '\''))
+    }
+}
+
+fn lines(content: &str) -> Vec<Line> {
+    let mut lines = Vec::new();
+
+    for (number, text) in content.lines().enumerate() {
+        let mut indent = text.len() - text.trim_start_matches(' ').len();
+        let mut dash = None;
+        let mut text = text.trim_start_matches(' ');
+
+        while let Some(item) = text.strip_prefix('-') {
+            if !(item.is_empty() || item.starts_with(' ')) {
+                break;
+            }
+            dash = Some(indent);
+            let trimmed = item.trim_start_matches(' ');
+            indent += 1 + item.len() - trimmed.len();
+            text = trimmed;
+        }
+
+        if text.starts_with('#') {
+            text = "";
+        }
+        if text.is_empty() && dash.is_none() {
+            continue;
+        }
+
+        lines.push(Line {
+            number: number + 1,
+            indent,
+            dash,
+            text,
+        });
+    }
+
+    lines
+}
+
+#[derive(Clone, Copy)]
+enum Segment<'a> {
+    Key(&'a str),
+    Index(usize),
+}
+
+fn segments(path: &str) -> Vec<Segment> {
+    let mut segments = Vec::new();
+
+    for part in path.split('.') {
+        let (key, indices) = part.split_once('[').unwrap_or((part, ""));
+        if !key.is_empty() {
+            segments.push(Segment::Key(key));
+        }
+        for index in indices.split(['[', ']']).filter(|index| !index.is_empty()) {
+            if let Ok(index) = index.parse() {
+                segments.push(Segment::Index(index));
+            }
+        }
+    }
+
+    segments
+}
+
+#[cfg(test)]
+mod tests {
+    use super::locate;
+
+    #[test]
+    fn keys_and_items() {
+        let content = "
+source:
+  driver: postgresql
+  # state: /tmp/state
+  state:
+    driver: postgresql
+    table: intake_state
+output:
+  partitions:
+    keys:
+      - time: day
+      -
+        column: tenant
+  directory: /var/lib/intake
+";
+
+        assert_eq!(locate(content, "source"), Some((2, 1)));
+        assert_eq!(locate(content, "source.state.table"), Some((7, 5)));
+        assert_eq!(
+            locate(content, "output.partitions.keys[0].time"),
+            Some((11, 9))
+        );
+        assert_eq!(
+            locate(content, "output.partitions.keys[1].column"),
+            Some((13, 9))
+        );
+        assert_eq!(locate(content, "output.directory"), Some((14, 3)));
+        // Keys of a nested block aren't keys of its parent.
+        assert_eq!(locate(content, "source.table"), None);
+        assert_eq!(locate(content, "output.partitions.keys[2]"), None);
+    }
+}
@@ -0,0 +1,867 @@
+// Config is the typed model of intake's YAML configuration. Every module receives its
+// own block, already validated, so a mistake in the configuration is reported once at
+// startup with the path and the line of the offending value instead of a panic deep
+// inside a module.
+//
+// Unknown keys are rejected so a typo doesn't silently fall back to a default. Each
+// block documents its keys next to the module that uses it.
+//
+// Secrets don't need to be written in the file: values can reference environment
+// variables and files, ie. `url: '${DATABASE_URL}'`. See interpolate for the syntax.
+
+use serde::de::{Deserializer, Error as _, MapAccess, Visitor};
+use serde::Deserialize;
+use std::marker::PhantomData;
+use std::path::{Path, PathBuf};
+use thiserror::Error;
+
+mod interpolate;
+mod locate;
+mod tagged;
+
+pub(crate) use interpolate::redact;
+use tagged::tagged;
+
+#[derive(Error, Debug)]
+pub(crate) enum Error {
+    #[error("could not read the configuration at {0}: {1}")]
+    FileError(String, String),
+
+    #[error("invalid configuration: {0}")]
+    InvalidConfig(String),
+
+    #[error("invalid configuration: {0}")]
+    InterpolationError(String),
+}
+
+impl From<serde_yaml::Error> for Error {
+    fn from(e: serde_yaml::Error) -> Self {
+        // Messages can quote the invalid value.
+        Error::InvalidConfig(redact(&e.to_string()))
+    }
+}
+
+// Unknown keys are reported by their block, at the line the block starts. Point to the
+// key instead.
+fn located(content: &str, e: serde_yaml::Error) -> Error {
+    let message = e.to_string();
+    let field = message
+        .split_once("unknown field `")
+        .and_then(|(_, rest)| rest.split_once('`'))
+        .map(|(field, _)| field);
+
+    let (field, location) = match (field, e.location()) {
+        (Some(field), Some(location)) => (field, location),
+        _ => return e.into(),
+    };
+
+    let suffix = format!(" at line {} column {}", location.line(), location.column());
+    let message = message.strip_suffix(&suffix).unwrap_or(&message);
+    let (path, message) = match message.split_once(": ") {
+        Some((path, rest)) if !path.contains(char::is_whitespace) => {
+            (format!("{}.{}", path, field), rest)
+        }
+        _ => (field.to_string(), message),
+    };
+
+    invalid(content, &path, message)
+}
+
+// Error for the key at path, with its line when it's written in the configuration.
+fn invalid(content: &str, path: &str, message: &str) -> Error {
+    let message = match locate::locate(content, path) {
+        Some((line, column)) => format!("{}: {} at line {} column {}", path, message, line, column),
+        None => format!("{}: {}", path, message),
+    };
+
+    Error::InvalidConfig(redact(&message))
+}
+
+// Read the configuration file at path, interpolate it and validate it.
+pub(crate) fn load(path: &Path) -> Result<Config, Error> {
+    let content = std::fs::read_to_string(path)
+        .map_err(|e| Error::FileError(path.to_string_lossy().to_string(), e.to_string()))?;
+
+    parse(&interpolate::interpolate(&content, &|name| {
+        std::env::var(name).ok()
+    })?)
+}
+
+pub(crate) fn parse(content: &str) -> Result<Config, Error> {
+    let config: Config = serde_yaml::from_str(content).map_err(|e| located(content, e))?;
+
+    let Source::Postgresql(source) = &config.source;
+    if matches!(source.state, StateStore::Storage(_)) && config.storage.is_none() {
+        return Err(invalid(
+            content,
+            "source.state",
+            "the storage driver needs a storage block",
+        ));
+    }
+
+    // A standby resumes from the state and the slot of the leader it replaces.
+    if let Some(election) = config.election.as_ref() {
+        if source.slot.is_none() {
+            return Err(invalid(
+                content,
+                "election",
+                "the source needs a persistent slot",
+            ));
+        }
+        if matches!(source.state, StateStore::File(_)) {
+            return Err(invalid(
+                content,
+                "election",
+                "the source state can't be kept in a local file",
+            ));
+        }
+        if let Election::Lease(lease) = election {
+            if lease.interval >= lease.ttl {
+                return Err(invalid(
+                    content,
+                    "election.interval",
+                    "should be shorter than election.ttl",
+                ));
+            }
+        }
+    }
+
+    // Mirror commits are applied in the order segments are closed, which is only the
+    // order of the changes when a table has a single segment open at a time.
+    if config.lake.as_ref().map(Lake::mirror) == Some(true)
+        && !config.output.partitions.keys.is_empty()
+    {
+        return Err(invalid(
+            content,
+            "lake.mirror",
+            "can't be used with output.partitions.keys",
+        ));
+    }
+
+    Ok(config)
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct Config {
+    pub source: Source,
+    #[serde(default)]
+    pub output: Output,
+    pub storage: Option<Storage>,
+    pub lake: Option<Lake>,
+    #[serde(default)]
+    pub compaction: Compaction,
+    pub election: Option<Election>,
+    #[serde(default)]
+    pub shutdown: Shutdown,
+    pub http: Option<Http>,
+}
+
+#[derive(Debug)]
+pub(crate) enum Source {
+    Postgresql(Postgresql),
+}
+
+tagged!(Source, "driver", { "postgresql" => Postgresql(Postgresql) });
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct Postgresql {
+    pub url: String,
+    #[serde(deserialize_with = "state_store")]
+    pub state: StateStore,
+    // Persistent slot replicated from. Without it, a temporary slot is created on
+    // every connection and changes made while intake is disconnected are lost.
+    #[serde(default, deserialize_with = "slot")]
+    pub slot: Option<String>,
+}
+
+// Where the replication state is kept. A path is a shorthand for the file driver:
+//
+//   state: /var/lib/intake/state
+//
+// The postgresql driver keeps it in a table of the source, or of the database at url,
+// and the storage driver keeps it at key in the destination of the storage block.
+// Both survive the loss of the local disk and refuse to overwrite a state saved by
+// another instance.
+#[derive(Debug)]
+pub(crate) enum StateStore {
+    File(FileStore),
+    Postgresql(TableStore),
+    Storage(ObjectStore),
+}
+
+tagged!(StateStore, "driver", {
+    "file" => File(FileStore),
+    "postgresql" => Postgresql(TableStore),
+    "storage" => Storage(ObjectStore),
+});
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct FileStore {
+    pub path: String,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct TableStore {
+    pub url: Option<String>,
+    #[serde(deserialize_with = "identifier")]
+    pub table: String,
+    pub name: String,
+}
+
+impl Default for TableStore {
+    fn default() -> Self {
+        TableStore {
+            url: None,
+            table: "intake_state".into(),
+            name: "intake".into(),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct ObjectStore {
+    pub key: String,
+}
+
+impl Default for ObjectStore {
+    fn default() -> Self {
+        ObjectStore {
+            key: "_state/intake".into(),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct Output {
+    pub directory: PathBuf,
+    #[serde(deserialize_with = "template")]
+    pub template: String,
+    pub partitions: Partitions,
+    pub manifest: Manifest,
+    // Let a column change type, files written afterward use the new type. Otherwise
+    // an event that changes the type of a column is an error.
+    pub type_changes: bool,
+}
+
+impl Default for Output {
+    fn default() -> Self {
+        Output {
+            directory: PathBuf::from("."),
+            template: "{schema}/{table}/{year}{month}{day}-{sequence}-{uuid}.parquet".into(),
+            partitions: Partitions::default(),
+            manifest: Manifest::default(),
+            type_changes: false,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct Partitions {
+    #[serde(deserialize_with = "positive")]
+    pub max_open: usize,
+    pub keys: Vec<PartitionKey>,
+}
+
+impl Default for Partitions {
+    fn default() -> Self {
+        Partitions {
+            max_open: 64,
+            keys: Vec::new(),
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "lowercase", deny_unknown_fields)]
+pub(crate) enum PartitionKey {
+    Time(Granularity),
+    Column(String),
+}
+
+#[derive(Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Granularity {
+    Hour,
+    Day,
+    Month,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct Manifest {
+    pub directory: String,
+    #[serde(deserialize_with = "positive")]
+    pub max_entries: usize,
+    // In seconds.
+    #[serde(deserialize_with = "positive")]
+    pub max_age: u64,
+}
+
+impl Default for Manifest {
+    fn default() -> Self {
+        Manifest {
+            directory: "_manifests".into(),
+            max_entries: 1_000,
+            max_age: 3_600,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub(crate) enum Storage {
+    S3(S3),
+    Filesystem(Filesystem),
+    Gcs(Gcs),
+    Azure(Azure),
+}
+
+tagged!(Storage, "driver", {
+    "s3" => S3(S3),
+    "filesystem" => Filesystem(Filesystem),
+    "gcs" => Gcs(Gcs),
+    "azure" => Azure(Azure),
+});
+
+impl Storage {
+    pub(crate) fn queue(&self) -> &Queue {
+        match self {
+            Storage::S3(config) => &config.queue,
+            Storage::Filesystem(config) => &config.queue,
+            Storage::Gcs(config) => &config.queue,
+            Storage::Azure(config) => &config.queue,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct S3 {
+    pub bucket: String,
+    #[serde(default)]
+    pub prefix: String,
+    pub region: Option<String>,
+    pub endpoint: Option<String>,
+    pub path_style: Option<bool>,
+    pub credentials: Option<S3Credentials>,
+    #[serde(default = "S3::part_size", deserialize_with = "part_size")]
+    pub part_size: u64,
+    #[serde(default = "S3::concurrency", deserialize_with = "positive")]
+    pub concurrency: usize,
+    #[serde(default)]
+    pub queue: Queue,
+}
+
+impl S3 {
+    pub(crate) const MIN_PART_SIZE: u64 = 5 * 1024 * 1024;
+
+    fn part_size() -> u64 {
+        16 * 1024 * 1024
+    }
+
+    fn concurrency() -> usize {
+        4
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct S3Credentials {
+    pub access_key_id: String,
+    pub secret_access_key: String,
+    pub session_token: Option<String>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct Filesystem {
+    pub directory: PathBuf,
+    #[serde(default)]
+    pub prefix: String,
+    #[serde(default)]
+    pub queue: Queue,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct Gcs {
+    pub bucket: String,
+    #[serde(default)]
+    pub prefix: String,
+    // Path to a service account JSON file.
+    pub credentials: Option<PathBuf>,
+    pub endpoint: Option<String>,
+    #[serde(default)]
+    pub queue: Queue,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct Azure {
+    pub container: String,
+    #[serde(default)]
+    pub prefix: String,
+    pub account: Option<String>,
+    pub key: Option<String>,
+    pub sas: Option<String>,
+    pub connection_string: Option<String>,
+    pub endpoint: Option<String>,
+    #[serde(default)]
+    pub queue: Queue,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct Queue {
+    pub directory: PathBuf,
+    #[serde(deserialize_with = "positive")]
+    pub workers: usize,
+    #[serde(deserialize_with = "positive")]
+    pub max_bytes: u64,
+    // In seconds.
+    #[serde(deserialize_with = "positive")]
+    pub backoff: u64,
+    #[serde(deserialize_with = "positive")]
+    pub max_backoff: u64,
+}
+
+impl Default for Queue {
+    fn default() -> Self {
+        Queue {
+            directory: PathBuf::from(".intake-queue"),
+            workers: 4,
+            max_bytes: 10 * 1024 * 1024 * 1024,
+            backoff: 1,
+            max_backoff: 300,
+        }
+    }
+}
+
+// Each format has its own keys. Mirroring is only supported by Iceberg: Delta can only
+// delete rows of existing files with deletion vectors, which need the position of every
+// row in the files it deletes from.
+#[derive(Debug)]
+pub(crate) enum Lake {
+    Iceberg(Iceberg),
+    Delta(Delta),
+}
+
+tagged!(Lake, "format", {
+    "iceberg" => Iceberg(Iceberg),
+    "delta" => Delta(Delta),
+});
+
+impl Lake {
+    pub(crate) fn journal(&self) -> &Path {
+        match self {
+            Lake::Iceberg(config) => &config.journal,
+            Lake::Delta(config) => &config.journal,
+        }
+    }
+
+    pub(crate) fn mirror(&self) -> bool {
+        match self {
+            Lake::Iceberg(config) => config.mirror,
+            Lake::Delta(_) => false,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct Iceberg {
+    #[serde(default = "Iceberg::warehouse")]
+    pub warehouse: String,
+    pub location: Option<String>,
+    #[serde(default = "journal")]
+    pub journal: PathBuf,
+    #[serde(default)]
+    pub mirror: bool,
+}
+
+impl Iceberg {
+    fn warehouse() -> String {
+        "iceberg".into()
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub(crate) struct Delta {
+    #[serde(default = "Delta::warehouse")]
+    pub warehouse: String,
+    pub location: Option<String>,
+    #[serde(default = "journal")]
+    pub journal: PathBuf,
+    #[serde(default = "Delta::checkpoint_interval", deserialize_with = "positive")]
+    pub checkpoint_interval: i64,
+}
+
+impl Delta {
+    fn warehouse() -> String {
+        "delta".into()
+    }
+
+    fn checkpoint_interval() -> i64 {
+        10
+    }
+}
+
+fn journal() -> PathBuf {
+    PathBuf::from(".intake-lake")
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct Compaction {
+    #[serde(deserialize_with = "positive")]
+    pub target_bytes: u64,
+    // In seconds.
+    pub min_age: u64,
+    pub retention: u64,
+}
+
+impl Default for Compaction {
+    fn default() -> Self {
+        Compaction {
+            target_bytes: 128 * 1024 * 1024,
+            min_age: 300,
+            retention: 3_600,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct Http {
+    pub address: std::net::SocketAddr,
+    // Seconds without any message from the source before intake isn't ready. The
+    // server sends a keepalive at least every half of its wal_sender_timeout.
+    #[serde(deserialize_with = "positive")]
+    pub max_idle: u64,
+}
+
+impl Default for Http {
+    fn default() -> Self {
+        Http {
+            address: ([0, 0, 0, 0], 9090).into(),
+            max_idle: 120,
+        }
+    }
+}
+
+// On SIGTERM or SIGINT, intake stops replicating and writes what it received before
+// exiting. Whatever isn't done after timeout seconds is resumed on the next start.
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct Shutdown {
+    #[serde(deserialize_with = "positive")]
+    pub timeout: u64,
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Shutdown { timeout: 30 }
+    }
+}
+
+// Election lets multiple instances run for availability. Only the leader replicates
+// and writes files, the others stand by until the leader stops holding its
+// leadership and one of them takes over from the last saved state.
+//
+// The advisory driver holds a Postgres advisory lock on the source, or on the
+// database at url, for as long as the leader is connected. The lease driver keeps a
+// lease next to the source state that the leader renews every interval, and that
+// expires ttl seconds after it was last renewed.
+#[derive(Debug)]
+pub(crate) enum Election {
+    Advisory(Advisory),
+    Lease(Lease),
+}
+
+tagged!(Election, "driver", {
+    "advisory" => Advisory(Advisory),
+    "lease" => Lease(Lease),
+});
+
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct Advisory {
+    pub url: Option<String>,
+    pub key: i64,
+    // In seconds.
+    #[serde(deserialize_with = "positive")]
+    pub interval: u64,
+}
+
+impl Default for Advisory {
+    fn default() -> Self {
+        Advisory {
+            url: None,
+            // "intake" in ASCII.
+            key: 0x696e_7461_6b65,
+            interval: 5,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(default, deny_unknown_fields)]
+pub(crate) struct Lease {
+    // In seconds.
+    #[serde(deserialize_with = "positive")]
+    pub ttl: u64,
+    #[serde(deserialize_with = "positive")]
+    pub interval: u64,
+}
+
+impl Default for Lease {
+    fn default() -> Self {
+        Lease {
+            ttl: 30,
+            interval: 10,
+        }
+    }
+}
+
+fn positive<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Deserialize<'de> + Default + PartialOrd + std::fmt::Display,
+{
+    let value = T::deserialize(deserializer)?;
+    if value <= T::default() {
+        return Err(D::Error::custom(format!(
+            "should be a positive integer, got {}",
+            value
+        )));
+    }
+
+    Ok(value)
+}
+
+fn part_size<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u64, D::Error> {
+    let value = u64::deserialize(deserializer)?;
+    if value < S3::MIN_PART_SIZE {
+        return Err(D::Error::custom(format!(
+            "should be at least {} bytes, got {}",
+            S3::MIN_PART_SIZE,
+            value
+        )));
+    }
+
+    Ok(value)
+}
+
+// Postgres only accepts lowercase letters, digits and underscores in slot names.
+fn slot<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<String>, D::Error> {
+    let slot = String::deserialize(deserializer)?;
+    if slot.is_empty()
+        || slot.len() > 63
+        || !slot
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_')
+    {
+        return Err(D::Error::custom(format!(
+            "invalid slot name {}, only lowercase letters, digits and underscores are allowed",
+            slot
+        )));
+    }
+
+    Ok(Some(slot))
+}
+
+fn state_store<'de, D: Deserializer<'de>>(deserializer: D) -> Result<StateStore, D::Error> {
+    struct Store;
+
+    impl<'de> Visitor<'de> for Store {
+        type Value = StateStore;
+
+        fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+            f.write_str("a path or a block with a `driver` key")
+        }
+
+        fn visit_str<E: serde::de::Error>(self, path: &str) -> Result<StateStore, E> {
+            Ok(StateStore::File(FileStore { path: path.into() }))
+        }
+
+        fn visit_map<A: MapAccess<'de>>(self, map: A) -> Result<StateStore, A::Error> {
+            tagged::Block(PhantomData).visit_map(map)
+        }
+    }
+
+    deserializer.deserialize_any(Store)
+}
+
+// Table names are written as is in queries, they can be qualified with a schema.
+fn identifier<'de, D: Deserializer<'de>>(deserializer: D) -> Result<String, D::Error> {
+    let identifier = String::deserialize(deserializer)?;
+    if identifier.is_empty()
+        || !identifier.split('.').all(|part| {
+            !part.is_empty()
+                && part
+                    .chars()
+                    .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_')
+        })
+    {
+        return Err(D::Error::custom(format!(
+            "invalid table name {}, only lowercase letters, digits and underscores are allowed",
+            identifier
+        )));
+    }
+
+    Ok(identifier)
+}
+
+fn template<'de, D: Deserializer<'de>>(deserializer: D) -> Result<String, D::Error> {
+    let template = String::deserialize(deserializer)?;
+    crate::events::output::validate(&template).map_err(D::Error::custom)?;
+
+    Ok(template)
+}
+
+#[cfg(test)]
+mod tests {
+    #[test]
+    fn defaults_and_errors() {
+        let config = super::parse(
+            "
+source:
+  driver: postgresql
+  url: postgres://localhost/db
+  state: /var/lib/intake/state
+storage:
+  driver: s3
+  bucket: intake
+lake:
+  format: delta
+",
+        )
+        .unwrap();
+
+        assert_eq!(config.output.partitions.max_open, 64);
+        assert_eq!(config.storage.unwrap().queue().workers, 4);
+        assert!(!config.lake.unwrap().mirror());
+
+        let error = super::parse(
+            "
+source:
+  driver: postgresql
+  url: postgres://localhost/db
+  state: /var/lib/intake/state
+output:
+  partitions:
+    max_open: 0
+",
+        )
+        .unwrap_err()
+        .to_string();
+        assert!(error.contains("output.partitions.max_open"), "{}", error);
+        assert!(error.contains("line 8"), "{}", error);
+
+        let error = super::parse(
+            "
+source:
+  driver: postgresql
+  url: postgres://localhost/db
+  state: /var/lib/intake/state
+  stat: typo
+",
+        )
+        .unwrap_err()
+        .to_string();
+        assert!(
+            error.contains("source.stat: unknown field `stat`"),
+            "{}",
+            error
+        );
+        assert!(error.contains("line 6"), "{}", error);
+
+        // Values of tagged blocks keep their line.
+        let error = super::parse(
+            "
+source:
+  driver: postgresql
+  url: postgres://localhost/db
+  state:
+    driver: postgresql
+    table: Intake
+",
+        )
+        .unwrap_err()
+        .to_string();
+        assert!(error.contains("source.state.table"), "{}", error);
+        assert!(error.contains("line 7"), "{}", error);
+
+        // The storage driver keeps the state in the storage block's destination.
+        let error = super::parse(
+            "
+source:
+  driver: postgresql
+  url: postgres://localhost/db
+  state:
+    driver: storage
+",
+        )
+        .unwrap_err()
+        .to_string();
+        assert!(error.contains("needs a storage block"), "{}", error);
+
+        // Standbys can only take over a persistent slot.
+        let error = super::parse(
+            "
+source:
+  driver: postgresql
+  url: postgres://localhost/db
+  state:
+    driver: postgresql
+election:
+  driver: lease
+",
+        )
+        .unwrap_err()
+        .to_string();
+        assert!(error.contains("needs a persistent slot"), "{}", error);
+        assert!(error.contains("line 7"), "{}", error);
+
+        // Partitions would commit the changes of a row out of order.
+        let error = super::parse(
+            "
+source:
+  driver: postgresql
+  url: postgres://localhost/db
+  state: /var/lib/intake/state
+output:
+  partitions:
+    keys:
+      - time: day
+lake:
+  format: iceberg
+  mirror: true
+",
+        )
+        .unwrap_err()
+        .to_string();
+        assert!(error.contains("lake.mirror"), "{}", error);
+
+        // Mirroring isn't a key of Delta lakes.
+        assert!(super::parse(
+            "
+source:
+  driver: postgresql
+  url: postgres://localhost/db
+  state: /var/lib/intake/state
+lake:
+  format: delta
+  mirror: true
+",
+        )
+        .is_err());
+    }
+}
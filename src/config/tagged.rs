@@ -0,0 +1,106 @@
+use serde::de::{DeserializeSeed, Deserializer, Error as _, MapAccess, Visitor};
+use serde::Deserialize;
+use std::fmt;
+use std::marker::PhantomData;
+
+// Blocks whose kind is picked by one of their keys, ie. `driver: s3`. Serde's internally
+// tagged enums buffer the whole block before picking the variant, which loses the line
+// of every value in it. When the tag is the first key of the block, the variant reads
+// the rest of the block in place and errors point to the offending key. Otherwise the
+// block is buffered and errors point to the block.
+pub(crate) trait Tagged: Sized {
+    const TAG: &'static str;
+    const VARIANTS: &'static [&'static str];
+
+    fn variant<'de, D: Deserializer<'de>>(name: &str, deserializer: D) -> Result<Self, D::Error>;
+}
+
+pub(crate) fn deserialize<'de, D, T>(deserializer: D) -> Result<T, D::Error>
+where
+    D: Deserializer<'de>,
+    T: Tagged,
+{
+    deserializer.deserialize_map(Block(PhantomData))
+}
+
+pub(crate) struct Block<T>(pub PhantomData<T>);
+
+impl<'de, T: Tagged> Visitor<'de> for Block<T> {
+    type Value = T;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "a block with a `{}` key", T::TAG)
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<T, A::Error> {
+        let mut buffered = serde_yaml::Mapping::new();
+
+        while let Some(key) = map.next_key::<serde_yaml::Value>()? {
+            if key.as_str() != Some(T::TAG) {
+                buffered.insert(key, map.next_value()?);
+                continue;
+            }
+
+            let name = map.next_value_seed(Name(T::VARIANTS))?;
+            if buffered.is_empty() {
+                return T::variant(&name, serde::de::value::MapAccessDeserializer::new(map));
+            }
+
+            while let Some((key, value)) = map.next_entry()? {
+                buffered.insert(key, value);
+            }
+            return T::variant(&name, serde_yaml::Value::Mapping(buffered))
+                .map_err(A::Error::custom);
+        }
+
+        Err(A::Error::missing_field(T::TAG))
+    }
+}
+
+// Read the tag, so an unknown variant is reported at its own line.
+struct Name(&'static [&'static str]);
+
+impl<'de> DeserializeSeed<'de> for Name {
+    type Value = String;
+
+    fn deserialize<D: Deserializer<'de>>(self, deserializer: D) -> Result<String, D::Error> {
+        let name = String::deserialize(deserializer)?;
+        if !self.0.contains(&name.as_str()) {
+            return Err(D::Error::unknown_variant(&name, self.0));
+        }
+
+        Ok(name)
+    }
+}
+
+// Implement Deserialize for an enum tagged by the given key, with one variant per name.
+macro_rules! tagged {
+    ($enum:ident, $tag:literal, { $($name:literal => $variant:ident($block:ty)),+ $(,)? }) => {
+        impl crate::config::tagged::Tagged for $enum {
+            const TAG: &'static str = $tag;
+            const VARIANTS: &'static [&'static str] = &[$($name),+];
+
+            fn variant<'de, D: serde::Deserializer<'de>>(
+                name: &str,
+                deserializer: D,
+            ) -> Result<Self, D::Error> {
+                match name {
+                    $($name => <$block as serde::Deserialize>::deserialize(deserializer)
+                        .map($enum::$variant),)+
+                    _ => Err(<D::Error as serde::de::Error>::unknown_variant(
+                        name,
+                        Self::VARIANTS,
+                    )),
+                }
+            }
+        }
+
+        impl<'de> serde::Deserialize<'de> for $enum {
+            fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+                crate::config::tagged::deserialize(deserializer)
+            }
+        }
+    };
+}
+
+pub(crate) use tagged;
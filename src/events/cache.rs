@@ -2,14 +2,20 @@ use std::collections::HashMap;
 
 use crate::events::{Value, Values};
 
-pub(crate) type Columns = HashMap<String, Column>;
+// Columns is the columnar representation of the rows held by a Cache. Every
+// column has exactly `rows` entries, rows that didn't have a value for a
+// given column are represented as None.
+pub(crate) struct Columns {
+    pub rows: usize,
+    columns: HashMap<String, Column>,
+}
 
 #[derive(Debug)]
 pub(crate) struct Cache(Vec<Values>);
 
 pub(crate) enum Column {
-    Int64(Vec<i64>),
-    String(Vec<String>),
+    Int64(Vec<Option<i64>>),
+    String(Vec<Option<String>>),
 }
 
 impl Cache {
@@ -29,40 +35,66 @@ impl Cache {
         self.0.is_empty()
     }
 
-    pub(crate) fn to_columns(mut self) -> Columns {
+    pub(crate) fn rows(&self) -> &[Values] {
+        &self.0
+    }
+
+    pub(crate) fn to_columns(self) -> Columns {
+        let rows = self.0.len();
         let mut columns: HashMap<String, Column> = HashMap::new();
 
-        for mut data in self.0.into_iter() {
-            for (key, value) in data.drain().into_iter() {
-                if let Some(column) = columns.get_mut(&key) {
-                    match column {
-                        Column::Int64(collection) => {
-                            if let Value::Int64(v) = value {
-                                collection.push(v);
-                            } else {
-                                panic!("Wrong type!");
-                            }
-                        }
-                        Column::String(collection) => {
-                            if let Value::String(v) = value {
-                                collection.push(v);
-                            } else {
-                                panic!("Wrong type!");
-                            }
-                        }
-                    }
-                } else {
-                    let typed_values = match value {
-                        Value::Int64(_) => Column::Int64(Vec::new()),
-                        Value::String(_) => Column::String(Vec::new()),
-                        _ => panic!("Not yet"),
-                    };
-
-                    columns.insert(key.to_owned(), typed_values);
-                }
+        for (row, mut data) in self.0.into_iter().enumerate() {
+            for (key, value) in data.drain() {
+                columns
+                    .entry(key)
+                    .or_insert_with(|| Column::with_nulls(&value, row))
+                    .push(value);
+            }
+
+            for column in columns.values_mut() {
+                column.pad(row + 1);
             }
         }
 
-        columns
+        Columns { rows, columns }
+    }
+}
+
+impl From<Vec<Values>> for Cache {
+    fn from(rows: Vec<Values>) -> Self {
+        Cache(rows)
+    }
+}
+
+impl Columns {
+    pub(crate) fn get(&self, name: &str) -> Option<&Column> {
+        self.columns.get(name)
+    }
+}
+
+impl Column {
+    // Return a new column matching the type of the value, filled with `len` nulls.
+    fn with_nulls(value: &Value, len: usize) -> Column {
+        match value {
+            Value::Int64(_) => Column::Int64(vec![None; len]),
+            Value::String(_) => Column::String(vec![None; len]),
+            _ => panic!("Not yet"),
+        }
+    }
+
+    fn push(&mut self, value: Value) {
+        match (self, value) {
+            (Column::Int64(collection), Value::Int64(v)) => collection.push(Some(v)),
+            (Column::String(collection), Value::String(v)) => collection.push(Some(v)),
+            _ => panic!("Wrong type!"),
+        }
+    }
+
+    // Add nulls at the end of the column until it has `len` entries.
+    fn pad(&mut self, len: usize) {
+        match self {
+            Column::Int64(collection) => collection.resize(len, None),
+            Column::String(collection) => collection.resize(len, None),
+        }
     }
 }
@@ -1,56 +1,293 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
 use tokio::sync::mpsc::Sender;
-use yaml_rust::Yaml;
 
-use crate::events::{self, errors::Error, schema::Schema, segment, terminator};
+use crate::config::Config;
+use crate::events::{
+    self, errors::Error, partition, partition::Partitioning, schema::Schema, segment, terminator,
+    watermark::Watermark,
+};
+use crate::health;
+use crate::lake::Lake;
+use crate::metrics;
+use crate::storage::queue::Queue;
 
 pub(crate) struct Collection {
     schemas: HashMap<String, Schema>,
+    origin: Option<events::Origin>,
+    partitioning: Partitioning,
     terminator: terminator::Terminator,
     expiration: Sender<events::Event>,
+    watermark: Arc<Watermark>,
+    type_changes: bool,
+    // Tables whose events couldn't be written, their events are skipped.
+    parked: HashSet<String>,
 }
 
 // Return a new Collection configured with the given config.
 //
 // The Collection is responsible to monitor different indices
 // that intake will ingest. Each index will have an entry in the collection
-// connecting the Schema and the ongoing Segment together.
-pub(crate) fn new(_config: &Yaml, expiration_sender: Sender<events::Event>) -> Collection {
+// connecting the Schema and its ongoing Segments together. An index
+// has one open Segment per active partition.
+pub(crate) fn new(
+    config: &Config,
+    expiration_sender: Sender<events::Event>,
+    queue: Option<Arc<Queue>>,
+    lake: Option<Arc<Lake>>,
+    watermark: Arc<Watermark>,
+) -> Collection {
     Collection {
         schemas: HashMap::new(),
+        origin: None,
+        partitioning: partition::new(&config.output.partitions),
         expiration: expiration_sender,
-        terminator: terminator::new(),
+        terminator: terminator::new(&config.output, queue, lake, watermark.clone()),
+        watermark,
+        type_changes: config.output.type_changes,
+        parked: HashSet::new(),
     }
 }
 
 // Public
 impl Collection {
-    pub(crate) fn insert(&mut self, index: &str, data: crate::events::Values) -> Result<(), Error> {
-        match self.schemas.get_mut(index) {
+    pub(crate) async fn insert(
+        &mut self,
+        index: &str,
+        operation: events::Operation,
+        data: crate::events::Values,
+        key: events::Key,
+        position: events::Position,
+    ) -> Result<(), Error> {
+        metrics::EVENTS.add(&[("table", index), ("operation", operation.as_str())], 1.0);
+        if self.parked.contains(index) {
+            metrics::EVENTS_SKIPPED.add(&[("table", index)], 1.0);
+            return Ok(());
+        }
+        let partition = self.partitioning.partition(&data, &position);
+
+        match self.schemas.get(index) {
+            None => {
+                let schema = Schema::try_from((index, &data))?;
+                self.schemas.insert(schema.name(), schema);
+            }
             Some(schema) => {
-                if let Some(seg) = schema.segment() {
-                    seg.add(data)?
-                } else {
-                    let mut seg = segment::new(&schema, self.expiration.clone());
-                    seg.add(data)?;
-                    *schema.segment() = Some(seg);
+                if let Some(evolved) = schema.evolve(&data, self.type_changes)? {
+                    // Segments of the previous version are closed as their files
+                    // can't hold the row.
+                    let mut previous = self
+                        .schemas
+                        .insert(evolved.name(), evolved)
+                        .expect("schema was just retrieved. This is a bug");
+                    for seg in previous.drain() {
+                        self.terminator
+                            .terminate(&previous, seg, self.origin.as_ref())
+                            .await?;
+                    }
                 }
             }
+        }
+
+        let opened = self
+            .schemas
+            .get_mut(index)
+            .and_then(|schema| schema.segment(&partition))
+            .is_some();
+
+        if !opened && self.open() >= self.partitioning.max_open {
+            self.evict().await?;
+        }
+
+        let schema = self
+            .schemas
+            .get_mut(index)
+            .expect("schema was just inserted. This is a bug");
+
+        match schema.segment(&partition) {
+            Some(seg) => seg.add(data, operation, key, position)?,
             None => {
-                let mut schema = Schema::try_from((index, &data))?;
-                *schema.segment() = Some(segment::new(&schema, self.expiration.clone()));
-                self.schemas.insert(schema.name(), schema.into());
+                let mut seg = segment::new(schema, partition, self.expiration.clone());
+                seg.add(data, operation, key, position)?;
+                self.watermark.hold(seg.uuid, position.lsn);
+                schema.open(seg);
             }
         }
+
+        metrics::SEGMENTS_OPEN.set(&[], self.open() as f64);
         Ok(())
     }
 
-    pub(crate) fn expired(&mut self, index: &str, id: &uuid::Uuid) {
+    pub(crate) async fn expired(&mut self, index: &str, id: &uuid::Uuid) -> Result<(), Error> {
         if let Some(schema) = self.schemas.get_mut(index) {
-            if let Some(seg) = schema.segment().take() {
+            if let Some(seg) = schema.take(id) {
                 self.terminator
-                    .terminate(seg, schema.types(), schema.properties());
+                    .terminate(schema, seg, self.origin.as_ref())
+                    .await?;
             }
         }
+
+        metrics::SEGMENTS_OPEN.set(&[], self.open() as f64);
+        Ok(())
+    }
+
+    // Skip the events of index from now on, after one of them at position couldn't be
+    // written. The watermark stays below position so the table's changes are
+    // replicated again once intake is fixed and restarted.
+    pub(crate) fn park(&mut self, index: &str, position: events::Position, error: &Error) {
+        log::error!(
+            "Could not write an event of {}: {}. Skipping its events until intake restarts.",
+            index,
+            error
+        );
+        health::REGISTRY.down(health::LISTENER, &format!("{} is parked: {}", index, error));
+
+        if self.parked.insert(index.to_string()) {
+            self.watermark.hold(uuid::Uuid::new_v4(), position.lsn);
+        }
+    }
+
+    // Record that every change up to lsn was added to a segment.
+    pub(crate) fn received(&mut self, lsn: u64) {
+        self.watermark.received(lsn);
+    }
+
+    // Record the origin of the events that follow. Segments that are
+    // already opened keep the events from the previous connection and are closed first.
+    pub(crate) async fn connected(&mut self, origin: events::Origin) -> Result<(), Error> {
+        self.close().await?;
+
+        self.origin = Some(origin);
+        Ok(())
+    }
+
+    // Close every open segment.
+    pub(crate) async fn close(&mut self) -> Result<(), Error> {
+        for schema in self.schemas.values_mut() {
+            for seg in schema.drain() {
+                self.terminator
+                    .terminate(schema, seg, self.origin.as_ref())
+                    .await?;
+            }
+        }
+
+        metrics::SEGMENTS_OPEN.set(&[], 0.0);
+        Ok(())
+    }
+}
+
+impl Collection {
+    // Number of segments currently opened across all indices.
+    fn open(&self) -> usize {
+        self.schemas
+            .values()
+            .map(|schema| schema.segments().count())
+            .sum()
+    }
+
+    // Close the oldest open segment to make room for a new one.
+    async fn evict(&mut self) -> Result<(), Error> {
+        let oldest = self
+            .schemas
+            .iter()
+            .flat_map(|(index, schema)| schema.segments().map(move |seg| (index, seg)))
+            .min_by_key(|(_, seg)| seg.opened_at)
+            .map(|(index, seg)| (index.clone(), seg.uuid));
+
+        match oldest {
+            Some((index, id)) => self.expired(&index, &id).await,
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::events::{watermark::Watermark, Key, Operation, Position, Value, Values};
+    use std::sync::Arc;
+
+    fn row(id: Value) -> Values {
+        Values::from([("id".to_string(), id)])
+    }
+
+    #[tokio::test]
+    async fn type_changes_park_their_table() {
+        let directory =
+            std::env::temp_dir().join(format!("intake-events-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&directory).unwrap();
+        let config = crate::config::parse(&format!(
+            "
+source:
+  driver: postgresql
+  url: postgres://localhost/db
+  state: /var/lib/intake/state
+output:
+  directory: {}
+",
+            directory.display()
+        ))
+        .unwrap();
+
+        let (sender, _receiver) = tokio::sync::mpsc::channel(10);
+        let watermark = Arc::new(Watermark::default());
+        let mut collection = super::new(&config, sender, None, None, watermark.clone());
+        let position = |lsn| Position {
+            lsn,
+            timestamp: None,
+        };
+
+        collection
+            .insert(
+                "public.users",
+                Operation::Insert,
+                row(Value::Int64(1)),
+                Key::default(),
+                position(10),
+            )
+            .await
+            .unwrap();
+        let error = collection
+            .insert(
+                "public.users",
+                Operation::Insert,
+                row(Value::String("2".into())),
+                Key::default(),
+                position(20),
+            )
+            .await
+            .unwrap_err();
+        collection.park("public.users", position(20), &error);
+        watermark.received(20);
+
+        // Events of the parked table are skipped, the others are still written.
+        collection
+            .insert(
+                "public.users",
+                Operation::Insert,
+                row(Value::Int64(3)),
+                Key::default(),
+                position(30),
+            )
+            .await
+            .unwrap();
+        collection
+            .insert(
+                "public.orders",
+                Operation::Insert,
+                row(Value::Int64(1)),
+                Key::default(),
+                position(40),
+            )
+            .await
+            .unwrap();
+        assert_eq!(collection.schemas["public.users"].segments().count(), 1);
+        assert_eq!(collection.schemas["public.orders"].segments().count(), 1);
+
+        // The changes of the parked table aren't acknowledged once the others are.
+        collection.close().await.unwrap();
+        watermark.settled().await;
+        watermark.received(40);
+        assert_eq!(watermark.lsn(), 19);
+
+        std::fs::remove_dir_all(&directory).unwrap();
     }
 }
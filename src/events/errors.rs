@@ -2,9 +2,37 @@
 pub(crate) enum Error {
     ParquetError(String),
     FileError(String),
+    StorageError(String),
+    LakeError(String),
+    SchemaError(String),
     SegmentWithoutCache,
 }
 
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Error::ParquetError(e) => write!(f, "parquet error: {}", e),
+            Error::FileError(e) => write!(f, "file error: {}", e),
+            Error::StorageError(e) => write!(f, "storage error: {}", e),
+            Error::LakeError(e) => write!(f, "lake error: {}", e),
+            Error::SchemaError(e) => write!(f, "schema error: {}", e),
+            Error::SegmentWithoutCache => write!(f, "segment has no cache"),
+        }
+    }
+}
+
+impl From<crate::lake::Error> for Error {
+    fn from(e: crate::lake::Error) -> Self {
+        Self::LakeError(e.to_string())
+    }
+}
+
+impl From<crate::storage::Error> for Error {
+    fn from(e: crate::storage::Error) -> Self {
+        Self::StorageError(e.to_string())
+    }
+}
+
 impl From<std::io::Error> for Error {
     fn from(e: std::io::Error) -> Self {
         Self::FileError(e.to_string())
@@ -0,0 +1,135 @@
+use crate::config;
+use crate::events::output;
+use chrono::{DateTime, Duration, Utc};
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use uuid::Uuid;
+
+// Manifest keeps track of every file written by intake so downstream loaders
+// don't need to list the output directory and guess which files are complete.
+//
+// Manifests are JSON lines files, one entry per closed segment, stored in a directory
+// relative to the output directory. Every time an entry is appended, the whole
+// manifest is rewritten to a temporary file and renamed in place so readers never
+// see a partial manifest. Once a manifest reaches its maximum number of entries
+// or its maximum age, it is never modified again and a new one is started.
+#[derive(Debug)]
+pub(crate) struct Manifest {
+    directory: PathBuf,
+    max_entries: usize,
+    max_age: Duration,
+
+    current: Option<Current>,
+}
+
+#[derive(Debug)]
+struct Current {
+    path: PathBuf,
+    opened_at: DateTime<Utc>,
+    content: Vec<u8>,
+    entries: usize,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub(crate) struct Entry {
+    // Location of the file, relative to the output directory.
+    pub path: String,
+    pub table: String,
+    pub rows: i64,
+    pub bytes: u64,
+    pub lsn_start: String,
+    pub lsn_end: String,
+    // Digests of the file computed while it was written, `sha256:{hex}` and `{hex}`.
+    pub checksum: String,
+    pub crc32c: String,
+    pub schema_fingerprint: String,
+    pub closed_at: String,
+    // Files this file replaces, once compacted. Readers should ignore them.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub replaces: Vec<String>,
+}
+
+// Return a new manifest for the given output directory. `config` is the
+// `output.manifest` block.
+pub(crate) fn new(output: &Path, config: &config::Manifest) -> Manifest {
+    Manifest {
+        directory: output.join(&config.directory),
+        max_entries: config.max_entries,
+        max_age: Duration::seconds(config.max_age as i64),
+        current: None,
+    }
+}
+
+impl Manifest {
+    pub(crate) fn directory(&self) -> &Path {
+        &self.directory
+    }
+
+    // Append the entry to the current manifest and publish it. Return the path of the manifest.
+    pub(crate) fn append(&mut self, entry: &Entry) -> Result<PathBuf, std::io::Error> {
+        let now = Utc::now();
+        let expired = match self.current.as_ref() {
+            Some(current) => {
+                current.entries >= self.max_entries || now - current.opened_at >= self.max_age
+            }
+            None => true,
+        };
+
+        if expired {
+            self.current = Some(Current {
+                path: self.directory.join(format!(
+                    "manifest-{}-{}.jsonl",
+                    now.format("%Y%m%dT%H%M%SZ"),
+                    Uuid::new_v4().as_hyphenated()
+                )),
+                opened_at: now,
+                content: Vec::new(),
+                entries: 0,
+            });
+        }
+
+        let current = self.current.as_mut().expect("manifest was just opened");
+        serde_json::to_writer(&mut current.content, entry)?;
+        current.content.push(b'\n');
+        current.entries += 1;
+
+        std::fs::create_dir_all(&self.directory)?;
+        output::write(&current.path, &current.content)?;
+
+        Ok(current.path.clone())
+    }
+}
+
+// Return the entries of the manifests written to disk, oldest manifest first.
+pub(crate) fn read(output: &Path, config: &config::Manifest) -> Result<Vec<Entry>, std::io::Error> {
+    let directory = output.join(&config.directory);
+    if !directory.exists() {
+        return Ok(Vec::new());
+    }
+
+    let mut paths: Vec<PathBuf> = std::fs::read_dir(&directory)?
+        .map(|entry| entry.map(|entry| entry.path()))
+        .collect::<Result<_, _>>()?;
+    // Names start with the time the manifest was opened.
+    paths.sort();
+
+    let mut entries = Vec::new();
+    for path in paths {
+        if path.extension().map(|ext| ext == "jsonl") != Some(true) {
+            continue;
+        }
+
+        for line in std::fs::read_to_string(&path)?.lines() {
+            if line.is_empty() {
+                continue;
+            }
+
+            match serde_json::from_str(line) {
+                Ok(entry) => entries.push(entry),
+                Err(e) => log::warn!("Ignoring invalid entry in {:?}: {}", path, e),
+            }
+        }
+    }
+
+    Ok(entries)
+}
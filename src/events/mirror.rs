@@ -0,0 +1,166 @@
+use crate::events::{cache::Cache, Operation, Value, Values};
+use parquet::schema::types::{Type, TypePtr};
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+
+// Mirror is the current state of the rows changed by a segment, for lakes that
+// reflect the source table instead of its changes:
+//
+//   rows:    the last version of every row inserted or updated, and not deleted since.
+//   deletes: the key of every row changed, which removes the versions committed by
+//            earlier segments. Inserts have one too so a change that's replicated
+//            again, ie. after `state set-lsn`, replaces its row instead of adding
+//            another copy.
+//
+// A row can't be committed along with a delete of its own key as deletes only apply
+// to what was committed before them, so rows only ever appear once per segment.
+pub(crate) struct Mirror {
+    pub key: Vec<String>,
+    pub rows: Cache,
+    pub deletes: Cache,
+}
+
+// Return the mirror of the rows, in the order they were changed by their operations.
+// Previous holds the keys rows had before an update changed them. None when the key
+// is unknown.
+pub(crate) fn new(
+    rows: &[Values],
+    operations: &[Operation],
+    key: &[String],
+    previous: &[Values],
+) -> Option<Mirror> {
+    if key.is_empty() {
+        return None;
+    }
+
+    let mut latest: HashMap<Vec<String>, usize> = HashMap::new();
+    let mut deleted: HashSet<Vec<String>> = HashSet::new();
+    let mut deletes: Vec<Values> = Vec::new();
+
+    let mut delete = |values: &Values| {
+        let identity = identity(values, key);
+        if deleted.insert(identity) {
+            deletes.push(
+                key.iter()
+                    .filter_map(|column| Some((column.clone(), values.get(column)?.clone())))
+                    .collect(),
+            );
+        }
+    };
+
+    for values in previous.iter() {
+        delete(values);
+    }
+
+    for (index, values) in rows.iter().enumerate() {
+        latest.insert(identity(values, key), index);
+        delete(values);
+    }
+
+    let mut current: Vec<usize> = latest
+        .into_values()
+        .filter(|index| operations[*index] != Operation::Delete)
+        .collect();
+    current.sort_unstable();
+
+    Some(Mirror {
+        key: key.to_vec(),
+        rows: Cache::from(
+            current
+                .into_iter()
+                .map(|index| rows[index].clone())
+                .collect::<Vec<Values>>(),
+        ),
+        deletes: Cache::from(deletes),
+    })
+}
+
+impl Mirror {
+    // Return the parquet definition of the delete file: the key's columns of types.
+    pub(crate) fn types(&self, types: &TypePtr) -> TypePtr {
+        let mut fields: Vec<TypePtr> = types
+            .get_fields()
+            .iter()
+            .filter(|field| self.key.iter().any(|column| column == field.name()))
+            .cloned()
+            .collect();
+
+        Arc::new(
+            Type::group_type_builder(types.name())
+                .with_fields(&mut fields)
+                .build()
+                .expect("key columns are a subset of a valid schema. This is a bug"),
+        )
+    }
+}
+
+// Values of the key's columns, in a form that can be compared. Missing columns
+// are nulls.
+fn identity(values: &Values, key: &[String]) -> Vec<String> {
+    key.iter()
+        .map(|column| match values.get(column) {
+            Some(Value::Int64(v)) => format!("i{}", v),
+            Some(Value::Float(v)) => format!("f{}", v),
+            Some(Value::String(v)) => format!("s{}", v),
+            None => String::new(),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::events::{Operation, Value, Values};
+
+    fn row(id: i64, name: &str) -> Values {
+        let mut values = Values::new();
+        values.insert("id".into(), Value::Int64(id));
+        values.insert("name".into(), Value::String(name.into()));
+        values
+    }
+
+    fn ids(rows: &[Values]) -> Vec<i64> {
+        rows.iter()
+            .map(|values| match values.get("id") {
+                Some(Value::Int64(id)) => *id,
+                _ => panic!("id is missing"),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn keeps_the_latest_version_of_rows() {
+        let mut previous = Values::new();
+        previous.insert("id".into(), Value::Int64(9));
+
+        let rows = vec![
+            row(1, "first"),
+            row(2, "second"),
+            row(1, "renamed"),
+            row(2, ""),
+            row(3, "third"),
+        ];
+        let operations = [
+            Operation::Insert,
+            Operation::Insert,
+            Operation::Update,
+            Operation::Delete,
+            Operation::Update,
+        ];
+
+        let mirror = super::new(&rows, &operations, &["id".into()], &[previous]).unwrap();
+
+        let current = mirror.rows.rows();
+        assert_eq!(ids(current), vec![1, 3]);
+        assert!(matches!(current[0].get("name"), Some(Value::String(name)) if name == "renamed"));
+
+        let deletes = mirror.deletes.rows();
+        assert_eq!(ids(deletes), vec![9, 1, 2, 3]);
+
+        // Replaying the segment yields the same mirror.
+        let replayed = super::new(&rows, &operations, &["id".into()], &[]).unwrap();
+        assert_eq!(ids(replayed.deletes.rows()), vec![1, 2, 3]);
+        assert!(deletes.iter().all(|values| values.len() == 1));
+
+        assert!(super::new(&rows, &operations, &[], &[]).is_none());
+    }
+}
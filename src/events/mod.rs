@@ -6,35 +6,104 @@
 // The conversion and rules of getting from the replications stream into the event's generic struct
 // is up to each source.
 
+use crate::config::Config;
+use crate::health;
+use crate::lake::Lake;
+use crate::metrics;
+use crate::storage::queue::Queue;
+use chrono::{DateTime, Utc};
 use std::collections::HashMap;
+use std::sync::Arc;
 use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
 use uuid::Uuid;
-use yaml_rust::Yaml;
+use watermark::Watermark;
 
 mod cache;
 mod collection;
 mod errors;
+mod mirror;
+mod partition;
 mod schema;
 mod terminator;
 
+pub(crate) mod manifest;
+pub(crate) mod output;
+pub(crate) mod provenance;
 pub(crate) mod segment;
+pub(crate) mod watermark;
 
 pub(crate) type Values = HashMap<String, Value>;
 
 #[derive(Debug, Clone)]
 pub(crate) enum Event {
-    Insert(String, Values),
-    Update(String, Values),
-    Delete(String, Values),
+    Insert(String, Values, Key, Position),
+    Update(String, Values, Key, Position),
+    Delete(String, Values, Key, Position),
     SegmentExpired(String, Uuid),
+    // Every change up to the lsn was sent.
+    Received(u64),
+    Connected(Origin),
+    // Close every open segment and stop listening. Events sent before are processed first.
+    Shutdown,
 }
 
 impl Default for Event {
     fn default() -> Self {
-        Event::Insert("undefined index".into(), Values::default())
+        Event::Insert(
+            "undefined index".into(),
+            Values::default(),
+            Key::default(),
+            Position::default(),
+        )
     }
 }
 
+// Position of an event in the source's replication stream. For PostgreSQL,
+// the lsn is the WAL location of the transaction that generated the event.
+// The timestamp is when that transaction was committed, if the source knows it.
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct Position {
+    pub lsn: u64,
+    pub timestamp: Option<DateTime<Utc>>,
+}
+
+// Primary key of the row an event changed, when the source knows it. When an update
+// changed the key itself, previous holds the values the key had before the update.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Key {
+    pub columns: Vec<String>,
+    pub previous: Option<Values>,
+}
+
+// Kind of change an event represents. Segments count the operations of their rows.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Operation {
+    Insert,
+    Update,
+    Delete,
+}
+
+impl Operation {
+    pub(crate) fn as_str(&self) -> &'static str {
+        match self {
+            Operation::Insert => "insert",
+            Operation::Update => "update",
+            Operation::Delete => "delete",
+        }
+    }
+}
+
+// Origin identifies the database the events are coming from. It is sent by
+// the source every time it connects and is embedded in every file written
+// afterward.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Origin {
+    pub system: String,
+    pub timeline: String,
+    pub database: String,
+}
+
 #[derive(Debug, Clone)]
 pub enum Value {
     Int64(i64),
@@ -58,26 +127,81 @@ impl From<&Value> for parquet::basic::Type {
     }
 }
 
-pub(crate) fn listen(config: &Yaml) -> mpsc::Sender<Event> {
+// Process the events sent to the returned sender. The task ends once a Shutdown
+// event was processed. The watermark follows the changes as their files are written.
+pub(crate) fn listen(
+    config: &Config,
+    queue: Option<Arc<Queue>>,
+    lake: Option<Arc<Lake>>,
+) -> (mpsc::Sender<Event>, JoinHandle<()>, Arc<Watermark>) {
     let (sender, mut receiver) = mpsc::channel(10);
-    let mut segments = collection::new(config, sender.clone());
+    let watermark = Arc::new(Watermark::default());
+    let mut segments = collection::new(config, sender.clone(), queue, lake, watermark.clone());
+    let depth = sender.clone();
+
+    let listener = tokio::spawn(async move {
+        let _health = health::REGISTRY.track(health::LISTENER);
 
-    tokio::spawn(async move {
         loop {
-            match receiver.recv().await {
-                Some(e) => match e {
-                    Event::Insert(index, data) => {
-                        segments.insert(&index, data).unwrap();
+            let event = receiver.recv().await;
+            metrics::CHANNEL_DEPTH.set(&[], (depth.max_capacity() - depth.capacity()) as f64);
+
+            // A table whose events can't be written is parked, the others keep going.
+            let change = match event {
+                Some(Event::Insert(index, data, key, position)) => {
+                    Some((index, Operation::Insert, data, key, position))
+                }
+                Some(Event::Update(index, data, key, position)) => {
+                    Some((index, Operation::Update, data, key, position))
+                }
+                Some(Event::Delete(index, data, key, position)) => {
+                    Some((index, Operation::Delete, data, key, position))
+                }
+                Some(Event::SegmentExpired(index, id)) => {
+                    if let Err(e) = segments.expired(&index, &id).await {
+                        failed(&format!("Could not close a segment of {}: {}", index, e));
                     }
-                    Event::SegmentExpired(index, id) => {
-                        segments.expired(&index, &id);
+                    None
+                }
+                Some(Event::Received(lsn)) => {
+                    segments.received(lsn);
+                    None
+                }
+                Some(Event::Connected(origin)) => {
+                    if let Err(e) = segments.connected(origin).await {
+                        failed(&format!(
+                            "Could not close the segments on reconnection: {}",
+                            e
+                        ));
                     }
-                    _ => unimplemented!("Not yet"),
-                },
-                None => {}
+                    None
+                }
+                Some(Event::Shutdown) => {
+                    if let Err(e) = segments.close().await {
+                        failed(&format!("Could not close the segments on shutdown: {}", e));
+                    }
+                    return;
+                }
+                None => None,
+            };
+
+            if let Some((index, operation, data, key, position)) = change {
+                if let Err(e) = segments
+                    .insert(&index, operation, data, key, position)
+                    .await
+                {
+                    segments.park(&index, position, &e);
+                }
             }
         }
     });
 
-    sender
+    (sender, listener, watermark)
+}
+
+// Report an error of the listener. The changes of the segments it couldn't close
+// aren't acknowledged, they're replicated again after a restart.
+fn failed(reason: &str) {
+    log::error!("{}", reason);
+    health::REGISTRY.down(health::LISTENER, reason);
 }
@@ -0,0 +1,446 @@
+use crate::config;
+use crate::events::partition;
+use chrono::{DateTime, Utc};
+use std::path::{Path, PathBuf};
+use uuid::Uuid;
+
+const TEMPORARY_EXTENSION: &str = "intake-tmp";
+
+// Output describes where closed segments are written on disk. The location
+// of each file is the base directory joined with the rendered template.
+//
+// The template supports the following placeholders:
+//  - {table}, {schema}: the table and the schema the events belong to, escaped so
+//    they can't add or climb directories
+//  - {partition}: the Hive-style directories of the segment's partition. When the
+//    template doesn't include it, the partition is inserted right before the file name.
+//  - {year}, {month}, {day}, {hour}: when the segment was opened (UTC)
+//  - {lsn_start}, {lsn_end}: the LSN range covered by the segment
+//  - {sequence}: number of segments opened for that table by this process. It
+//    restarts at 0 with the process, so it can't make names unique on its own.
+//  - {uuid}: the segment's unique identifier, required
+#[derive(Debug)]
+pub(crate) struct Output {
+    directory: PathBuf,
+    template: Vec<Token>,
+    // Number of directories the partition adds to the path of a file.
+    partitions: usize,
+}
+
+// Naming holds all the values that can be referenced by the template
+// for a given segment.
+pub(crate) struct Naming<'a> {
+    pub index: &'a str,
+    pub partition: &'a str,
+    pub sequence: u64,
+    pub uuid: Uuid,
+    pub lsn: (u64, u64),
+    pub opened_at: DateTime<Utc>,
+}
+
+#[derive(Debug, PartialEq)]
+enum Token {
+    Literal(String),
+    Partition,
+    Table,
+    Schema,
+    Year,
+    Month,
+    Day,
+    Hour,
+    LsnStart,
+    LsnEnd,
+    Sequence,
+    Uuid,
+}
+
+// The template is validated when the configuration is loaded.
+pub(crate) fn new(config: &config::Output) -> Output {
+    Output {
+        directory: config.directory.clone(),
+        template: parse(&config.template).expect("output.template is validated"),
+        partitions: partition::depth(&config.partitions.keys),
+    }
+}
+
+impl Output {
+    // Return the full path for the segment described by naming.
+    pub(crate) fn path(&self, naming: &Naming) -> PathBuf {
+        let (schema, table) = split(naming.index);
+        let (schema, table) = (component(schema), component(table));
+        let mut name = String::new();
+
+        for token in self.template.iter() {
+            match token {
+                Token::Literal(literal) => name.push_str(literal),
+                Token::Partition => name.push_str(naming.partition),
+                Token::Table => name.push_str(&table),
+                Token::Schema => name.push_str(&schema),
+                Token::Year => name.push_str(&naming.opened_at.format("%Y").to_string()),
+                Token::Month => name.push_str(&naming.opened_at.format("%m").to_string()),
+                Token::Day => name.push_str(&naming.opened_at.format("%d").to_string()),
+                Token::Hour => name.push_str(&naming.opened_at.format("%H").to_string()),
+                Token::LsnStart => name.push_str(&format!("{:016X}", naming.lsn.0)),
+                Token::LsnEnd => name.push_str(&format!("{:016X}", naming.lsn.1)),
+                Token::Sequence => name.push_str(&format!("{:06}", naming.sequence)),
+                Token::Uuid => name.push_str(&naming.uuid.as_hyphenated().to_string()),
+            }
+        }
+
+        if !naming.partition.is_empty() && !self.template.contains(&Token::Partition) {
+            name = match name.rsplit_once('/') {
+                Some((directories, file)) => {
+                    format!("{}/{}/{}", directories, naming.partition, file)
+                }
+                None => format!("{}/{}", naming.partition, name),
+            };
+        }
+
+        self.directory.join(name)
+    }
+}
+
+impl Output {
+    pub(crate) fn directory(&self) -> &Path {
+        &self.directory
+    }
+
+    // Return the path relative to the output directory.
+    pub(crate) fn relative<'a>(&self, path: &'a Path) -> &'a Path {
+        path.strip_prefix(&self.directory).unwrap_or(path)
+    }
+
+    // Remove temporary files left behind by a previous process that crashed while
+    // writing a segment or a manifest. The output directory defaults to the working
+    // directory, so only the directories the template renders to are visited, along
+    // with the manifests'. Returns the number of files removed.
+    pub(crate) fn cleanup(&self, manifests: &Path) -> Result<usize, std::io::Error> {
+        let (prefix, depth) = self.scope();
+
+        Ok(cleanup(&self.directory.join(prefix), depth)? + cleanup(manifests, 0)?)
+    }
+
+    // Return the static directories the template starts with, and how many levels of
+    // directories can follow them before the file name.
+    fn scope(&self) -> (String, usize) {
+        let mut prefix = match self.template.first() {
+            Some(Token::Literal(literal)) => literal.clone(),
+            _ => String::new(),
+        };
+        prefix.truncate(prefix.rfind('/').map(|i| i + 1).unwrap_or(0));
+
+        let rendered = self.template.iter().map(|token| match token {
+            Token::Literal(literal) => literal.matches('/').count(),
+            _ => 0,
+        });
+        let depth = rendered.sum::<usize>() - prefix.matches('/').count() + self.partitions;
+
+        (prefix, depth)
+    }
+}
+
+// Return the hidden temporary path a file is written to before being renamed to `path`.
+pub(crate) fn temporary(path: &Path) -> PathBuf {
+    let name = path
+        .file_name()
+        .map(|name| name.to_string_lossy())
+        .unwrap_or_default();
+
+    path.with_file_name(format!(".{}.{}", name, TEMPORARY_EXTENSION))
+}
+
+// Return the path of a file derived from the file at path, `{name}.{suffix}.parquet`.
+pub(crate) fn sibling(path: &Path, suffix: &str) -> PathBuf {
+    let stem = path
+        .file_stem()
+        .map(|stem| stem.to_string_lossy())
+        .unwrap_or_default();
+
+    path.with_file_name(format!("{}.{}.parquet", stem, suffix))
+}
+
+// Atomically replace the file at path with the content. The content is written
+// to a temporary file, fsynced and renamed in place.
+pub(crate) fn write(path: &Path, content: &[u8]) -> Result<(), std::io::Error> {
+    use std::fs::File;
+    use std::io::Write;
+
+    let temporary = temporary(path);
+    let result = File::create(&temporary).and_then(|mut file| {
+        file.write_all(content)?;
+        file.sync_all()
+    });
+
+    if let Err(e) = result {
+        let _ = std::fs::remove_file(&temporary);
+        return Err(e);
+    }
+
+    std::fs::rename(&temporary, path)?;
+    sync_parent(path)
+}
+
+// Sync the directory of path, a file renamed into it is only durable afterward.
+pub(crate) fn sync_parent(path: &Path) -> Result<(), std::io::Error> {
+    // The parent of a bare file name is empty, it's the working directory.
+    if let Some(parent) = path.parent() {
+        let parent = if parent.as_os_str().is_empty() {
+            Path::new(".")
+        } else {
+            parent
+        };
+        std::fs::File::open(parent)?.sync_all()?;
+    }
+
+    Ok(())
+}
+
+fn is_temporary(path: &Path) -> bool {
+    let hidden = path
+        .file_name()
+        .map(|name| name.to_string_lossy().starts_with('.'))
+        .unwrap_or(false);
+
+    hidden && path.extension().map(|ext| ext == TEMPORARY_EXTENSION) == Some(true)
+}
+
+// Remove the temporary files of the directory and of its subdirectories, up to depth levels.
+fn cleanup(directory: &Path, depth: usize) -> Result<usize, std::io::Error> {
+    let mut removed = 0;
+
+    if !directory.is_dir() {
+        return Ok(0);
+    }
+
+    for entry in std::fs::read_dir(directory)? {
+        let entry = entry?;
+        let path = entry.path();
+
+        if entry.file_type()?.is_dir() {
+            if depth > 0 {
+                removed += cleanup(&path, depth - 1)?;
+            }
+        } else if is_temporary(&path) {
+            std::fs::remove_file(&path)?;
+            removed += 1;
+        }
+    }
+
+    Ok(removed)
+}
+
+// Indices are formatted as `schema.table`. Indices that don't have a schema
+// are considered to be part of the `public` schema.
+fn split(index: &str) -> (&str, &str) {
+    match index.split_once('.') {
+        Some((schema, table)) => (schema, table),
+        None => ("public", index),
+    }
+}
+
+// Escape a name so it's a single path component: separators and the escape
+// character are percent-encoded, and so are the dots of `.` and `..`.
+fn component(name: &str) -> String {
+    if name.chars().all(|c| c == '.') {
+        return name.replace('.', "%2E");
+    }
+
+    let mut escaped = String::with_capacity(name.len());
+    for c in name.chars() {
+        match c {
+            '/' | '\\' | '%' => escaped.push_str(&format!("%{:02X}", c as u32)),
+            c if c.is_control() => escaped.push_str(&format!("%{:02X}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+
+    escaped
+}
+
+// Check that the template can be rendered.
+pub(crate) fn validate(template: &str) -> Result<(), String> {
+    parse(template).map(|_| ())
+}
+
+fn parse(template: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let mut literal = String::new();
+    let mut chars = template.chars();
+
+    while let Some(c) = chars.next() {
+        if c != '{' {
+            literal.push(c);
+            continue;
+        }
+
+        let mut placeholder = String::new();
+        loop {
+            match chars.next() {
+                Some('}') => break,
+                Some(c) => placeholder.push(c),
+                None => return Err(format!("unclosed placeholder `{{{}`", placeholder)),
+            }
+        }
+
+        if !literal.is_empty() {
+            tokens.push(Token::Literal(std::mem::take(&mut literal)));
+        }
+
+        tokens.push(match placeholder.as_str() {
+            "partition" => Token::Partition,
+            "table" => Token::Table,
+            "schema" => Token::Schema,
+            "year" => Token::Year,
+            "month" => Token::Month,
+            "day" => Token::Day,
+            "hour" => Token::Hour,
+            "lsn_start" => Token::LsnStart,
+            "lsn_end" => Token::LsnEnd,
+            "sequence" => Token::Sequence,
+            "uuid" => Token::Uuid,
+            unknown => return Err(format!("unknown placeholder `{{{}}}`", unknown)),
+        });
+    }
+
+    if !literal.is_empty() {
+        tokens.push(Token::Literal(literal));
+    }
+
+    // Files are renamed in place, a name that's reused replaces a previous file.
+    if !tokens.contains(&Token::Uuid) {
+        return Err("template needs {uuid} to generate unique names".into());
+    }
+
+    Ok(tokens)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{cleanup, is_temporary, parse, temporary, write, Naming, Output};
+    use chrono::{TimeZone, Utc};
+    use std::path::{Path, PathBuf};
+    use uuid::Uuid;
+
+    fn naming<'a>(index: &'a str, partition: &'a str) -> Naming<'a> {
+        Naming {
+            index,
+            partition,
+            sequence: 12,
+            uuid: Uuid::nil(),
+            lsn: (0x16B3748, 0x16B37F0),
+            opened_at: Utc.with_ymd_and_hms(2022, 3, 7, 9, 30, 0).unwrap(),
+        }
+    }
+
+    #[test]
+    fn render_every_placeholder() {
+        let output = Output {
+            directory: PathBuf::from("/data"),
+            template: parse(
+                "{schema}/{table}/{year}-{month}-{day}T{hour}/{lsn_start}_{lsn_end}_{sequence}_{uuid}.parquet",
+            )
+            .unwrap(),
+            partitions: 0,
+        };
+
+        assert_eq!(
+            output.path(&naming("public.users", "")),
+            PathBuf::from("/data/public/users/2022-03-07T09/00000000016B3748_00000000016B37F0_000012_00000000-0000-0000-0000-000000000000.parquet")
+        );
+    }
+
+    #[test]
+    fn index_without_schema() {
+        let output = Output {
+            directory: PathBuf::from("."),
+            template: parse("{schema}/{table}/{sequence}-{uuid}.parquet").unwrap(),
+            partitions: 0,
+        };
+
+        assert_eq!(
+            output.path(&naming("users", "")),
+            PathBuf::from("./public/users/000012-00000000-0000-0000-0000-000000000000.parquet")
+        );
+    }
+
+    #[test]
+    fn partition_before_file_name() {
+        let output = Output {
+            directory: PathBuf::from("."),
+            template: parse("{schema}/{table}/{sequence}-{uuid}.parquet").unwrap(),
+            partitions: 0,
+        };
+
+        assert_eq!(
+            output.path(&naming("public.users", "year=2022/month=03")),
+            PathBuf::from(
+                "./public/users/year=2022/month=03/000012-00000000-0000-0000-0000-000000000000.parquet"
+            )
+        );
+    }
+
+    #[test]
+    fn names_stay_in_their_directory() {
+        let output = Output {
+            directory: PathBuf::from("."),
+            template: parse("{schema}/{table}/{uuid}.parquet").unwrap(),
+            partitions: 0,
+        };
+
+        assert_eq!(
+            output.path(&naming("a/b...", "")),
+            PathBuf::from("./a%2Fb/%2E%2E/00000000-0000-0000-0000-000000000000.parquet")
+        );
+    }
+
+    #[test]
+    fn temporary_files_are_hidden() {
+        let path = temporary(Path::new("./public/users/000012.parquet"));
+
+        assert_eq!(
+            path,
+            PathBuf::from("./public/users/.000012.parquet.intake-tmp")
+        );
+        assert!(is_temporary(&path));
+        assert!(!is_temporary(Path::new("./public/users/000012.parquet")));
+    }
+
+    #[test]
+    fn write_bare_file_names() {
+        let path = PathBuf::from(format!("intake-write-{}.json", Uuid::new_v4()));
+
+        write(&path, b"{}").unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), b"{}");
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn cleanup_stays_in_the_template() {
+        let output = Output {
+            directory: PathBuf::from("."),
+            template: parse("data/{schema}/{table}/{uuid}.parquet").unwrap(),
+            partitions: 3,
+        };
+        assert_eq!(output.scope(), ("data/".to_string(), 5));
+
+        let root = std::env::temp_dir().join(format!("intake-cleanup-{}", Uuid::new_v4()));
+        let nested = root.join("public/users");
+        std::fs::create_dir_all(&nested).unwrap();
+        std::fs::write(temporary(&root.join("a.parquet")), b"").unwrap();
+        std::fs::write(temporary(&nested.join("b.parquet")), b"").unwrap();
+
+        assert_eq!(cleanup(&root, 1).unwrap(), 1);
+        assert_eq!(cleanup(&root, 2).unwrap(), 1);
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn invalid_templates() {
+        assert!(parse("{table}/{nope}.parquet").is_err());
+        assert!(parse("{table}/{uuid.parquet").is_err());
+        assert!(parse("{table}.parquet").is_err());
+        assert!(parse("{table}/{sequence}.parquet").is_err());
+    }
+}
@@ -0,0 +1,148 @@
+use crate::config::{self, Granularity, PartitionKey as Key};
+use crate::events::{Position, Value, Values};
+use chrono::{DateTime, Utc};
+
+const DEFAULT_PARTITION: &str = "__HIVE_DEFAULT_PARTITION__";
+
+// Partitioning splits the events of a table into multiple segments based on
+// a list of keys. Each key generates one or more Hive-style directories
+// (`key=value`) so query engines can prune files when reading them.
+//
+// Keys are configured under `output.partitions.keys` and can either be
+// a bucket of the transaction's commit timestamp or the value of a column:
+//
+//   partitions:
+//     max_open: 64
+//     keys:
+//       - time: day     # hour, day or month
+//       - column: region
+#[derive(Debug)]
+pub(crate) struct Partitioning {
+    keys: Vec<Key>,
+
+    // Maximum number of segments that can be opened at once, across all
+    // partitions and tables.
+    pub max_open: usize,
+}
+
+pub(crate) fn new(config: &config::Partitions) -> Partitioning {
+    Partitioning {
+        keys: config.keys.clone(),
+        max_open: config.max_open,
+    }
+}
+
+impl Partitioning {
+    // Return the relative path of the partition the event belongs to. An empty
+    // string is returned when no partition keys are configured.
+    pub(crate) fn partition(&self, values: &Values, position: &Position) -> String {
+        let mut directories = Vec::new();
+
+        for key in self.keys.iter() {
+            match key {
+                Key::Time(granularity) => {
+                    let timestamp = position.timestamp.unwrap_or_else(Utc::now);
+                    directories.extend(time(granularity, &timestamp));
+                }
+                Key::Column(column) => {
+                    let value = match values.get(column) {
+                        Some(Value::Int64(v)) => v.to_string(),
+                        Some(Value::Float(v)) => escape(&v.to_string()),
+                        Some(Value::String(v)) if !v.is_empty() => escape(v),
+                        _ => DEFAULT_PARTITION.to_string(),
+                    };
+                    directories.push(format!("{}={}", escape(column), value));
+                }
+            }
+        }
+
+        directories.join("/")
+    }
+}
+
+// Return the number of directories the keys add to the path of a file.
+pub(crate) fn depth(keys: &[Key]) -> usize {
+    keys.iter()
+        .map(|key| match key {
+            Key::Time(Granularity::Month) => 2,
+            Key::Time(Granularity::Day) => 3,
+            Key::Time(Granularity::Hour) => 4,
+            Key::Column(_) => 1,
+        })
+        .sum()
+}
+
+fn time(granularity: &Granularity, timestamp: &DateTime<Utc>) -> Vec<String> {
+    let mut directories = vec![
+        timestamp.format("year=%Y").to_string(),
+        timestamp.format("month=%m").to_string(),
+    ];
+
+    match granularity {
+        Granularity::Month => {}
+        Granularity::Day => directories.push(timestamp.format("day=%d").to_string()),
+        Granularity::Hour => {
+            directories.push(timestamp.format("day=%d").to_string());
+            directories.push(timestamp.format("hour=%H").to_string());
+        }
+    }
+
+    directories
+}
+
+// Escape characters that have a meaning in a path or in a Hive partition
+// the same way Hive does, with their hexadecimal representation.
+fn escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+
+    for c in value.chars() {
+        match c {
+            '/' | '\\' | '=' | '%' | '"' | '\'' | ':' | '*' | '?' | '#' | '{' | '}' | '[' | ']'
+            | '^' => escaped.push_str(&format!("%{:02X}", c as u32)),
+            c if c.is_control() => escaped.push_str(&format!("%{:02X}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Granularity, Key, Partitioning};
+    use crate::events::{Position, Value, Values};
+    use chrono::{TimeZone, Utc};
+
+    #[test]
+    fn time_and_column_partitions() {
+        let partitioning = Partitioning {
+            keys: vec![Key::Time(Granularity::Hour), Key::Column("region".into())],
+            max_open: 1,
+        };
+
+        let mut values = Values::new();
+        values.insert("region".into(), Value::String("us/east".into()));
+        let position = Position {
+            lsn: 0,
+            timestamp: Some(Utc.with_ymd_and_hms(2022, 3, 7, 9, 30, 0).unwrap()),
+        };
+
+        assert_eq!(
+            partitioning.partition(&values, &position),
+            "year=2022/month=03/day=07/hour=09/region=us%2Feast"
+        );
+    }
+
+    #[test]
+    fn missing_column_uses_default_partition() {
+        let partitioning = Partitioning {
+            keys: vec![Key::Column("region".into())],
+            max_open: 1,
+        };
+
+        assert_eq!(
+            partitioning.partition(&Values::new(), &Position::default()),
+            "region=__HIVE_DEFAULT_PARTITION__"
+        );
+    }
+}
@@ -0,0 +1,54 @@
+use crate::events::{schema::Schema, segment::Segment, Origin};
+use parquet::format::KeyValue;
+
+// Provenance is embedded in the key-value metadata of every parquet file so
+// a file can be audited and reprocessed without a separate catalog. All keys
+// are prefixed with `intake.`. LSNs are formatted the way PostgreSQL does (`16/B374D848`)
+// and timestamps are RFC 3339.
+pub(crate) fn metadata(
+    origin: Option<&Origin>,
+    schema: &Schema,
+    segment: &Segment,
+) -> Vec<KeyValue> {
+    let mut metadata = Vec::new();
+    let mut add = |key: &str, value: String| {
+        metadata.push(KeyValue::new(format!("intake.{}", key), value));
+    };
+
+    if let Some(origin) = origin {
+        add("source.system_identifier", origin.system.clone());
+        add("source.timeline", origin.timeline.clone());
+        add("source.database", origin.database.clone());
+    }
+
+    add("table", schema.name());
+    add("schema.version", schema.version().to_string());
+
+    let (min, max) = segment.lsn();
+    add("lsn.min", lsn(min));
+    add("lsn.max", lsn(max));
+
+    if let Some((min, max)) = segment.timestamps() {
+        add("commit_timestamp.min", min.to_rfc3339());
+        add("commit_timestamp.max", max.to_rfc3339());
+    }
+
+    let operations = segment.operations();
+    add("rows.insert", operations.inserts.to_string());
+    add("rows.update", operations.updates.to_string());
+    add("rows.delete", operations.deletes.to_string());
+
+    add("version", env!("CARGO_PKG_VERSION").to_string());
+
+    metadata
+}
+
+pub(crate) fn lsn(lsn: u64) -> String {
+    format!("{:X}/{:X}", lsn >> 32, lsn & 0xFFFF_FFFF)
+}
+
+// Parse an LSN formatted by lsn, `{high}/{low}`.
+pub(crate) fn parse_lsn(lsn: &str) -> Option<u64> {
+    let (high, low) = lsn.split_once('/')?;
+    Some((u64::from_str_radix(high, 16).ok()? << 32) | u64::from_str_radix(low, 16).ok()?)
+}
@@ -1,17 +1,24 @@
 use crate::events::segment::Segment;
 use crate::events::Values;
+use parquet::basic::{Repetition, Type as PhysicalType};
 use parquet::file::properties::WriterPropertiesPtr;
+use parquet::format::KeyValue;
 use parquet::schema::types::TypePtr;
 use std::borrow::Borrow;
+use std::collections::{BTreeMap, HashMap};
 use std::hash::{Hash, Hasher};
 
 #[derive(Debug)]
 pub(crate) struct Schema {
-    segment: Option<Segment>,
+    // Open segments, keyed by the partition they belong to.
+    segments: HashMap<String, Segment>,
+    sequence: u64,
+
+    // Incremented every time the schema evolves, starting at 1.
+    version: u32,
 
     name: String,
     types: TypePtr,
-    properties: WriterPropertiesPtr,
 }
 
 impl PartialEq for Schema {
@@ -44,46 +51,188 @@ impl TryFrom<(&str, &Values)> for Schema {
     type Error = crate::events::errors::Error;
 
     fn try_from(tuple: (&str, &Values)) -> Result<Self, Self::Error> {
-        use parquet::file::properties::WriterProperties;
-        use parquet::schema::types::Type;
-
-        let mut fields = Vec::new();
-
-        let properties = WriterProperties::builder().build();
-        let mut definition = Type::group_type_builder(tuple.0);
-        for (key, value) in tuple.1.iter() {
-            let field = Type::primitive_type_builder(key, value.into());
-            fields.push(TypePtr::new(field.build()?));
-        }
-
-        definition = definition.with_fields(&mut fields);
+        let fields = tuple
+            .1
+            .iter()
+            .map(|(key, value)| (key.clone(), (value.into(), Repetition::REQUIRED)))
+            .collect();
 
         Ok(Schema {
             name: tuple.0.to_owned(),
-            types: TypePtr::new(definition.build()?),
-            properties: WriterPropertiesPtr::new(properties),
-            segment: None,
+            types: build(tuple.0, fields)?,
+            segments: HashMap::new(),
+            sequence: 0,
+            version: 1,
         })
     }
 }
 
+// Build the parquet definition of a table. Columns are sorted by name so the same
+// set of columns always generates the same definition.
+fn build(
+    name: &str,
+    fields: BTreeMap<String, (PhysicalType, Repetition)>,
+) -> Result<TypePtr, crate::events::errors::Error> {
+    use parquet::schema::types::Type;
+
+    let mut columns = Vec::new();
+    for (key, (physical, repetition)) in fields.into_iter() {
+        let field = Type::primitive_type_builder(&key, physical)
+            .with_repetition(repetition)
+            .build()?;
+        columns.push(TypePtr::new(field));
+    }
+
+    let definition = Type::group_type_builder(name)
+        .with_fields(&mut columns)
+        .build()?;
+
+    Ok(TypePtr::new(definition))
+}
+
 impl Schema {
     #[inline]
     pub(crate) fn types(&self) -> TypePtr {
         self.types.clone()
     }
 
-    #[inline]
-    pub(crate) fn properties(&self) -> WriterPropertiesPtr {
-        self.properties.clone()
+    // Return the writer's properties for a new file, including the given key-value metadata.
+    pub(crate) fn properties(&self, metadata: Vec<KeyValue>) -> WriterPropertiesPtr {
+        use parquet::file::properties::WriterProperties;
+
+        WriterPropertiesPtr::new(
+            WriterProperties::builder()
+                .set_key_value_metadata(Some(metadata))
+                .build(),
+        )
+    }
+
+    pub(crate) fn version(&self) -> u32 {
+        self.version
+    }
+
+    // Return a SHA-256 of the columns and their types. Two files with the same
+    // fingerprint can be read with the same schema.
+    pub(crate) fn fingerprint(&self) -> String {
+        use sha2::{Digest, Sha256};
+
+        let mut hasher = Sha256::new();
+        for field in self.types.get_fields().iter() {
+            hasher.update(field.name().as_bytes());
+            hasher.update(b":");
+            hasher.update(field.get_physical_type().to_string().as_bytes());
+            if field.get_basic_info().repetition() == Repetition::OPTIONAL {
+                hasher.update(b"?");
+            }
+            hasher.update(b";");
+        }
+
+        format!("{:x}", hasher.finalize())
+    }
+
+    // Return a new version of this schema if the values don't fit in the current one:
+    // they have a column that is unknown, or they miss a required column (deletes only
+    // carry the key, nulls are left out), which then becomes optional. A column can
+    // only change type when type_changes is set. The new schema starts without any
+    // open segments.
+    pub(crate) fn evolve(
+        &self,
+        values: &Values,
+        type_changes: bool,
+    ) -> Result<Option<Schema>, crate::events::errors::Error> {
+        use crate::events::errors::Error;
+
+        let mut fields: BTreeMap<String, (PhysicalType, Repetition)> = self
+            .types
+            .get_fields()
+            .iter()
+            .map(|field| {
+                let info = field.get_basic_info();
+                (
+                    field.name().to_string(),
+                    (field.get_physical_type(), info.repetition()),
+                )
+            })
+            .collect();
+
+        let mut changed = false;
+        for (key, value) in values.iter() {
+            let physical: PhysicalType = value.into();
+            match fields.get_mut(key) {
+                Some((current, _)) if *current == physical => {}
+                Some((current, _)) if type_changes => {
+                    *current = physical;
+                    changed = true;
+                }
+                Some((current, _)) => {
+                    return Err(Error::SchemaError(format!(
+                        "column {} of {} changed from {} to {}, see output.type_changes",
+                        key, self.name, current, physical
+                    )))
+                }
+                None => {
+                    fields.insert(key.clone(), (physical, Repetition::REQUIRED));
+                    changed = true;
+                }
+            }
+        }
+
+        for (key, (_, repetition)) in fields.iter_mut() {
+            if *repetition == Repetition::REQUIRED && !values.contains_key(key) {
+                *repetition = Repetition::OPTIONAL;
+                changed = true;
+            }
+        }
+
+        if !changed {
+            return Ok(None);
+        }
+
+        Ok(Some(Schema {
+            name: self.name.clone(),
+            types: build(&self.name, fields)?,
+            segments: HashMap::new(),
+            sequence: self.sequence,
+            version: self.version + 1,
+        }))
+    }
+
+    // Remove and return all the open segments.
+    pub(crate) fn drain(&mut self) -> Vec<Segment> {
+        self.segments.drain().map(|(_, segment)| segment).collect()
     }
 
     pub(crate) fn name(&self) -> String {
         self.name.clone()
     }
 
-    pub(crate) fn segment(&mut self) -> &mut Option<Segment> {
-        &mut self.segment
+    pub(crate) fn segment(&mut self, partition: &str) -> Option<&mut Segment> {
+        self.segments.get_mut(partition)
+    }
+
+    pub(crate) fn open(&mut self, segment: Segment) {
+        self.segments.insert(segment.partition.clone(), segment);
+    }
+
+    // Remove the segment with the given id from the open segments, if it is still open.
+    pub(crate) fn take(&mut self, id: &uuid::Uuid) -> Option<Segment> {
+        let partition = self
+            .segments
+            .iter()
+            .find(|(_, segment)| &segment.uuid == id)
+            .map(|(partition, _)| partition.clone())?;
+
+        self.segments.remove(&partition)
+    }
+
+    pub(crate) fn segments(&self) -> impl Iterator<Item = &Segment> {
+        self.segments.values()
+    }
+
+    // Increment and return the number of segments opened for this schema.
+    pub(crate) fn next_sequence(&mut self) -> u64 {
+        self.sequence += 1;
+        self.sequence
     }
 }
 
@@ -91,7 +240,7 @@ impl Schema {
 mod tests {
     use super::Schema;
     use crate::events::{Value, Values};
-    use parquet::basic::Type as PhysicalType;
+    use parquet::basic::{Repetition, Type as PhysicalType};
 
     #[test]
     fn generate_schema_from_values() {
@@ -118,10 +267,45 @@ mod tests {
         let schema = Schema::try_from(("my_index", &values)).unwrap();
         let types = schema.types().get_fields().to_owned();
 
-        // This assert might be flakey as the order is an implementation
-        // details.
+        // Columns are sorted by name.
         assert_eq!(types[2].get_physical_type(), PhysicalType::BYTE_ARRAY);
         assert_eq!(types[1].get_physical_type(), PhysicalType::INT64);
         assert_eq!(types[0].get_physical_type(), PhysicalType::FLOAT);
     }
+
+    #[test]
+    fn evolve_with_new_columns() {
+        let mut values = Values::new();
+        values.insert("id".into(), Value::Int64(1));
+
+        let schema = Schema::try_from(("my_index", &values)).unwrap();
+        assert!(schema.evolve(&values, false).unwrap().is_none());
+
+        values.insert("name".into(), Value::String("intake".into()));
+        let evolved = schema.evolve(&values, false).unwrap().unwrap();
+        assert_eq!(evolved.version(), 2);
+        assert_eq!(evolved.types().get_fields().len(), 2);
+
+        // Missing columns become optional, once.
+        let mut partial = Values::new();
+        partial.insert("id".into(), Value::Int64(2));
+        let evolved = evolved.evolve(&partial, false).unwrap().unwrap();
+        let name = &evolved.types().get_fields()[1];
+        assert_eq!(name.get_basic_info().repetition(), Repetition::OPTIONAL);
+        assert!(evolved.evolve(&partial, false).unwrap().is_none());
+    }
+
+    #[test]
+    fn type_changes_are_opt_in() {
+        let mut values = Values::new();
+        values.insert("id".into(), Value::Int64(1));
+        let schema = Schema::try_from(("my_index", &values)).unwrap();
+
+        values.insert("id".into(), Value::String("1".into()));
+        assert!(schema.evolve(&values, false).is_err());
+
+        let evolved = schema.evolve(&values, true).unwrap().unwrap();
+        let id = &evolved.types().get_fields()[0];
+        assert_eq!(id.get_physical_type(), PhysicalType::BYTE_ARRAY);
+    }
 }
@@ -2,8 +2,12 @@ use crate::events::{
     self,
     cache::{Cache, Columns},
     errors::Error,
+    mirror::{self, Mirror},
+    output,
     schema::Schema,
 };
+use crate::storage::checksum::{self, Checksum};
+use chrono::{DateTime, Utc};
 use parquet::errors::ParquetError;
 use parquet::file::{
     properties::WriterPropertiesPtr,
@@ -12,13 +16,36 @@ use parquet::file::{
 use parquet::format::FileMetaData;
 use parquet::schema::types::TypePtr;
 use std::fs::File;
+use std::io::Write;
+use std::path::Path;
 use tokio::sync::mpsc::Sender;
 use uuid::Uuid;
 
 #[derive(Debug)]
 pub(crate) struct Segment {
     pub uuid: Uuid,
+    pub partition: String,
+    pub sequence: u64,
+    pub opened_at: DateTime<Utc>,
     cache: Option<Cache>,
+    lsn: Option<(u64, u64)>,
+    timestamps: Option<(DateTime<Utc>, DateTime<Utc>)>,
+    operations: Operations,
+    // Operation of every row of the cache, in the same order.
+    changes: Vec<events::Operation>,
+
+    // Primary key of the rows, as last reported by the source, and the values
+    // keys had before updates changed them.
+    key: Vec<String>,
+    previous: Vec<events::Values>,
+}
+
+// Number of rows in a segment for each kind of operation.
+#[derive(Debug, Default, Clone, Copy)]
+pub(crate) struct Operations {
+    pub inserts: u64,
+    pub updates: u64,
+    pub deletes: u64,
 }
 
 impl From<ParquetError> for Error {
@@ -27,12 +54,25 @@ impl From<ParquetError> for Error {
     }
 }
 
-pub(crate) fn new(schema: &Schema, expiration: Sender<events::Event>) -> Segment {
+pub(crate) fn new(
+    schema: &mut Schema,
+    partition: String,
+    expiration: Sender<events::Event>,
+) -> Segment {
     use std::time::Duration;
 
     let segment = Segment {
         uuid: Uuid::new_v4(),
+        partition,
+        sequence: schema.next_sequence(),
+        opened_at: Utc::now(),
         cache: Some(Cache::new()),
+        lsn: None,
+        timestamps: None,
+        operations: Operations::default(),
+        changes: Vec::new(),
+        key: Vec::new(),
+        previous: Vec::new(),
     };
 
     let name = schema.name().to_owned();
@@ -41,41 +81,25 @@ pub(crate) fn new(schema: &Schema, expiration: Sender<events::Event>) -> Segment
     tokio::task::spawn(async move {
         tokio::time::sleep(Duration::from_secs(2)).await;
 
-        if let Err(e) = expiration
+        // The collection stops listening on shutdown, after closing every segment.
+        let _ = expiration
             .send(events::Event::SegmentExpired(name, uuid))
-            .await
-        {
-            panic!("Could not send expiration event: {:?}", e);
-        }
+            .await;
     });
 
     segment
 }
 
 impl Segment {
-    // closes the Segment
+    // closes the Segment, writing its content as a parquet file at the given path.
     pub(crate) fn close(
         self,
+        path: &Path,
         types: TypePtr,
         properties: WriterPropertiesPtr,
-    ) -> Result<FileMetaData, Error> {
-        use std::path::Path;
-
-        let columns = self
-            .cache
-            .expect("A cache should exists. This is a bug")
-            .to_columns();
-
-        let filename = format!("./{}.parquet", self.uuid.as_hyphenated().to_string());
-        let path = Path::new(&filename);
-        let file = File::create(&path)?;
-        let mut writer = SerializedFileWriter::new(file, types.clone(), properties)?;
-        let mut group = writer.next_row_group()?;
-
-        Self::write(columns, &mut group);
-        group.close()?;
-
-        Ok(writer.close().unwrap())
+    ) -> Result<(FileMetaData, Checksum), Error> {
+        let cache = self.cache.expect("A cache should exists. This is a bug");
+        publish(path, cache, types, properties)
     }
 
     // Return whether the underlying cache is empty or not.
@@ -93,19 +117,127 @@ impl Segment {
     // is set, an error will be returned.
     // It's possible that the behavior change to panic overtime as it is expected
     // that the segment should always have an underlying cache.
-    pub(crate) fn add(&mut self, values: events::Values) -> Result<(), Error> {
+    pub(crate) fn add(
+        &mut self,
+        values: events::Values,
+        operation: events::Operation,
+        key: events::Key,
+        position: events::Position,
+    ) -> Result<(), Error> {
         match self.cache.as_mut() {
             None => Err(Error::SegmentWithoutCache),
             Some(c) => {
                 c.add(values);
+                self.lsn = match self.lsn {
+                    Some((start, end)) => Some((start.min(position.lsn), end.max(position.lsn))),
+                    None => Some((position.lsn, position.lsn)),
+                };
+
+                if let Some(timestamp) = position.timestamp {
+                    self.timestamps = match self.timestamps {
+                        Some((min, max)) => Some((min.min(timestamp), max.max(timestamp))),
+                        None => Some((timestamp, timestamp)),
+                    };
+                }
+
+                if !key.columns.is_empty() {
+                    self.key = key.columns;
+                }
+                if let Some(previous) = key.previous {
+                    self.previous.push(previous);
+                }
+
+                self.changes.push(operation);
+                match operation {
+                    events::Operation::Insert => self.operations.inserts += 1,
+                    events::Operation::Update => self.operations.updates += 1,
+                    events::Operation::Delete => self.operations.deletes += 1,
+                }
+
                 Ok(())
             }
         }
     }
+
+    // Return the lowest and highest LSN of the events added to this segment.
+    pub(crate) fn lsn(&self) -> (u64, u64) {
+        self.lsn.unwrap_or_default()
+    }
+
+    // Return the earliest and latest commit timestamp of the events added to this
+    // segment, if the source provided them.
+    pub(crate) fn timestamps(&self) -> Option<(DateTime<Utc>, DateTime<Utc>)> {
+        self.timestamps
+    }
+
+    pub(crate) fn operations(&self) -> Operations {
+        self.operations
+    }
+
+    // Return the current state of the rows changed in this segment. None when the
+    // source didn't report a primary key for them.
+    pub(crate) fn mirror(&self) -> Option<Mirror> {
+        let cache = self.cache.as_ref()?;
+        mirror::new(cache.rows(), &self.changes, &self.key, &self.previous)
+    }
+}
+
+// Write the cache as a parquet file at the given path. Missing parent directories
+// are created.
+//
+// The content is first written to a hidden temporary file next to the destination
+// which is then fsynced and renamed in place. Readers polling the directory only
+// ever see complete files.
+pub(crate) fn publish(
+    path: &Path,
+    cache: Cache,
+    types: TypePtr,
+    properties: WriterPropertiesPtr,
+) -> Result<(FileMetaData, Checksum), Error> {
+    let columns = cache.to_columns();
+
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+
+    let temporary = output::temporary(path);
+    let closed = match Segment::persist(&temporary, columns, types, properties) {
+        Ok(closed) => closed,
+        Err(e) => {
+            let _ = std::fs::remove_file(&temporary);
+            return Err(e);
+        }
+    };
+
+    std::fs::rename(&temporary, path)?;
+    output::sync_parent(path)?;
+
+    Ok(closed)
 }
 
 impl Segment {
-    fn write(columns: Columns, writer: &mut SerializedRowGroupWriter<File>) {
+    // The checksum is computed from the bytes as they are written so it describes
+    // exactly what the parquet writer produced.
+    fn persist(
+        path: &Path,
+        columns: Columns,
+        types: TypePtr,
+        properties: WriterPropertiesPtr,
+    ) -> Result<(FileMetaData, Checksum), Error> {
+        let mut file = checksum::Writer::new(File::create(path)?);
+        let mut writer = SerializedFileWriter::new(&mut file, types, properties)?;
+        let mut group = writer.next_row_group()?;
+
+        Self::write(columns, &mut group);
+        group.close()?;
+
+        let metadata = writer.close()?;
+        file.get_ref().sync_all()?;
+
+        Ok((metadata, file.checksum()))
+    }
+
+    fn write<W: Write + Send>(columns: Columns, writer: &mut SerializedRowGroupWriter<W>) {
         use crate::events::cache::Column;
         use parquet::column::writer::ColumnWriter;
         use parquet::data_type::ByteArray;
@@ -113,36 +245,32 @@ impl Segment {
         while let Ok(Some(mut col)) = writer.next_column() {
             match col.untyped() {
                 ColumnWriter::Int64ColumnWriter(writer) => {
-                    let descriptor = writer.get_descriptor();
-                    let name = descriptor.name();
-                    let column = columns.get(name).unwrap();
-                    if let Column::Int64(collection) = column {
-                        writer
-                            .write_batch(
-                                collection.as_slice(),
-                                Some(&[descriptor.max_def_level()]),
-                                Some(&[descriptor.max_rep_level()]),
-                            )
-                            .unwrap();
-                    }
+                    let name = writer.get_descriptor().name().to_string();
+                    let optional = writer.get_descriptor().max_def_level() > 0;
+                    let (values, levels) = match columns.get(&name) {
+                        Some(Column::Int64(collection)) => levels(collection),
+                        Some(_) => panic!("Wrong type!"),
+                        None => (Vec::new(), vec![0; columns.rows]),
+                    };
+
+                    writer
+                        .write_batch(values.as_slice(), optional.then_some(&levels[..]), None)
+                        .unwrap();
                 }
                 ColumnWriter::ByteArrayColumnWriter(writer) => {
-                    let descriptor = writer.get_descriptor();
-                    let name = descriptor.name();
-                    let column = columns.get(name).unwrap();
-                    if let Column::String(collection) = column {
-                        let values: Vec<ByteArray> = collection
-                            .iter()
-                            .map(|v| ByteArray::from(v.as_str()))
-                            .collect();
-                        writer
-                            .write_batch(
-                                values.as_slice(),
-                                Some(&[descriptor.max_def_level()]),
-                                Some(&[descriptor.max_rep_level()]),
-                            )
-                            .unwrap();
-                    }
+                    let name = writer.get_descriptor().name().to_string();
+                    let optional = writer.get_descriptor().max_def_level() > 0;
+                    let (values, levels) = match columns.get(&name) {
+                        Some(Column::String(collection)) => levels(collection),
+                        Some(_) => panic!("Wrong type!"),
+                        None => (Vec::new(), vec![0; columns.rows]),
+                    };
+                    let values: Vec<ByteArray> =
+                        values.iter().map(|v| ByteArray::from(v.as_str())).collect();
+
+                    writer
+                        .write_batch(values.as_slice(), optional.then_some(&levels[..]), None)
+                        .unwrap();
                 }
                 _ => unimplemented!("Coming up soon."),
             }
@@ -150,6 +278,26 @@ impl Segment {
             col.close().unwrap();
         }
 
-        println!("Done writing columns");
+        log::debug!("Done writing columns");
     }
 }
+
+// Split a column into its non-null values and its definition levels. Columns are
+// not nested, so the definition level is 1 when the value is present and 0 when it
+// is null. Required columns have a value in every row and don't use the levels.
+fn levels<T: Clone>(collection: &[Option<T>]) -> (Vec<T>, Vec<i16>) {
+    let mut values = Vec::with_capacity(collection.len());
+    let mut levels = Vec::with_capacity(collection.len());
+
+    for value in collection.iter() {
+        match value {
+            Some(v) => {
+                values.push(v.clone());
+                levels.push(1);
+            }
+            None => levels.push(0),
+        }
+    }
+
+    (values, levels)
+}
@@ -1,6 +1,19 @@
-use crate::events::segment::Segment;
+use crate::config;
+use crate::events::cache::Cache;
+use crate::events::errors::Error;
+use crate::events::manifest::{self, Entry, Manifest};
+use crate::events::output::{self, Naming, Output};
+use crate::events::segment::{self, Segment};
+use crate::events::watermark::Watermark;
+use crate::events::{provenance, schema::Schema, Origin};
+use crate::lake::{self, Lake};
+use crate::metrics;
+use crate::storage::queue::Queue;
 use parquet::file::properties::WriterPropertiesPtr;
 use parquet::schema::types::TypePtr;
+use std::path::Path;
+use std::sync::Arc;
+use uuid::Uuid;
 
 // Terminator is responsible to close Segments that are
 // either full or that the timer reached its limit
@@ -8,24 +21,229 @@ use parquet::schema::types::TypePtr;
 // so that the option is set to None ready to be initialized again
 // when a new event for that index reaches intake.
 
-pub(crate) struct Terminator(Option<Box<dyn crate::storage::Expeditor + Send>>);
+pub(crate) struct Terminator {
+    output: Output,
+    manifest: Manifest,
+    queue: Option<Arc<Queue>>,
+    lake: Option<Arc<Lake>>,
+    watermark: Arc<Watermark>,
+}
+
+// Return a new Terminator. When a queue is given, closed segments and manifests are
+// enqueued for upload. Segments are removed from the output directory once uploaded.
+// When a lake is given, closed segments are also registered in their table. Lakes that
+// mirror their source get the current state of the rows instead, written next to the
+// segment. Segments hold the watermark until their files are uploaded and committed.
+pub(crate) fn new(
+    config: &config::Output,
+    queue: Option<Arc<Queue>>,
+    lake: Option<Arc<Lake>>,
+    watermark: Arc<Watermark>,
+) -> Terminator {
+    let output = output::new(config);
+    let manifest = manifest::new(output.directory(), &config.manifest);
+
+    match output.cleanup(manifest.directory()) {
+        Ok(0) => {}
+        Ok(removed) => log::info!("Removed {} orphaned temporary files.", removed),
+        Err(e) => panic!("could not clean up the output directory: {}", e),
+    }
 
-pub(crate) fn new() -> Terminator {
-    Terminator(None)
+    Terminator {
+        manifest,
+        output,
+        queue,
+        lake,
+        watermark,
+    }
 }
 
 impl Terminator {
-    pub(crate) fn terminate(
+    pub(crate) async fn terminate(
+        &mut self,
+        schema: &Schema,
+        segment: Segment,
+        origin: Option<&Origin>,
+    ) -> Result<(), Error> {
+        if segment.is_empty() {
+            log::debug!("Empty segment, dropping it.");
+            self.watermark.release(&segment.uuid);
+            return Ok(());
+        }
+
+        let index = schema.name();
+        let path = self.output.path(&Naming {
+            index: &index,
+            partition: &segment.partition,
+            sequence: segment.sequence,
+            uuid: segment.uuid,
+            lsn: segment.lsn(),
+            opened_at: segment.opened_at,
+        });
+
+        let segment_id = segment.uuid;
+        let metadata = provenance::metadata(origin, schema, &segment);
+        let (lsn_start, lsn_end) = segment.lsn();
+
+        let mirror = match self.lake.as_ref() {
+            Some(lake) if lake.mirror() => {
+                let mirror = segment.mirror();
+                if mirror.is_none() {
+                    log::info!(
+                        "{} has no primary key, its changes are appended to the lake.",
+                        index
+                    );
+                }
+                mirror
+            }
+            _ => None,
+        };
+
+        let started = std::time::Instant::now();
+        let (file, checksum) =
+            segment.close(&path, schema.types(), schema.properties(metadata.clone()))?;
+        metrics::FILE_CLOSE_SECONDS.observe(started.elapsed().as_secs_f64());
+
+        let entry = Entry {
+            path: self.output.relative(&path).to_string_lossy().to_string(),
+            table: index,
+            rows: file.num_rows,
+            bytes: std::fs::metadata(&path)?.len(),
+            lsn_start: provenance::lsn(lsn_start),
+            lsn_end: provenance::lsn(lsn_end),
+            checksum: format!("sha256:{}", checksum.sha256_hex()),
+            crc32c: checksum.crc32c_hex(),
+            schema_fingerprint: schema.fingerprint(),
+            closed_at: chrono::Utc::now().to_rfc3339(),
+            replaces: Vec::new(),
+        };
+
+        metrics::SEGMENTS_CLOSED.add(&[("table", entry.table.as_str())], 1.0);
+        metrics::ROWS_WRITTEN.add(&[("table", entry.table.as_str())], entry.rows as f64);
+        metrics::BYTES_WRITTEN.add(&[("table", entry.table.as_str())], entry.bytes as f64);
+
+        let manifest = self.manifest.append(&entry)?;
+        let mut uploads = vec![entry.path.clone()];
+        let mut commit = None;
+
+        if let Some(queue) = self.queue.as_ref() {
+            queue
+                .enqueue(path.clone(), entry.path.clone(), Some(checksum), true)
+                .await?;
+
+            let key = self
+                .output
+                .relative(&manifest)
+                .to_string_lossy()
+                .to_string();
+            // The manifest keeps changing until it's uploaded, its checksum is computed then.
+            queue.enqueue(manifest, key, None, false).await?;
+        }
+
+        if let Some(lake) = self.lake.as_ref() {
+            let files = match mirror {
+                Some(mirror) => {
+                    let deletes = mirror.types(&schema.types());
+                    let mut files = Vec::new();
+                    if !mirror.rows.is_empty() {
+                        let path = output::sibling(&path, "rows");
+                        let properties = schema.properties(metadata.clone());
+                        files.push(
+                            self.publish(&path, mirror.rows, schema.types(), properties, &[])
+                                .await?,
+                        );
+                    }
+                    if !mirror.deletes.is_empty() {
+                        let path = output::sibling(&path, "deletes");
+                        let properties = schema.properties(metadata);
+                        files.push(
+                            self.publish(&path, mirror.deletes, deletes, properties, &mirror.key)
+                                .await?,
+                        );
+                    }
+                    files
+                }
+                None => vec![lake::DataFile {
+                    key: entry.path,
+                    rows: entry.rows,
+                    bytes: entry.bytes,
+                    keys: Vec::new(),
+                }],
+            };
+            uploads.extend(files.iter().map(|file| file.key.clone()));
+            uploads.dedup();
+
+            commit = Some(
+                lake.append(lake::Commit {
+                    id: segment_id,
+                    table: entry.table,
+                    columns: lake::columns(&schema.types()),
+                    files,
+                })
+                .await?,
+            );
+        }
+
+        self.durable(segment_id, uploads, commit);
+        Ok(())
+    }
+
+    // Release the hold of the segment on the watermark once the files at the keys are
+    // uploaded and the commit, given by its sequence, is applied. A file that can't be
+    // uploaded keeps the hold: its changes are replicated again on the next start.
+    fn durable(&self, id: Uuid, uploads: Vec<String>, commit: Option<u64>) {
+        if self.queue.is_none() && self.lake.is_none() {
+            self.watermark.release(&id);
+            return;
+        }
+
+        let queue = self.queue.clone();
+        let lake = self.lake.clone();
+        self.watermark.release_after(id, async move {
+            if let Some(queue) = queue {
+                for key in uploads {
+                    if let Err(e) = queue.uploaded(&key).await {
+                        log::warn!(
+                            "Could not upload {}: {}. Its changes are replicated again on the next start.",
+                            key, e
+                        );
+                        return false;
+                    }
+                }
+            }
+            if let (Some(lake), Some(sequence)) = (lake, commit) {
+                lake.committed(sequence).await;
+            }
+
+            true
+        });
+    }
+
+    // Write the rows at path and enqueue the file for upload. Keys are the columns
+    // of an equality delete file, empty for data files.
+    async fn publish(
         &self,
-        mut segment: Segment,
+        path: &Path,
+        rows: Cache,
         types: TypePtr,
         properties: WriterPropertiesPtr,
-    ) {
-        if segment.is_empty() {
-            println!("Empty segment, dropping it.");
-            return;
+        keys: &[String],
+    ) -> Result<lake::DataFile, Error> {
+        let (file, checksum) = segment::publish(path, rows, types, properties)?;
+        let key = self.output.relative(path).to_string_lossy().to_string();
+        let bytes = std::fs::metadata(path)?.len();
+
+        if let Some(queue) = self.queue.as_ref() {
+            queue
+                .enqueue(path.to_path_buf(), key.clone(), Some(checksum), true)
+                .await?;
         }
 
-        segment.close(types, properties).unwrap();
+        Ok(lake::DataFile {
+            key,
+            rows: file.num_rows,
+            bytes,
+            keys: keys.to_vec(),
+        })
     }
 }
@@ -0,0 +1,114 @@
+use std::collections::HashMap;
+use std::future::Future;
+use std::sync::{Arc, Mutex};
+use tokio::sync::Notify;
+use uuid::Uuid;
+
+// Watermark is the position of the replication stream up to which every change is
+// durable: written in a closed file and, when a storage or a lake is configured,
+// uploaded and committed. Sources acknowledge the watermark instead of what they
+// received so the changes that only live in open segments, or in files that aren't
+// uploaded or committed yet, are replicated again after a restart.
+//
+// Each segment holds the position of its first change until its files are durable.
+#[derive(Default)]
+pub(crate) struct Watermark {
+    inner: Mutex<Inner>,
+    settled: Notify,
+}
+
+#[derive(Default)]
+struct Inner {
+    // Every change up to this position was added to a segment.
+    received: u64,
+    holds: HashMap<Uuid, u64>,
+    // Holds waiting for their files to be uploaded and committed.
+    releasing: usize,
+}
+
+impl Watermark {
+    // Record that every change up to lsn was added to a segment.
+    pub(crate) fn received(&self, lsn: u64) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.received = inner.received.max(lsn);
+    }
+
+    // Keep the watermark below lsn, the position of the first change of segment id.
+    pub(crate) fn hold(&self, id: Uuid, lsn: u64) {
+        self.inner.lock().unwrap().holds.insert(id, lsn);
+    }
+
+    pub(crate) fn release(&self, id: &Uuid) {
+        self.inner.lock().unwrap().holds.remove(id);
+    }
+
+    // Release the hold of id once durable resolves to true. A hold that isn't
+    // released keeps the watermark where it is until intake restarts.
+    pub(crate) fn release_after<F>(self: &Arc<Self>, id: Uuid, durable: F)
+    where
+        F: Future<Output = bool> + Send + 'static,
+    {
+        self.inner.lock().unwrap().releasing += 1;
+
+        let watermark = self.clone();
+        tokio::spawn(async move {
+            let durable = durable.await;
+
+            let mut inner = watermark.inner.lock().unwrap();
+            if durable {
+                inner.holds.remove(&id);
+            }
+            inner.releasing -= 1;
+            if inner.releasing == 0 {
+                watermark.settled.notify_waiters();
+            }
+        });
+    }
+
+    // Wait until every hold given to release_after is released or kept for good.
+    pub(crate) async fn settled(&self) {
+        loop {
+            let settled = self.settled.notified();
+
+            if self.inner.lock().unwrap().releasing == 0 {
+                return;
+            }
+
+            settled.await;
+        }
+    }
+
+    // Position up to which every change is durable.
+    pub(crate) fn lsn(&self) -> u64 {
+        let inner = self.inner.lock().unwrap();
+
+        match inner.holds.values().min() {
+            Some(first) => inner.received.min(first.saturating_sub(1)),
+            None => inner.received,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Watermark;
+    use uuid::Uuid;
+
+    #[test]
+    fn held_by_open_segments() {
+        let watermark = Watermark::default();
+        let (first, second) = (Uuid::new_v4(), Uuid::new_v4());
+
+        watermark.hold(first, 10);
+        watermark.received(10);
+        watermark.hold(second, 20);
+        watermark.received(30);
+        assert_eq!(watermark.lsn(), 9);
+
+        watermark.release(&first);
+        assert_eq!(watermark.lsn(), 19);
+
+        watermark.release(&second);
+        assert_eq!(watermark.lsn(), 30);
+    }
+}
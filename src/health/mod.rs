@@ -0,0 +1,217 @@
+// Health keeps the status reported by the tasks intake runs in the background, so
+// the http server can tell whether intake is alive and whether it replicates:
+//
+//   /healthz    fails when a task panicked. Intake can't recover from it, the
+//               process needs to be restarted. Answering at all shows the runtime
+//               isn't blocked.
+//   /readyz     fails when the replication stream isn't connected, when the source
+//               didn't send anything for max_idle seconds, when the storage can't be
+//               reached or when the last upload to the storage failed. Standbys are
+//               never ready.
+//
+// The storage is probed every PROBE by looking up a marker key, readiness reports
+// the result of the last probe.
+//
+// A task is tracked with a guard that records its status when it's dropped, which
+// also happens when the task panics.
+
+use crate::check::{Outcome, Report};
+use crate::storage::Expeditor;
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+// Component streaming changes from the source.
+pub(crate) const REPLICATION: &str = "replication";
+// Component writing the events to files.
+pub(crate) const LISTENER: &str = "listener";
+// Storage the files are uploaded to.
+pub(crate) const STORAGE: &str = "storage";
+// Component uploading files to the storage.
+pub(crate) const UPLOADS: &str = "uploads";
+
+const PROBE: Duration = Duration::from_secs(30);
+// Key looked up to probe the storage, it doesn't need to exist.
+const PROBE_KEY: &str = "_intake/probe";
+
+pub(crate) static REGISTRY: Registry = Registry::new();
+
+#[derive(Debug, Clone)]
+enum Status {
+    // Running, with the time of its last activity.
+    Up(Instant),
+    Down(String),
+    Panicked,
+}
+
+pub(crate) struct Registry {
+    components: Mutex<BTreeMap<&'static str, Status>>,
+}
+
+// Guard marks its component as down once dropped.
+pub(crate) struct Guard {
+    registry: &'static Registry,
+    name: &'static str,
+}
+
+impl Guard {
+    // Record an activity of the component.
+    pub(crate) fn beat(&self) {
+        self.registry.up(self.name);
+    }
+}
+
+impl Drop for Guard {
+    fn drop(&mut self) {
+        let status = if std::thread::panicking() {
+            Status::Panicked
+        } else {
+            Status::Down("stopped".into())
+        };
+        self.registry.set(self.name, status);
+    }
+}
+
+impl Registry {
+    const fn new() -> Registry {
+        Registry {
+            components: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    // Mark the component as up until the returned guard is dropped.
+    pub(crate) fn track(&'static self, name: &'static str) -> Guard {
+        self.up(name);
+        Guard {
+            registry: self,
+            name,
+        }
+    }
+
+    pub(crate) fn up(&self, name: &'static str) {
+        self.set(name, Status::Up(Instant::now()));
+    }
+
+    pub(crate) fn down(&self, name: &'static str, reason: &str) {
+        self.set(name, Status::Down(reason.to_string()));
+    }
+
+    fn set(&self, name: &'static str, status: Status) {
+        self.components
+            .lock()
+            .expect("health lock is poisoned")
+            .insert(name, status);
+    }
+
+    fn status(&self, name: &str) -> Option<Status> {
+        self.components
+            .lock()
+            .expect("health lock is poisoned")
+            .get(name)
+            .cloned()
+    }
+
+    pub(crate) fn liveness(&self) -> Report {
+        let mut outcomes = vec![Outcome::new("process", Ok("alive".into()))];
+
+        for (name, status) in self
+            .components
+            .lock()
+            .expect("health lock is poisoned")
+            .iter()
+        {
+            let result = match status {
+                Status::Panicked => Err("panicked, intake needs to be restarted".into()),
+                Status::Up(_) => Ok("running".into()),
+                Status::Down(reason) => Ok(reason.clone()),
+            };
+            outcomes.push(Outcome::new(name, result));
+        }
+
+        Report { outcomes }
+    }
+
+    pub(crate) fn readiness(&self, max_idle: Duration) -> Report {
+        let replication = match self.status(REPLICATION) {
+            Some(Status::Up(beat)) if beat.elapsed() <= max_idle => {
+                Ok(format!("last message {}s ago", beat.elapsed().as_secs()))
+            }
+            Some(Status::Up(beat)) => Err(format!("no message for {}s", beat.elapsed().as_secs())),
+            Some(Status::Down(reason)) => Err(reason),
+            Some(Status::Panicked) => Err("panicked".into()),
+            None => Err("not replicating".into()),
+        };
+        let mut outcomes = vec![Outcome::new(REPLICATION, replication)];
+
+        for name in [LISTENER, STORAGE, UPLOADS] {
+            let result = match self.status(name) {
+                Some(Status::Up(_)) => Ok("running".into()),
+                Some(Status::Down(reason)) => Err(reason),
+                Some(Status::Panicked) => Err("panicked".into()),
+                // Nothing was uploaded yet, or no storage is configured.
+                None => continue,
+            };
+            outcomes.push(Outcome::new(name, result));
+        }
+
+        Report { outcomes }
+    }
+}
+
+// Probe the storage every PROBE in the background. The storage isn't ready until the
+// first probe succeeds.
+pub(crate) fn probe(expeditor: Arc<dyn Expeditor>) {
+    REGISTRY.down(STORAGE, "not probed yet");
+
+    tokio::spawn(async move {
+        let mut probes = tokio::time::interval(PROBE);
+        loop {
+            probes.tick().await;
+            match expeditor.exists(PROBE_KEY).await {
+                Ok(_) => REGISTRY.up(STORAGE),
+                Err(e) => REGISTRY.down(STORAGE, &format!("unreachable: {}", e)),
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Registry, LISTENER, REPLICATION, STORAGE, UPLOADS};
+    use std::time::Duration;
+
+    #[test]
+    fn tracks_liveness_and_readiness() {
+        static REGISTRY: Registry = Registry::new();
+        let max_idle = Duration::from_secs(60);
+
+        assert!(!REGISTRY.readiness(max_idle).passed());
+
+        let replication = REGISTRY.track(REPLICATION);
+        let _listener = REGISTRY.track(LISTENER);
+        replication.beat();
+        assert!(REGISTRY.readiness(max_idle).passed());
+
+        REGISTRY.down(STORAGE, "not probed yet");
+        assert!(!REGISTRY.readiness(max_idle).passed());
+        REGISTRY.up(STORAGE);
+        assert!(REGISTRY.readiness(max_idle).passed());
+
+        REGISTRY.down(UPLOADS, "upload failed");
+        assert!(!REGISTRY.readiness(max_idle).passed());
+        REGISTRY.up(UPLOADS);
+
+        // A task that stopped isn't ready but is still alive.
+        drop(replication);
+        assert!(!REGISTRY.readiness(max_idle).passed());
+        assert!(REGISTRY.liveness().passed());
+
+        std::thread::spawn(|| {
+            let _replication = REGISTRY.track(REPLICATION);
+            panic!("ingest failed");
+        })
+        .join()
+        .unwrap_err();
+        assert!(!REGISTRY.liveness().passed());
+    }
+}
@@ -0,0 +1,74 @@
+// Http serves the endpoints used to operate intake:
+//
+//   GET /metrics    metrics in the Prometheus text format
+//   GET /healthz    whether intake is alive, see health
+//   GET /readyz     whether intake replicates, see health
+//
+//   http:
+//     address: 0.0.0.0:9090
+//     max_idle: 120          # seconds without a message before intake isn't ready
+
+use crate::check::Report;
+use crate::config;
+use crate::health;
+use crate::metrics;
+use hyper::header::CONTENT_TYPE;
+use hyper::service::{make_service_fn, service_fn};
+use hyper::{Body, Method, Request, Response, StatusCode};
+use std::convert::Infallible;
+use std::time::Duration;
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+pub(crate) enum Error {
+    #[error("could not listen on {0}: {1}")]
+    BindError(String, String),
+}
+
+// Start serving on the configured address. Requests are served in the background.
+pub(crate) fn start(config: &config::Http) -> Result<(), Error> {
+    let max_idle = Duration::from_secs(config.max_idle);
+    let service = make_service_fn(move |_| async move {
+        Ok::<_, Infallible>(service_fn(move |request| handle(request, max_idle)))
+    });
+    let server = hyper::Server::try_bind(&config.address)
+        .map_err(|e| Error::BindError(config.address.to_string(), e.to_string()))?
+        .serve(service);
+
+    log::info!("Serving metrics and health on {}", config.address);
+    tokio::spawn(async move {
+        if let Err(e) = server.await {
+            log::error!("Http server stopped: {}", e);
+        }
+    });
+
+    Ok(())
+}
+
+async fn handle(request: Request<Body>, max_idle: Duration) -> Result<Response<Body>, Infallible> {
+    let response = match (request.method(), request.uri().path()) {
+        (&Method::GET, "/metrics") => Response::builder()
+            .header(CONTENT_TYPE, "text/plain; version=0.0.4")
+            .body(Body::from(metrics::render())),
+        (&Method::GET, "/healthz") => report(health::REGISTRY.liveness()),
+        (&Method::GET, "/readyz") => report(health::REGISTRY.readiness(max_idle)),
+        _ => Response::builder()
+            .status(StatusCode::NOT_FOUND)
+            .body(Body::from("not found\n")),
+    };
+
+    Ok(response.expect("response is valid. This is a bug"))
+}
+
+fn report(report: Report) -> Result<Response<Body>, hyper::http::Error> {
+    let status = if report.passed() {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+
+    Response::builder()
+        .status(status)
+        .header(CONTENT_TYPE, "text/plain")
+        .body(Body::from(report.to_string()))
+}
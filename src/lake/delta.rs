@@ -0,0 +1,512 @@
+use crate::config;
+use crate::lake::{Column, Commit, Error, Format, Kind, Warehouse};
+use crate::metrics;
+use crate::storage::Expeditor;
+use arrow::datatypes::{DataType, Field, Fields, Schema};
+use serde_json::{json, Value};
+use std::collections::{BTreeMap, HashMap};
+use std::sync::Arc;
+use tokio::sync::Mutex;
+use uuid::Uuid;
+
+// Delta maintains a Delta table for every replicated table:
+//
+//   {warehouse}/{schema}/{table}/_delta_log/{version}.json
+//   {warehouse}/{schema}/{table}/_delta_log/{version}.checkpoint.parquet
+//   {warehouse}/{schema}/{table}/_delta_log/_last_checkpoint
+//
+// Every commit is a new JSON file in the log, created only if no other writer
+// created that version first. As each file is written at once, readers either see
+// a commit entirely or not at all. Data files stay where intake shipped them and
+// are added with their absolute URI.
+//
+// Every `checkpoint_interval` versions, the state of the table is written to a
+// parquet checkpoint so readers, and intake itself, don't replay the whole log.
+//
+//   lake:
+//     format: delta
+//     checkpoint_interval: 10
+pub(crate) struct Delta {
+    warehouse: Warehouse,
+    checkpoint_interval: i64,
+
+    // State of the tables committed to, keyed by their directory.
+    tables: Mutex<HashMap<String, Table>>,
+}
+
+// Table is the state of a table at a version, as replayed from its log.
+#[derive(Default)]
+struct Table {
+    version: Option<i64>,
+    protocol: Option<Value>,
+    metadata: Option<Value>,
+    files: BTreeMap<String, Value>,
+}
+
+pub(crate) fn new(config: &config::Delta, catalog: Arc<dyn Expeditor>) -> Delta {
+    Delta {
+        warehouse: Warehouse::new(&config.warehouse, config.location.as_deref(), catalog),
+        checkpoint_interval: config.checkpoint_interval,
+        tables: Mutex::new(HashMap::new()),
+    }
+}
+
+impl From<arrow::error::ArrowError> for Error {
+    fn from(e: arrow::error::ArrowError) -> Self {
+        Error::FormatError(e.to_string())
+    }
+}
+
+impl From<parquet::errors::ParquetError> for Error {
+    fn from(e: parquet::errors::ParquetError) -> Self {
+        Error::FormatError(e.to_string())
+    }
+}
+
+impl Kind {
+    fn delta(&self) -> &'static str {
+        match self {
+            Kind::Long => "long",
+            Kind::Float => "float",
+            Kind::String => "string",
+        }
+    }
+}
+
+impl Table {
+    fn apply(&mut self, action: &Value) {
+        if let Some(add) = action.get("add") {
+            if let Some(path) = add["path"].as_str() {
+                self.files.insert(path.to_string(), add.clone());
+            }
+        } else if let Some(remove) = action.get("remove") {
+            if let Some(path) = remove["path"].as_str() {
+                self.files.remove(path);
+            }
+        } else if let Some(metadata) = action.get("metaData") {
+            self.metadata = Some(metadata.clone());
+        } else if let Some(protocol) = action.get("protocol") {
+            self.protocol = Some(protocol.clone());
+        }
+    }
+
+    // Return a metaData action if the columns don't fit in the table's schema.
+    // Columns are only ever added, at the end of the schema.
+    fn evolve(&self, name: &str, columns: &[Column]) -> Result<Option<Value>, Error> {
+        let mut fields: Vec<Value> = match self.metadata.as_ref() {
+            Some(metadata) => {
+                let schema: Value =
+                    serde_json::from_str(metadata["schemaString"].as_str().unwrap_or("{}"))?;
+                schema["fields"].as_array().cloned().unwrap_or_default()
+            }
+            None => Vec::new(),
+        };
+
+        let mut changed = self.metadata.is_none();
+        for column in columns.iter() {
+            match fields
+                .iter()
+                .find(|field| field["name"] == column.name.as_str())
+            {
+                Some(field) if field["type"] == column.kind.delta() => {}
+                Some(field) => {
+                    return Err(Error::SchemaError(format!(
+                        "column {} changed from {} to {}, which Delta can't evolve to",
+                        column.name,
+                        field["type"],
+                        column.kind.delta()
+                    )))
+                }
+                None => {
+                    fields.push(json!({
+                        "name": column.name,
+                        "type": column.kind.delta(),
+                        "nullable": true,
+                        "metadata": {},
+                    }));
+                    changed = true;
+                }
+            }
+        }
+
+        if !changed {
+            return Ok(None);
+        }
+
+        let schema = json!({"type": "struct", "fields": fields}).to_string();
+        let metadata = match self.metadata.clone() {
+            Some(mut metadata) => {
+                metadata["schemaString"] = Value::from(schema);
+                metadata
+            }
+            None => json!({
+                "id": Uuid::new_v4(),
+                "name": name,
+                "format": {"provider": "parquet", "options": {}},
+                "schemaString": schema,
+                "partitionColumns": [],
+                "configuration": {},
+                "createdTime": chrono::Utc::now().timestamp_millis(),
+            }),
+        };
+
+        Ok(Some(metadata))
+    }
+
+    // Actions describing the whole table, as written in checkpoints.
+    fn actions(&self) -> Vec<Value> {
+        let mut actions = Vec::new();
+        if let Some(protocol) = self.protocol.as_ref() {
+            actions.push(json!({ "protocol": protocol }));
+        }
+        if let Some(metadata) = self.metadata.as_ref() {
+            actions.push(json!({ "metaData": metadata }));
+        }
+        for add in self.files.values() {
+            actions.push(json!({ "add": add }));
+        }
+
+        actions
+    }
+}
+
+fn log_key(base: &str, version: i64) -> String {
+    format!("{}/_delta_log/{:020}.json", base, version)
+}
+
+fn checkpoint_key(base: &str, version: i64) -> String {
+    format!("{}/_delta_log/{:020}.checkpoint.parquet", base, version)
+}
+
+// Schema of checkpoints, limited to the actions intake writes.
+fn checkpoint_schema() -> Schema {
+    let string = |name: &str| Field::new(name, DataType::Utf8, true);
+    let long = |name: &str| Field::new(name, DataType::Int64, true);
+    let map = |name: &str| {
+        let entries = Fields::from(vec![
+            Field::new("key", DataType::Utf8, false),
+            Field::new("value", DataType::Utf8, true),
+        ]);
+        Field::new(
+            name,
+            DataType::Map(
+                Arc::new(Field::new("key_value", DataType::Struct(entries), false)),
+                false,
+            ),
+            true,
+        )
+    };
+    let record = |name: &str, fields: Vec<Field>| {
+        Field::new(name, DataType::Struct(Fields::from(fields)), true)
+    };
+
+    Schema::new(vec![
+        record(
+            "protocol",
+            vec![
+                Field::new("minReaderVersion", DataType::Int32, true),
+                Field::new("minWriterVersion", DataType::Int32, true),
+            ],
+        ),
+        record(
+            "metaData",
+            vec![
+                string("id"),
+                string("name"),
+                string("description"),
+                record("format", vec![string("provider"), map("options")]),
+                string("schemaString"),
+                Field::new(
+                    "partitionColumns",
+                    DataType::List(Arc::new(Field::new("element", DataType::Utf8, true))),
+                    true,
+                ),
+                map("configuration"),
+                long("createdTime"),
+            ],
+        ),
+        record(
+            "add",
+            vec![
+                string("path"),
+                map("partitionValues"),
+                long("size"),
+                long("modificationTime"),
+                Field::new("dataChange", DataType::Boolean, true),
+                string("stats"),
+            ],
+        ),
+        record(
+            "remove",
+            vec![
+                string("path"),
+                long("deletionTimestamp"),
+                Field::new("dataChange", DataType::Boolean, true),
+            ],
+        ),
+    ])
+}
+
+impl Delta {
+    // Bring the table up to date with its log, starting from the last checkpoint
+    // when the table was never loaded.
+    async fn refresh(&self, base: &str, table: &mut Table) -> Result<(), Error> {
+        let catalog = &self.warehouse.catalog;
+
+        if table.version.is_none() {
+            let last = catalog
+                .get(&format!("{}/_delta_log/_last_checkpoint", base))
+                .await?;
+            if let Some(last) = last {
+                let version = serde_json::from_slice::<Value>(&last)?["version"]
+                    .as_i64()
+                    .ok_or_else(|| {
+                        Error::FormatError(format!("invalid _last_checkpoint for {}", base))
+                    })?;
+                for action in self.read_checkpoint(base, version).await? {
+                    table.apply(&action);
+                }
+                table.version = Some(version);
+            }
+        }
+
+        loop {
+            let next = table.version.map(|version| version + 1).unwrap_or(0);
+            let content = match catalog.get(&log_key(base, next)).await? {
+                Some(content) => content,
+                None => return Ok(()),
+            };
+
+            for line in content.split(|byte| *byte == b'\n') {
+                if !line.iter().all(u8::is_ascii_whitespace) {
+                    table.apply(&serde_json::from_slice(line)?);
+                }
+            }
+            table.version = Some(next);
+        }
+    }
+
+    async fn read_checkpoint(&self, base: &str, version: i64) -> Result<Vec<Value>, Error> {
+        use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+
+        let key = checkpoint_key(base, version);
+        let content = self
+            .warehouse
+            .catalog
+            .get(&key)
+            .await?
+            .ok_or_else(|| Error::FormatError(format!("checkpoint {} is missing", key)))?;
+
+        let reader =
+            ParquetRecordBatchReaderBuilder::try_new(bytes::Bytes::from(content))?.build()?;
+
+        let mut actions = Vec::new();
+        for batch in reader {
+            let mut writer = arrow::json::ArrayWriter::new(Vec::new());
+            writer.write(&batch?)?;
+            writer.finish()?;
+            let rows: Vec<Value> = serde_json::from_slice(&writer.into_inner())?;
+            actions.extend(rows);
+        }
+
+        Ok(actions)
+    }
+
+    async fn checkpoint(&self, base: &str, table: &Table) -> Result<(), Error> {
+        let version = table
+            .version
+            .expect("checkpoints are only written after a commit. This is a bug");
+        let actions = table.actions();
+
+        let schema = Arc::new(checkpoint_schema());
+        let mut decoder = arrow::json::ReaderBuilder::new(schema.clone()).build_decoder()?;
+        decoder.serialize(&actions)?;
+
+        let mut writer = parquet::arrow::ArrowWriter::try_new(Vec::new(), schema, None)?;
+        if let Some(batch) = decoder.flush()? {
+            writer.write(&batch)?;
+        }
+        let content = writer.into_inner()?;
+
+        let catalog = &self.warehouse.catalog;
+        catalog.put(&checkpoint_key(base, version), content).await?;
+        catalog
+            .put(
+                &format!("{}/_delta_log/_last_checkpoint", base),
+                json!({"version": version, "size": actions.len()})
+                    .to_string()
+                    .into_bytes(),
+            )
+            .await?;
+
+        Ok(())
+    }
+}
+
+#[async_trait::async_trait]
+impl Format for Delta {
+    async fn commit(&self, commit: &Commit) -> Result<(), Error> {
+        let base = self.warehouse.directory(&commit.table)?;
+        let mut tables = self.tables.lock().await;
+        let table = tables.entry(base.clone()).or_default();
+
+        loop {
+            self.refresh(&base, table).await?;
+
+            let files: Vec<String> = commit
+                .files
+                .iter()
+                .map(|file| self.warehouse.uri(&file.key))
+                .collect();
+            if files.iter().all(|file| table.files.contains_key(file)) {
+                return Ok(());
+            }
+
+            let now = chrono::Utc::now().timestamp_millis();
+            let mut actions = Vec::new();
+            if table.version.is_none() {
+                actions.push(json!({"protocol": {"minReaderVersion": 1, "minWriterVersion": 2}}));
+            }
+            if let Some(metadata) = table.evolve(&commit.table, &commit.columns)? {
+                actions.push(json!({ "metaData": metadata }));
+            }
+            for (file, uri) in commit.files.iter().zip(files) {
+                actions.push(json!({"add": {
+                    "path": uri,
+                    "partitionValues": {},
+                    "size": file.bytes,
+                    "modificationTime": now,
+                    "dataChange": true,
+                    "stats": json!({"numRecords": file.rows}).to_string(),
+                }}));
+            }
+            actions.push(json!({"commitInfo": {
+                "timestamp": now,
+                "operation": "WRITE",
+                "operationParameters": {"mode": "Append"},
+                "engineInfo": format!("intake/{}", env!("CARGO_PKG_VERSION")),
+                "txnId": commit.id,
+            }}));
+
+            let version = table.version.map(|version| version + 1).unwrap_or(0);
+            let content = actions
+                .iter()
+                .map(|action| action.to_string())
+                .collect::<Vec<String>>()
+                .join("\n");
+
+            if !self
+                .warehouse
+                .catalog
+                .create(&log_key(&base, version), content.into_bytes())
+                .await?
+            {
+                log::info!(
+                    "{} was committed by another writer, retrying on top of it.",
+                    commit.table
+                );
+                metrics::LAKE_COMMITS.add(&[("table", &commit.table), ("result", "conflict")], 1.0);
+                continue;
+            }
+
+            for action in actions.iter() {
+                table.apply(action);
+            }
+            table.version = Some(version);
+
+            // The commit is done, a missing checkpoint only slows down readers.
+            if version > 0 && version % self.checkpoint_interval == 0 {
+                if let Err(e) = self.checkpoint(&base, table).await {
+                    log::warn!(
+                        "Could not checkpoint {} at {}: {}",
+                        commit.table,
+                        version,
+                        e
+                    );
+                }
+            }
+
+            return Ok(());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::lake::{Column, Commit, DataFile, Format, Kind};
+
+    fn commit(key: &str, columns: &[(&str, Kind)]) -> Commit {
+        Commit {
+            id: uuid::Uuid::new_v4(),
+            table: "public.users".into(),
+            columns: columns
+                .iter()
+                .map(|(name, kind)| Column {
+                    name: name.to_string(),
+                    kind: *kind,
+                })
+                .collect(),
+            files: vec![DataFile {
+                key: key.into(),
+                rows: 10,
+                bytes: 1024,
+                keys: Vec::new(),
+            }],
+        }
+    }
+
+    #[tokio::test]
+    async fn commits_and_checkpoints_to_a_local_table() {
+        let root = std::env::temp_dir().join(format!("intake-delta-{}", uuid::Uuid::new_v4()));
+        let config = serde_yaml::from_str("checkpoint_interval: 2").unwrap();
+        let delta = super::new(&config, crate::storage::local(&root));
+
+        let first = commit("public/users/1.parquet", &[("id", Kind::Long)]);
+        delta.commit(&first).await.unwrap();
+        // Applying the same commit again is a no-op.
+        delta.commit(&first).await.unwrap();
+
+        delta
+            .commit(&commit("public/users/2.parquet", &[("id", Kind::Long)]))
+            .await
+            .unwrap();
+        delta
+            .commit(&commit(
+                "public/users/3.parquet",
+                &[("id", Kind::Long), ("email", Kind::String)],
+            ))
+            .await
+            .unwrap();
+
+        assert!(root
+            .join("delta/public/users/_delta_log/00000000000000000002.checkpoint.parquet")
+            .exists());
+
+        // A new writer starts from the checkpoint.
+        let reloaded = super::new(&config[0], crate::storage::local(&root)).unwrap();
+        let mut table = super::Table::default();
+        reloaded
+            .refresh("delta/public/users", &mut table)
+            .await
+            .unwrap();
+
+        assert_eq!(table.version, Some(2));
+        assert_eq!(table.files.len(), 3);
+        let schema = table.metadata.unwrap()["schemaString"]
+            .as_str()
+            .unwrap()
+            .to_string();
+        assert!(schema.contains("\"email\""));
+
+        assert!(reloaded
+            .commit(&commit("public/users/3.parquet", &[("id", Kind::String)]))
+            .await
+            .is_ok());
+        assert!(reloaded
+            .commit(&commit("public/users/4.parquet", &[("id", Kind::String)]))
+            .await
+            .is_err());
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,815 @@
+use crate::config;
+use crate::lake::{Column, Commit, DataFile, Error, Format, Kind, Warehouse};
+use crate::metrics;
+use crate::storage::Expeditor;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+use std::collections::BTreeMap;
+use std::sync::Arc;
+use uuid::Uuid;
+
+const FORMAT_VERSION: i32 = 2;
+const NAME_MAPPING: &str = "schema.name-mapping.default";
+const COMMIT_ID: &str = "intake.commit-id";
+
+// Iceberg maintains an Iceberg table for every replicated table, laid out like
+// tables of a Hadoop catalog so engines can load them directly:
+//
+//   {warehouse}/{schema}/{table}/metadata/v{N}.metadata.json
+//   {warehouse}/{schema}/{table}/metadata/version-hint.text
+//
+// A new version is committed by creating the next metadata file, which only succeeds
+// if no other writer created it first. The hint is updated afterward, readers don't
+// rely on it being current. Data files are registered where intake shipped them.
+//
+// Field ids are assigned the first time a column is seen and never change. The parquet
+// files intake writes don't carry field ids, so every table has a name mapping that
+// resolves columns to their ids. Tables are unpartitioned: partitions only shape the
+// paths of the files.
+pub(crate) struct Iceberg {
+    warehouse: Warehouse,
+}
+
+pub(crate) fn new(config: &config::Iceberg, catalog: Arc<dyn Expeditor>) -> Iceberg {
+    Iceberg {
+        warehouse: Warehouse::new(&config.warehouse, config.location.as_deref(), catalog),
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "kebab-case")]
+struct Metadata {
+    format_version: i32,
+    table_uuid: Uuid,
+    location: String,
+    last_sequence_number: i64,
+    last_updated_ms: i64,
+    last_column_id: i32,
+    current_schema_id: i32,
+    schemas: Vec<Schema>,
+    default_spec_id: i32,
+    partition_specs: Vec<Value>,
+    last_partition_id: i32,
+    default_sort_order_id: i32,
+    sort_orders: Vec<Value>,
+    #[serde(default)]
+    properties: BTreeMap<String, String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    current_snapshot_id: Option<i64>,
+    #[serde(default)]
+    refs: Map<String, Value>,
+    #[serde(default)]
+    snapshots: Vec<Snapshot>,
+    #[serde(default)]
+    snapshot_log: Vec<Value>,
+    #[serde(default)]
+    metadata_log: Vec<Value>,
+
+    // Anything intake doesn't manage is kept as is.
+    #[serde(flatten)]
+    other: Map<String, Value>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "kebab-case")]
+struct Schema {
+    #[serde(rename = "type")]
+    kind: String,
+    schema_id: i32,
+    fields: Vec<Field>,
+    #[serde(flatten)]
+    other: Map<String, Value>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct Field {
+    id: i32,
+    name: String,
+    required: bool,
+    #[serde(rename = "type")]
+    kind: Value,
+    #[serde(flatten)]
+    other: Map<String, Value>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "kebab-case")]
+struct Snapshot {
+    snapshot_id: i64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    parent_snapshot_id: Option<i64>,
+    #[serde(default)]
+    sequence_number: i64,
+    timestamp_ms: i64,
+    manifest_list: String,
+    #[serde(default)]
+    summary: BTreeMap<String, String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    schema_id: Option<i32>,
+    #[serde(flatten)]
+    other: Map<String, Value>,
+}
+
+impl Kind {
+    fn iceberg(&self) -> &'static str {
+        match self {
+            Kind::Long => "long",
+            Kind::Float => "float",
+            Kind::String => "string",
+        }
+    }
+}
+
+impl Metadata {
+    fn new(location: String) -> Metadata {
+        Metadata {
+            format_version: FORMAT_VERSION,
+            table_uuid: Uuid::new_v4(),
+            location,
+            last_sequence_number: 0,
+            last_updated_ms: chrono::Utc::now().timestamp_millis(),
+            last_column_id: 0,
+            current_schema_id: 0,
+            schemas: vec![Schema {
+                kind: "struct".into(),
+                schema_id: 0,
+                fields: Vec::new(),
+                other: Map::new(),
+            }],
+            default_spec_id: 0,
+            partition_specs: vec![json!({"spec-id": 0, "fields": []})],
+            last_partition_id: 999,
+            default_sort_order_id: 0,
+            sort_orders: vec![json!({"order-id": 0, "fields": []})],
+            properties: BTreeMap::new(),
+            current_snapshot_id: None,
+            refs: Map::new(),
+            snapshots: Vec::new(),
+            snapshot_log: Vec::new(),
+            metadata_log: Vec::new(),
+            other: Map::new(),
+        }
+    }
+
+    fn schema(&self) -> Result<&Schema, Error> {
+        self.schemas
+            .iter()
+            .find(|schema| schema.schema_id == self.current_schema_id)
+            .ok_or_else(|| {
+                Error::SchemaError(format!("schema {} is missing", self.current_schema_id))
+            })
+    }
+
+    // Some writers use -1 when there's no current snapshot.
+    fn current_snapshot(&self) -> Option<&Snapshot> {
+        let id = self.current_snapshot_id.filter(|id| *id >= 0)?;
+        self.snapshots
+            .iter()
+            .find(|snapshot| snapshot.snapshot_id == id)
+    }
+
+    // Add the columns that are missing from the current schema, with new field ids,
+    // and return the id of the schema that includes all the columns.
+    fn evolve(&mut self, columns: &[Column]) -> Result<i32, Error> {
+        let current = self.schema()?.clone();
+        let mut fields = current.fields.clone();
+
+        for column in columns.iter() {
+            let kind = json!(column.kind.iceberg());
+            match fields.iter().find(|field| field.name == column.name) {
+                Some(field) if field.kind == kind => {}
+                Some(field) => {
+                    return Err(Error::SchemaError(format!(
+                        "column {} changed from {} to {}, which Iceberg can't evolve to",
+                        column.name, field.kind, kind
+                    )))
+                }
+                None => {
+                    self.last_column_id += 1;
+                    fields.push(Field {
+                        id: self.last_column_id,
+                        name: column.name.clone(),
+                        required: false,
+                        kind,
+                        other: Map::new(),
+                    });
+                }
+            }
+        }
+
+        if fields.len() == current.fields.len() {
+            return Ok(self.current_schema_id);
+        }
+
+        if current.fields.is_empty() && self.snapshots.is_empty() {
+            // A new table gets its first schema.
+            let schema = self
+                .schemas
+                .iter_mut()
+                .find(|schema| schema.schema_id == self.current_schema_id)
+                .expect("schema was just retrieved. This is a bug");
+            schema.fields = fields.clone();
+        } else {
+            let id = self
+                .schemas
+                .iter()
+                .map(|schema| schema.schema_id)
+                .max()
+                .unwrap_or(0)
+                + 1;
+            self.schemas.push(Schema {
+                kind: "struct".into(),
+                schema_id: id,
+                fields: fields.clone(),
+                other: Map::new(),
+            });
+            self.current_schema_id = id;
+        }
+
+        let mapping: Vec<Value> = fields
+            .iter()
+            .map(|field| json!({"field-id": field.id, "names": [field.name]}))
+            .collect();
+        self.properties
+            .insert(NAME_MAPPING.to_string(), Value::from(mapping).to_string());
+
+        Ok(self.current_schema_id)
+    }
+}
+
+// Avro schemas of manifests and manifest lists, for format version 2. Only the
+// required fields are written.
+const MANIFEST_ENTRY: &str = r#"{
+  "type": "record",
+  "name": "manifest_entry",
+  "fields": [
+    {"name": "status", "type": "int", "field-id": 0},
+    {"name": "snapshot_id", "type": ["null", "long"], "default": null, "field-id": 1},
+    {"name": "sequence_number", "type": ["null", "long"], "default": null, "field-id": 3},
+    {"name": "file_sequence_number", "type": ["null", "long"], "default": null, "field-id": 4},
+    {"name": "data_file", "field-id": 2, "type": {
+      "type": "record",
+      "name": "r2",
+      "fields": [
+        {"name": "content", "type": "int", "field-id": 134},
+        {"name": "file_path", "type": "string", "field-id": 100},
+        {"name": "file_format", "type": "string", "field-id": 101},
+        {"name": "partition", "field-id": 102, "type": {"type": "record", "name": "r102", "fields": []}},
+        {"name": "record_count", "type": "long", "field-id": 103},
+        {"name": "file_size_in_bytes", "type": "long", "field-id": 104},
+        {"name": "equality_ids", "type": ["null", {"type": "array", "items": "int", "element-id": 136}], "default": null, "field-id": 135}
+      ]
+    }}
+  ]
+}"#;
+
+const MANIFEST_FILE: &str = r#"{
+  "type": "record",
+  "name": "manifest_file",
+  "fields": [
+    {"name": "manifest_path", "type": "string", "field-id": 500},
+    {"name": "manifest_length", "type": "long", "field-id": 501},
+    {"name": "partition_spec_id", "type": "int", "field-id": 502},
+    {"name": "content", "type": "int", "field-id": 517},
+    {"name": "sequence_number", "type": "long", "field-id": 515},
+    {"name": "min_sequence_number", "type": "long", "field-id": 516},
+    {"name": "added_snapshot_id", "type": "long", "field-id": 503},
+    {"name": "added_files_count", "type": "int", "field-id": 504},
+    {"name": "existing_files_count", "type": "int", "field-id": 505},
+    {"name": "deleted_files_count", "type": "int", "field-id": 506},
+    {"name": "added_rows_count", "type": "long", "field-id": 512},
+    {"name": "existing_rows_count", "type": "long", "field-id": 513},
+    {"name": "deleted_rows_count", "type": "long", "field-id": 514}
+  ]
+}"#;
+
+#[derive(Serialize)]
+struct ManifestEntry {
+    status: i32,
+    snapshot_id: Option<i64>,
+    // Added entries inherit their sequence numbers from the manifest list.
+    sequence_number: Option<i64>,
+    file_sequence_number: Option<i64>,
+    data_file: DataFileEntry,
+}
+
+#[derive(Serialize)]
+struct DataFileEntry {
+    content: i32,
+    file_path: String,
+    file_format: String,
+    partition: Partition,
+    record_count: i64,
+    file_size_in_bytes: i64,
+    // Field ids of the key columns of equality delete files.
+    equality_ids: Option<Vec<i32>>,
+}
+
+#[derive(Serialize)]
+struct Partition {}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct ManifestFile {
+    manifest_path: String,
+    manifest_length: i64,
+    partition_spec_id: i32,
+    #[serde(default)]
+    content: i32,
+    #[serde(default)]
+    sequence_number: i64,
+    #[serde(default)]
+    min_sequence_number: i64,
+    added_snapshot_id: i64,
+    #[serde(default, alias = "added_data_files_count")]
+    added_files_count: i32,
+    #[serde(default, alias = "existing_data_files_count")]
+    existing_files_count: i32,
+    #[serde(default, alias = "deleted_data_files_count")]
+    deleted_files_count: i32,
+    #[serde(default)]
+    added_rows_count: i64,
+    #[serde(default)]
+    existing_rows_count: i64,
+    #[serde(default)]
+    deleted_rows_count: i64,
+}
+
+impl From<apache_avro::Error> for Error {
+    fn from(e: apache_avro::Error) -> Self {
+        Error::FormatError(e.to_string())
+    }
+}
+
+// Return an Avro file with the records, written with the schema and file metadata.
+fn avro<T: Serialize>(
+    schema: &str,
+    metadata: &[(&str, String)],
+    records: &[T],
+) -> Result<Vec<u8>, Error> {
+    let schema = apache_avro::Schema::parse_str(schema)?;
+    let mut writer = apache_avro::Writer::new(&schema, Vec::new());
+
+    for (key, value) in metadata.iter() {
+        writer.add_user_metadata(key.to_string(), value)?;
+    }
+
+    for record in records.iter() {
+        writer.append(apache_avro::to_value(record)?.resolve(&schema)?)?;
+    }
+
+    Ok(writer.into_inner()?)
+}
+
+impl Iceberg {
+    // Return the current version of the table and its metadata. Version 0 means
+    // the table doesn't exist yet.
+    async fn current(&self, base: &str) -> Result<(u64, Option<Metadata>), Error> {
+        let hint = self
+            .warehouse
+            .catalog
+            .get(&format!("{}/metadata/version-hint.text", base))
+            .await?
+            .and_then(|hint| String::from_utf8_lossy(&hint).trim().parse().ok())
+            .unwrap_or(0);
+
+        let mut version = hint;
+        let mut content = match version {
+            0 => None,
+            _ => Some(
+                self.warehouse
+                    .catalog
+                    .get(&metadata_key(base, version))
+                    .await?
+                    .ok_or_else(|| {
+                        Error::FormatError(format!("{} is missing", metadata_key(base, version)))
+                    })?,
+            ),
+        };
+
+        // The hint is updated after the commit and can be behind.
+        while let Some(next) = self
+            .warehouse
+            .catalog
+            .get(&metadata_key(base, version + 1))
+            .await?
+        {
+            version += 1;
+            content = Some(next);
+        }
+
+        match content {
+            Some(content) => Ok((version, Some(serde_json::from_slice(&content)?))),
+            None => Ok((0, None)),
+        }
+    }
+
+    async fn manifests(&self, list: &str) -> Result<Vec<ManifestFile>, Error> {
+        let key = self.warehouse.key(list)?;
+        let content = self
+            .warehouse
+            .catalog
+            .get(key)
+            .await?
+            .ok_or_else(|| Error::FormatError(format!("manifest list {} is missing", list)))?;
+
+        let mut manifests = Vec::new();
+        for value in apache_avro::Reader::new(content.as_slice())? {
+            manifests.push(apache_avro::from_value(&value?)?);
+        }
+
+        Ok(manifests)
+    }
+
+    // Write the manifest of files added by a snapshot and return its entry in the
+    // manifest list. Files are either all data files or all equality delete files.
+    async fn manifest(
+        &self,
+        base: &str,
+        metadata: &Metadata,
+        schema_id: i32,
+        snapshot_id: i64,
+        sequence: i64,
+        files: &[&DataFile],
+    ) -> Result<ManifestFile, Error> {
+        let deletes = files.iter().any(|file| !file.keys.is_empty());
+        let schema = metadata.schema()?;
+
+        let mut entries = Vec::new();
+        for file in files.iter() {
+            let equality_ids = if deletes {
+                Some(
+                    file.keys
+                        .iter()
+                        .map(|key| {
+                            schema
+                                .fields
+                                .iter()
+                                .find(|field| field.name == *key)
+                                .map(|field| field.id)
+                                .ok_or_else(|| {
+                                    Error::SchemaError(format!("key column {} is missing", key))
+                                })
+                        })
+                        .collect::<Result<Vec<i32>, Error>>()?,
+                )
+            } else {
+                None
+            };
+
+            entries.push(ManifestEntry {
+                status: 1,
+                snapshot_id: Some(snapshot_id),
+                sequence_number: None,
+                file_sequence_number: None,
+                data_file: DataFileEntry {
+                    content: if deletes { 2 } else { 0 },
+                    file_path: self.warehouse.uri(&file.key),
+                    file_format: "PARQUET".into(),
+                    partition: Partition {},
+                    record_count: file.rows,
+                    file_size_in_bytes: file.bytes as i64,
+                    equality_ids,
+                },
+            });
+        }
+
+        let manifest = avro(
+            MANIFEST_ENTRY,
+            &[
+                ("schema", serde_json::to_string(schema)?),
+                ("schema-id", schema_id.to_string()),
+                ("partition-spec", "[]".into()),
+                ("partition-spec-id", "0".into()),
+                ("format-version", FORMAT_VERSION.to_string()),
+                ("content", if deletes { "deletes" } else { "data" }.into()),
+            ],
+            &entries,
+        )?;
+        let manifest_length = manifest.len() as i64;
+        let manifest_key = format!("{}/metadata/{}-m0.avro", base, Uuid::new_v4());
+        self.warehouse.catalog.put(&manifest_key, manifest).await?;
+
+        Ok(ManifestFile {
+            manifest_path: self.warehouse.uri(&manifest_key),
+            manifest_length,
+            partition_spec_id: 0,
+            content: if deletes { 1 } else { 0 },
+            sequence_number: sequence,
+            min_sequence_number: sequence,
+            added_snapshot_id: snapshot_id,
+            added_files_count: files.len() as i32,
+            existing_files_count: 0,
+            deleted_files_count: 0,
+            added_rows_count: files.iter().map(|file| file.rows).sum(),
+            existing_rows_count: 0,
+            deleted_rows_count: 0,
+        })
+    }
+
+    // Write the manifests and manifest list for the commit and add the snapshot to the
+    // metadata. Delete files and the data files of the same commit share a sequence
+    // number, so deletes only apply to rows committed before them.
+    async fn snapshot(
+        &self,
+        base: &str,
+        metadata: &mut Metadata,
+        schema_id: i32,
+        commit: &Commit,
+    ) -> Result<(), Error> {
+        let snapshot_id = (Uuid::new_v4().as_u64_pair().0 >> 1) as i64;
+        let sequence = metadata.last_sequence_number + 1;
+        let now = chrono::Utc::now().timestamp_millis();
+
+        let (deletes, data): (Vec<&DataFile>, Vec<&DataFile>) =
+            commit.files.iter().partition(|file| !file.keys.is_empty());
+
+        let parent = metadata.current_snapshot().map(|s| s.snapshot_id);
+        let mut manifests = match metadata.current_snapshot() {
+            Some(current) => self.manifests(&current.manifest_list).await?,
+            None => Vec::new(),
+        };
+
+        for files in [&data, &deletes] {
+            if !files.is_empty() {
+                manifests.push(
+                    self.manifest(base, metadata, schema_id, snapshot_id, sequence, files)
+                        .await?,
+                );
+            }
+        }
+
+        let list = avro(
+            MANIFEST_FILE,
+            &[
+                ("snapshot-id", snapshot_id.to_string()),
+                (
+                    "parent-snapshot-id",
+                    parent.map(|id| id.to_string()).unwrap_or("null".into()),
+                ),
+                ("sequence-number", sequence.to_string()),
+                ("format-version", FORMAT_VERSION.to_string()),
+            ],
+            &manifests,
+        )?;
+        let list_key = format!(
+            "{}/metadata/snap-{}-1-{}.avro",
+            base,
+            snapshot_id,
+            Uuid::new_v4()
+        );
+        self.warehouse.catalog.put(&list_key, list).await?;
+
+        let rows: i64 = data.iter().map(|file| file.rows).sum();
+        let deleted: i64 = deletes.iter().map(|file| file.rows).sum();
+        let bytes: u64 = commit.files.iter().map(|file| file.bytes).sum();
+        let mut summary = BTreeMap::from([
+            ("operation".to_string(), "append".to_string()),
+            ("added-data-files".into(), data.len().to_string()),
+            ("added-records".into(), rows.to_string()),
+            ("added-files-size".into(), bytes.to_string()),
+            (COMMIT_ID.into(), commit.id.to_string()),
+        ]);
+        if !deletes.is_empty() {
+            summary.insert("operation".into(), "overwrite".into());
+            summary.insert("added-delete-files".into(), deletes.len().to_string());
+            summary.insert(
+                "added-equality-delete-files".into(),
+                deletes.len().to_string(),
+            );
+            summary.insert("added-equality-deletes".into(), deleted.to_string());
+        }
+
+        metadata.snapshots.push(Snapshot {
+            snapshot_id,
+            parent_snapshot_id: parent,
+            sequence_number: sequence,
+            timestamp_ms: now,
+            manifest_list: self.warehouse.uri(&list_key),
+            summary,
+            schema_id: Some(schema_id),
+            other: Map::new(),
+        });
+        metadata
+            .snapshot_log
+            .push(json!({"timestamp-ms": now, "snapshot-id": snapshot_id}));
+        metadata.refs.insert(
+            "main".into(),
+            json!({"snapshot-id": snapshot_id, "type": "branch"}),
+        );
+        metadata.current_snapshot_id = Some(snapshot_id);
+        metadata.last_sequence_number = sequence;
+        metadata.last_updated_ms = now;
+
+        Ok(())
+    }
+}
+
+fn metadata_key(base: &str, version: u64) -> String {
+    format!("{}/metadata/v{}.metadata.json", base, version)
+}
+
+#[async_trait::async_trait]
+impl Format for Iceberg {
+    async fn commit(&self, commit: &Commit) -> Result<(), Error> {
+        let base = self.warehouse.directory(&commit.table)?;
+
+        loop {
+            let (version, current) = self.current(&base).await?;
+            let mut metadata = match current {
+                Some(metadata) => metadata,
+                None => Metadata::new(self.warehouse.uri(&base)),
+            };
+
+            let id = commit.id.to_string();
+            if metadata
+                .snapshots
+                .iter()
+                .any(|snapshot| snapshot.summary.get(COMMIT_ID) == Some(&id))
+            {
+                return Ok(());
+            }
+
+            if version > 0 {
+                metadata.metadata_log.push(json!({
+                    "timestamp-ms": metadata.last_updated_ms,
+                    "metadata-file": self.warehouse.uri(&metadata_key(&base, version)),
+                }));
+            }
+
+            let schema_id = metadata.evolve(&commit.columns)?;
+            self.snapshot(&base, &mut metadata, schema_id, commit)
+                .await?;
+
+            let next = version + 1;
+            let content = serde_json::to_vec_pretty(&metadata)?;
+            if self
+                .warehouse
+                .catalog
+                .create(&metadata_key(&base, next), content)
+                .await?
+            {
+                self.warehouse
+                    .catalog
+                    .put(
+                        &format!("{}/metadata/version-hint.text", base),
+                        next.to_string().into_bytes(),
+                    )
+                    .await?;
+                return Ok(());
+            }
+
+            log::info!(
+                "{} was committed by another writer, retrying on top of it.",
+                commit.table
+            );
+            metrics::LAKE_COMMITS.add(&[("table", &commit.table), ("result", "conflict")], 1.0);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::lake::{Column, Commit, DataFile, Format, Kind};
+
+    fn column(name: &str, kind: Kind) -> Column {
+        Column {
+            name: name.into(),
+            kind,
+        }
+    }
+
+    #[test]
+    fn field_ids_are_stable() {
+        let mut metadata = super::Metadata::new("file:///lake/public/users".into());
+
+        let id = metadata
+            .evolve(&[column("id", Kind::Long), column("name", Kind::String)])
+            .unwrap();
+        assert_eq!(id, 0);
+        assert_eq!(metadata.last_column_id, 2);
+
+        // Same columns, in a different order.
+        let id = metadata
+            .evolve(&[column("name", Kind::String), column("id", Kind::Long)])
+            .unwrap();
+        assert_eq!(id, 0);
+
+        metadata.snapshots.push(super::Snapshot {
+            snapshot_id: 1,
+            parent_snapshot_id: None,
+            sequence_number: 1,
+            timestamp_ms: 0,
+            manifest_list: String::new(),
+            summary: Default::default(),
+            schema_id: Some(0),
+            other: Default::default(),
+        });
+
+        let id = metadata
+            .evolve(&[column("email", Kind::String), column("id", Kind::Long)])
+            .unwrap();
+        assert_eq!(id, 1);
+
+        let fields: Vec<(i32, &str)> = metadata
+            .schema()
+            .unwrap()
+            .fields
+            .iter()
+            .map(|field| (field.id, field.name.as_str()))
+            .collect();
+        assert_eq!(fields, vec![(1, "id"), (2, "name"), (3, "email")]);
+
+        assert!(metadata.evolve(&[column("id", Kind::String)]).is_err());
+    }
+
+    #[tokio::test]
+    async fn commits_snapshots_to_a_local_catalog() {
+        let root = std::env::temp_dir().join(format!("intake-iceberg-{}", uuid::Uuid::new_v4()));
+        let catalog = crate::storage::local(&root);
+        let config = serde_yaml::from_str("warehouse: iceberg").unwrap();
+        let iceberg = super::new(&config, catalog);
+
+        let commit = Commit {
+            id: uuid::Uuid::new_v4(),
+            table: "public.users".into(),
+            columns: vec![column("id", Kind::Long)],
+            files: vec![DataFile {
+                key: "public/users/file.parquet".into(),
+                rows: 10,
+                bytes: 1024,
+                keys: Vec::new(),
+            }],
+        };
+
+        iceberg.commit(&commit).await.unwrap();
+        // Applying the same commit again is a no-op.
+        iceberg.commit(&commit).await.unwrap();
+
+        let second = Commit {
+            id: uuid::Uuid::new_v4(),
+            ..commit
+        };
+        iceberg.commit(&second).await.unwrap();
+
+        let (version, metadata) = iceberg.current("iceberg/public/users").await.unwrap();
+        let metadata = metadata.unwrap();
+        assert_eq!(version, 2);
+        assert_eq!(metadata.snapshots.len(), 2);
+        assert_eq!(metadata.last_sequence_number, 2);
+
+        let list = &metadata.current_snapshot().unwrap().manifest_list;
+        let manifests = iceberg.manifests(list).await.unwrap();
+        assert_eq!(manifests.len(), 2);
+        assert_eq!(manifests[1].added_rows_count, 10);
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn commits_equality_deletes() {
+        let root = std::env::temp_dir().join(format!("intake-iceberg-{}", uuid::Uuid::new_v4()));
+        let catalog = crate::storage::local(&root);
+        let config = serde_yaml::from_str("warehouse: iceberg").unwrap();
+        let iceberg = super::new(&config, catalog);
+
+        let file = |key: &str, keys: &[&str]| DataFile {
+            key: key.into(),
+            rows: 2,
+            bytes: 512,
+            keys: keys.iter().map(|key| key.to_string()).collect(),
+        };
+
+        iceberg
+            .commit(&Commit {
+                id: uuid::Uuid::new_v4(),
+                table: "public.users".into(),
+                columns: vec![column("id", Kind::Long), column("name", Kind::String)],
+                files: vec![
+                    file("public/users/file.rows.parquet", &[]),
+                    file("public/users/file.deletes.parquet", &["id"]),
+                ],
+            })
+            .await
+            .unwrap();
+
+        let (_, metadata) = iceberg.current("iceberg/public/users").await.unwrap();
+        let snapshot = metadata.as_ref().unwrap().current_snapshot().unwrap();
+        assert_eq!(snapshot.summary["operation"], "overwrite");
+        assert_eq!(snapshot.summary["added-equality-deletes"], "2");
+
+        let manifests = iceberg.manifests(&snapshot.manifest_list).await.unwrap();
+        let contents: Vec<i32> = manifests.iter().map(|manifest| manifest.content).collect();
+        assert_eq!(contents, vec![0, 1]);
+
+        // Keys must be columns of the table.
+        let invalid = Commit {
+            id: uuid::Uuid::new_v4(),
+            table: "public.users".into(),
+            columns: vec![column("id", Kind::Long)],
+            files: vec![file("public/users/other.deletes.parquet", &["email"])],
+        };
+        assert!(iceberg.commit(&invalid).await.is_err());
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,496 @@
+// Lake registers the files written by intake in open table formats so query
+// engines can read each replicated table as a table instead of a prefix full of
+// parquet files.
+//
+// Registering a file is a commit on the table. Commits are journaled on disk before
+// they are handed to the committer, which applies them one at a time, in the order
+// the files were written. When files are shipped to a storage, a commit waits until
+// its files are uploaded so tables never reference files that can't be read yet.
+//
+// Commits that fail because of the storage are retried until they succeed. Commits
+// that can never succeed as they are, like a column that changed type, park their
+// table: its commits are moved to the `failed` directory of the journal, in order,
+// while the other tables keep being committed. Once the table is repaired, moving
+// them back to the journal resumes the table on the next start.
+//
+// By default, tables hold the changes of their source table, one row per event. When
+// `mirror` is enabled, tables reflect the current state of their source instead: each
+// commit adds the latest version of the rows it changed along with a delete file that
+// removes their previous versions by primary key. Readers apply deletes when they read.
+
+use crate::config;
+use crate::events::output;
+use crate::metrics;
+use crate::storage::{self, queue::Queue, Expeditor};
+use parquet::basic::Type as PhysicalType;
+use parquet::schema::types::TypePtr;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+use thiserror::Error;
+use tokio::sync::{mpsc, watch};
+use uuid::Uuid;
+
+mod delta;
+mod iceberg;
+
+const BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(300);
+
+#[derive(Error, Debug)]
+pub(crate) enum Error {
+    #[error("storage error: `{0}`")]
+    StorageError(String),
+
+    #[error("schema error: `{0}`")]
+    SchemaError(String),
+
+    #[error("format error: `{0}`")]
+    FormatError(String),
+
+    #[error("file error: `{0}`")]
+    FileError(String),
+}
+
+impl Error {
+    // Whether the commit can't succeed by retrying it, the table or the commit have
+    // to be repaired first.
+    fn permanent(&self) -> bool {
+        matches!(self, Error::SchemaError(_) | Error::FormatError(_))
+    }
+}
+
+impl From<storage::Error> for Error {
+    fn from(e: storage::Error) -> Self {
+        Error::StorageError(e.to_string())
+    }
+}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        Error::FileError(e.to_string())
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(e: serde_json::Error) -> Self {
+        Error::FormatError(e.to_string())
+    }
+}
+
+// Format of the tables maintained in the lake.
+#[async_trait::async_trait]
+pub(crate) trait Format: Send + Sync {
+    // Apply the commit to its table, creating the table if needed. Applying a
+    // commit that was already applied has no effect.
+    async fn commit(&self, commit: &Commit) -> Result<(), Error>;
+}
+
+// Commit registers files written for a table. Columns describe the schema the
+// files were written with.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub(crate) struct Commit {
+    pub id: Uuid,
+    pub table: String,
+    pub columns: Vec<Column>,
+    pub files: Vec<DataFile>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub(crate) struct Column {
+    pub name: String,
+    pub kind: Kind,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub(crate) enum Kind {
+    Long,
+    Float,
+    String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub(crate) struct DataFile {
+    // Key of the file, relative to the storage's root.
+    pub key: String,
+    pub rows: i64,
+    pub bytes: u64,
+
+    // Columns of an equality delete file: rows of the table that match a row of the
+    // file on these columns are deleted. Empty for data files.
+    #[serde(default)]
+    pub keys: Vec<String>,
+}
+
+// Return the columns of a parquet definition.
+pub(crate) fn columns(types: &TypePtr) -> Vec<Column> {
+    types
+        .get_fields()
+        .iter()
+        .map(|field| Column {
+            name: field.name().to_string(),
+            kind: match field.get_physical_type() {
+                PhysicalType::INT64 => Kind::Long,
+                PhysicalType::FLOAT => Kind::Float,
+                _ => Kind::String,
+            },
+        })
+        .collect()
+}
+
+// Warehouse is where the tables of a lake are stored, with keys relative to the
+// catalog's root. Files are referenced by their URI, under location.
+pub(crate) struct Warehouse {
+    pub catalog: Arc<dyn Expeditor>,
+    prefix: String,
+    location: String,
+}
+
+impl Warehouse {
+    // Location defaults to the catalog's own location.
+    fn new(prefix: &str, location: Option<&str>, catalog: Arc<dyn Expeditor>) -> Warehouse {
+        let location = match location {
+            Some(location) => location.trim_end_matches('/').to_string(),
+            None => catalog.location(),
+        };
+
+        Warehouse {
+            catalog,
+            prefix: prefix.trim_matches('/').to_string(),
+            location,
+        }
+    }
+
+    // Key of the table's directory, `{warehouse}/{schema}/{table}`.
+    pub(crate) fn directory(&self, table: &str) -> Result<String, Error> {
+        let (namespace, name) = table
+            .split_once('.')
+            .ok_or_else(|| Error::SchemaError(format!("{} is not schema.table", table)))?;
+
+        if self.prefix.is_empty() {
+            return Ok(format!("{}/{}", namespace, name));
+        }
+
+        Ok(format!("{}/{}/{}", self.prefix, namespace, name))
+    }
+
+    pub(crate) fn uri(&self, key: &str) -> String {
+        format!("{}/{}", self.location, key)
+    }
+
+    pub(crate) fn key<'a>(&self, uri: &'a str) -> Result<&'a str, Error> {
+        uri.strip_prefix(&self.location)
+            .map(|key| key.trim_start_matches('/'))
+            .ok_or_else(|| Error::FormatError(format!("{} is outside of {}", uri, self.location)))
+    }
+}
+
+pub(crate) struct Lake {
+    mirror: bool,
+    journal: PathBuf,
+    sequence: AtomicU64,
+    sender: mpsc::UnboundedSender<(u64, PathBuf, Commit)>,
+
+    // Number of commits applied, commits are applied in the order of their sequence.
+    applied: watch::Receiver<u64>,
+}
+
+// Start the lake configured in the `lake` block and resume the commits journaled by
+// a previous process. Tables are stored with the Expeditor, or in the output directory
+// when no storage is configured. Return None when no lake is configured.
+//
+//   lake:
+//     format: iceberg               # iceberg or delta
+//     warehouse: iceberg            # key prefix of the tables
+//     location: s3://bucket/prefix  # optional, URI of the storage's root
+//     journal: .intake-lake
+//     checkpoint_interval: 10       # delta only, versions between checkpoints
+//     mirror: false                 # iceberg only, apply updates and deletes
+pub(crate) async fn start(
+    config: Option<&config::Lake>,
+    output: &config::Output,
+    expeditor: Option<Arc<dyn Expeditor>>,
+    queue: Option<Arc<Queue>>,
+) -> Result<Option<Arc<Lake>>, Error> {
+    let config = match config {
+        Some(config) => config,
+        None => return Ok(None),
+    };
+
+    let catalog = expeditor.unwrap_or_else(|| storage::local(&output.directory));
+
+    let format: Arc<dyn Format> = match config {
+        config::Lake::Iceberg(config) => Arc::new(iceberg::new(config, catalog)),
+        config::Lake::Delta(config) => Arc::new(delta::new(config, catalog)),
+    };
+
+    let journal = config.journal().to_path_buf();
+    let failed = journal.join("failed");
+    tokio::fs::create_dir_all(&failed).await?;
+
+    let commits = recover(&journal)?;
+    let parked = recover(&failed)?;
+    let next = commits
+        .iter()
+        .chain(parked.iter())
+        .map(|(sequence, _, _)| sequence + 1)
+        .max()
+        .unwrap_or(0);
+
+    let first = commits
+        .first()
+        .map(|(sequence, _, _)| *sequence)
+        .unwrap_or(next);
+    let (sender, receiver) = mpsc::unbounded_channel();
+    let (applied, watcher) = watch::channel(first);
+    let parked: HashSet<String> = parked.into_iter().map(|(_, _, c)| c.table).collect();
+    for table in parked.iter() {
+        log::warn!("{} is parked, see {:?}.", table, failed);
+    }
+    tokio::spawn(work(format, queue, receiver, applied, failed, parked));
+
+    if !commits.is_empty() {
+        log::info!("Resuming {} pending commits.", commits.len());
+    }
+    for commit in commits {
+        sender
+            .send(commit)
+            .map_err(|e| Error::FileError(e.to_string()))?;
+    }
+
+    Ok(Some(Arc::new(Lake {
+        mirror: config.mirror(),
+        journal,
+        sequence: AtomicU64::new(next),
+        sender,
+        applied: watcher,
+    })))
+}
+
+impl Lake {
+    // Whether tables reflect the current state of their source instead of its changes.
+    pub(crate) fn mirror(&self) -> bool {
+        self.mirror
+    }
+
+    // Journal the commit and hand it over to the committer. Return the sequence of the
+    // commit.
+    pub(crate) async fn append(&self, commit: Commit) -> Result<u64, Error> {
+        let sequence = self.sequence.fetch_add(1, Ordering::SeqCst);
+        let path = self.journal.join(format!("{:020}.json", sequence));
+        let content = serde_json::to_vec(&commit)?;
+
+        let journaled = path.clone();
+        tokio::task::spawn_blocking(move || output::write(&journaled, &content))
+            .await
+            .map_err(|e| Error::FileError(e.to_string()))??;
+
+        self.sender
+            .send((sequence, path, commit))
+            .map_err(|e| Error::FileError(e.to_string()))?;
+
+        Ok(sequence)
+    }
+
+    // Wait until the commit with sequence is applied, or its table parked.
+    pub(crate) async fn committed(&self, sequence: u64) {
+        let mut applied = self.applied.clone();
+
+        while *applied.borrow_and_update() <= sequence {
+            if applied.changed().await.is_err() {
+                return;
+            }
+        }
+    }
+
+    // Wait until every commit appended so far is applied.
+    pub(crate) async fn applied(&self) {
+        let appended = self.sequence.load(Ordering::SeqCst);
+        let mut applied = self.applied.clone();
+
+        while *applied.borrow_and_update() < appended {
+            if applied.changed().await.is_err() {
+                return;
+            }
+        }
+    }
+}
+
+// Apply the commits in order. Commits of parked tables are moved to failed.
+async fn work(
+    format: Arc<dyn Format>,
+    queue: Option<Arc<Queue>>,
+    mut receiver: mpsc::UnboundedReceiver<(u64, PathBuf, Commit)>,
+    applied: watch::Sender<u64>,
+    failed: PathBuf,
+    mut parked: HashSet<String>,
+) {
+    while let Some((sequence, path, commit)) = receiver.recv().await {
+        if !parked.contains(&commit.table) {
+            if let Err(e) = uploaded(queue.as_deref(), &commit).await {
+                log::error!(
+                    "Commit to {} references a file that can't be uploaded: {}. Parking the table in {:?}.",
+                    commit.table, e, failed
+                );
+                parked.insert(commit.table.clone());
+            } else if let Err(e) = apply(format.as_ref(), &commit).await {
+                log::error!(
+                    "Commit to {} can't be applied: {}. Parking the table in {:?}.",
+                    commit.table,
+                    e,
+                    failed
+                );
+                parked.insert(commit.table.clone());
+            }
+            if parked.contains(&commit.table) {
+                metrics::LAKE_COMMITS.add(&[("table", &commit.table), ("result", "parked")], 1.0);
+            }
+        }
+
+        let result = if parked.contains(&commit.table) {
+            let name = path.file_name().expect("commits are journaled as files");
+            tokio::fs::rename(&path, failed.join(name)).await
+        } else {
+            tokio::fs::remove_file(&path).await
+        };
+        if let Err(e) = result {
+            log::warn!("Could not remove the journaled commit {:?}: {}", path, e);
+        }
+        let _ = applied.send(sequence + 1);
+    }
+}
+
+// Wait until the files of the commit are uploaded, when they're shipped with a queue.
+async fn uploaded(queue: Option<&Queue>, commit: &Commit) -> Result<(), storage::Error> {
+    if let Some(queue) = queue {
+        for file in commit.files.iter() {
+            queue.uploaded(&file.key).await?;
+        }
+    }
+
+    Ok(())
+}
+
+// Apply the commit, retrying until it succeeds or fails permanently.
+async fn apply(format: &dyn Format, commit: &Commit) -> Result<(), Error> {
+    let mut delay = BACKOFF;
+
+    loop {
+        match format.commit(commit).await {
+            Ok(()) => {
+                metrics::LAKE_COMMITS
+                    .add(&[("table", &commit.table), ("result", "committed")], 1.0);
+                return Ok(());
+            }
+            Err(e) if e.permanent() => return Err(e),
+            Err(e) => {
+                metrics::LAKE_COMMITS.add(&[("table", &commit.table), ("result", "retried")], 1.0);
+                log::warn!(
+                    "Commit to {} failed: {}. Retrying in {:?}.",
+                    commit.table,
+                    e,
+                    delay
+                );
+                tokio::time::sleep(delay).await;
+                delay = (delay * 2).min(MAX_BACKOFF);
+            }
+        }
+    }
+}
+
+// Return the journaled commits, in the order they were journaled.
+fn recover(journal: &Path) -> Result<Vec<(u64, PathBuf, Commit)>, Error> {
+    let mut commits = Vec::new();
+
+    for entry in std::fs::read_dir(journal)? {
+        let path = entry?.path();
+        let name = path
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default();
+
+        if name.starts_with('.') {
+            std::fs::remove_file(&path)?;
+            continue;
+        }
+
+        let sequence = match name.strip_suffix(".json").and_then(|s| s.parse().ok()) {
+            Some(sequence) => sequence,
+            None => continue,
+        };
+
+        match serde_json::from_slice(&std::fs::read(&path)?) {
+            Ok(commit) => commits.push((sequence, path, commit)),
+            Err(e) => log::warn!("Ignoring invalid commit {:?}: {}", path, e),
+        }
+    }
+
+    commits.sort_by_key(|(sequence, _, _)| *sequence);
+    Ok(commits)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Commit, Error, Format};
+    use std::collections::HashSet;
+    use tokio::sync::{mpsc, watch};
+    use uuid::Uuid;
+
+    struct Strict;
+
+    #[async_trait::async_trait]
+    impl Format for Strict {
+        async fn commit(&self, commit: &Commit) -> Result<(), Error> {
+            match commit.table.as_str() {
+                "public.broken" => Err(Error::SchemaError("id changed type".into())),
+                _ => Ok(()),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn permanent_failures_park_their_table() {
+        let journal = std::env::temp_dir().join(format!("intake-journal-{}", Uuid::new_v4()));
+        let failed = journal.join("failed");
+        std::fs::create_dir_all(&failed).unwrap();
+
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let (applied, mut watcher) = watch::channel(0);
+        let handle = tokio::spawn(super::work(
+            std::sync::Arc::new(Strict),
+            None,
+            receiver,
+            applied,
+            failed.clone(),
+            HashSet::new(),
+        ));
+
+        for (sequence, table) in ["public.broken", "public.users", "public.broken"]
+            .iter()
+            .enumerate()
+        {
+            let path = journal.join(format!("{:020}.json", sequence));
+            std::fs::write(&path, b"{}").unwrap();
+            let commit = Commit {
+                id: Uuid::new_v4(),
+                table: table.to_string(),
+                columns: Vec::new(),
+                files: Vec::new(),
+            };
+            sender.send((sequence as u64, path, commit)).unwrap();
+        }
+        drop(sender);
+        handle.await.unwrap();
+
+        assert_eq!(*watcher.borrow_and_update(), 3);
+        assert!(failed.join(format!("{:020}.json", 0)).exists());
+        assert!(!journal.join(format!("{:020}.json", 1)).exists());
+        assert!(!failed.join(format!("{:020}.json", 1)).exists());
+        assert!(failed.join(format!("{:020}.json", 2)).exists());
+
+        std::fs::remove_dir_all(&journal).unwrap();
+    }
+}
@@ -1,7 +1,16 @@
-use clap::Parser;
+use clap::{Parser, Subcommand};
 use env_logger;
+use std::time::Duration;
+use tokio::signal::unix::{signal, SignalKind};
 
+mod check;
+mod compaction;
+mod config;
 mod events;
+mod health;
+mod http;
+mod lake;
+mod metrics;
 mod source;
 mod storage;
 
@@ -10,46 +19,195 @@ mod storage;
 struct Args {
     #[clap(short, long, value_parser)]
     config: String,
+
+    #[clap(subcommand)]
+    command: Option<Command>,
+}
+
+// Without a command, intake replicates the source.
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Rewrite small files into larger ones, then exit.
+    Compact,
+    /// Load and validate the configuration, without connecting to anything.
+    Validate,
+    /// Verify that the source and the destinations are usable, then exit.
+    Check,
+    /// Inspect or change the replication state.
+    State {
+        #[clap(subcommand)]
+        command: source::StateCommand,
+    },
 }
 
 #[tokio::main]
 async fn main() {
-    use std::fs::File;
-    use std::io::Read;
-    use yaml_rust::YamlLoader;
-
-    env_logger::init();
+    // Log what intake is doing unless RUST_LOG asks for something else.
+    env_logger::Builder::from_env(env_logger::Env::default().default_filter_or("info")).init();
 
     let args = Args::parse();
-    let mut file = File::open(args.config).expect("can't open file at that location");
-    let mut content = String::new();
+    let config = match config::load(std::path::Path::new(&args.config)) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
 
-    file.read_to_string(&mut content)
-        .expect("couldn't read the content of config.");
+    match args.command {
+        Some(Command::Compact) => {
+            let summary = compaction::new(&config)
+                .await
+                .expect("could not start the compaction")
+                .run()
+                .await
+                .expect("compaction failed");
 
-    let config =
-        &YamlLoader::load_from_str(&content).expect("content of the config file is not YAML");
+            println!(
+                "Compacted {} files into {}, removed {} replaced files.",
+                summary.replaced, summary.written, summary.removed
+            );
+            return;
+        }
+        // Loading the configuration already validated it.
+        Some(Command::Validate) => {
+            println!("{} is valid.", args.config);
+            return;
+        }
+        Some(Command::Check) => {
+            let report = check::run(&config).await;
+            print!("{}", report);
 
-    if config.len() != 1 {
-        panic!("YAML configuration requires the file to contain exactly 1 top level object(found: {} top level objects).", config.len());
+            if !report.passed() {
+                std::process::exit(1);
+            }
+            return;
+        }
+        Some(Command::State { command }) => {
+            match source::state(&config, command).await {
+                Ok(report) => print!("{}", report),
+                Err(e) => {
+                    eprintln!("{}", e);
+                    std::process::exit(1);
+                }
+            }
+            return;
+        }
+        None => {}
     }
-    let config = &config[0];
 
-    let sender = events::listen(config);
+    if let Some(settings) = config.http.as_ref() {
+        if let Err(e) = http::start(settings) {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    }
 
-    loop {
-        let (mut src, failure) = source::initialize(&config["source"]).await.unwrap();
+    // Standbys wait here, they don't upload or write anything until they lead.
+    if let Err(e) = source::elect(&config).await {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+
+    let expeditor = storage::initialize(config.storage.as_ref())
+        .await
+        .expect("could not initialize storage");
+    if let (Some(_), Some(expeditor)) = (config.http.as_ref(), expeditor.clone()) {
+        health::probe(expeditor);
+    }
+
+    // Files are only shipped from the queue so uploads survive restarts and outages.
+    let queue = match (config.storage.as_ref(), expeditor.clone()) {
+        (Some(settings), Some(expeditor)) => Some(
+            storage::queue::start(settings.queue(), expeditor)
+                .await
+                .expect("could not start the upload queue"),
+        ),
+        _ => None,
+    };
+
+    let lake = lake::start(
+        config.lake.as_ref(),
+        &config.output,
+        expeditor,
+        queue.clone(),
+    )
+    .await
+    .expect("could not start the lake");
+
+    let (sender, listener, watermark) = events::listen(&config, queue.clone(), lake.clone());
+    let mut terminate = signal(SignalKind::terminate()).expect("could not handle SIGTERM");
+
+    // A source that lost its state to another instance stops, it isn't reconnected.
+    let (mut src, conflict) = loop {
+        let (mut src, mut failure) = match source::initialize(&config, watermark.clone()).await {
+            Ok(source) => source,
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(1);
+            }
+        };
 
         // Cloning is needed here for the sender because the loop will re-execute and
         // the sender will be moved after the first iteration.
         src.connect(sender.clone()).await;
 
-        match failure.recv() {
-            Ok(e) => println!("Disconnected: {}. Attempting reconnection.", e),
-            Err(e) => panic!(
-                "unexpected failure on the channel monitoring connection health. This is a bug: {}",
-                e
-            ),
+        tokio::select! {
+            failure = failure.recv() => match failure {
+                Some(source::Error::ConflictError(e)) => {
+                    log::error!("Stopping the replication: {}", config::redact(&e));
+                    break (src, true);
+                }
+                Some(e) => {
+                    log::warn!(
+                        "Disconnected: {}. Attempting reconnection.",
+                        config::redact(&e.to_string())
+                    );
+                    metrics::RECONNECTS.add(&[], 1.0);
+                }
+                None => panic!(
+                    "unexpected failure on the channel monitoring connection health. This is a bug"
+                ),
+            },
+            _ = terminate.recv() => break (src, false),
+            _ = tokio::signal::ctrl_c() => break (src, false),
+        }
+    };
+
+    // Events sent before the shutdown event are written first, and the last status
+    // update is only sent to the source once their files are uploaded and committed.
+    log::info!(
+        "Shutting down, waiting up to {}s for the files to be written.",
+        config.shutdown.timeout
+    );
+    let shutdown = async {
+        src.stop().await;
+        sender
+            .send(events::Event::Shutdown)
+            .await
+            .expect("could not stop the events listener");
+        listener.await.expect("the events listener failed");
+
+        if let Some(queue) = queue.as_ref() {
+            queue.drained().await;
+        }
+        if let Some(lake) = lake.as_ref() {
+            lake.applied().await;
+        }
+        watermark.settled().await;
+
+        src.acknowledge().await;
+    };
+
+    match tokio::time::timeout(Duration::from_secs(config.shutdown.timeout), shutdown).await {
+        Ok(()) if conflict => std::process::exit(1),
+        Ok(()) => log::info!("Shut down."),
+        Err(_) => {
+            log::error!(
+                "Could not shut down within {}s. Pending uploads and commits resume on the next start, changes that weren't written yet are replicated again.",
+                config.shutdown.timeout
+            );
+            std::process::exit(1);
         }
     }
 }
@@ -0,0 +1,263 @@
+// Metrics records what intake is doing so it can be scraped by Prometheus from the
+// `/metrics` endpoint of the http server. Metrics are global so every module records
+// its own without a handle being passed around.
+//
+// Each metric is a family of values, one per set of labels, rendered in the
+// Prometheus text format.
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::Mutex;
+
+pub(crate) static EVENTS: Family = Family::counter(
+    "intake_events_total",
+    "Events received from the source, by table and operation.",
+);
+pub(crate) static EVENTS_SKIPPED: Family = Family::counter(
+    "intake_events_skipped_total",
+    "Events of parked tables that weren't written, by table.",
+);
+pub(crate) static ROWS_WRITTEN: Family = Family::counter(
+    "intake_rows_written_total",
+    "Rows written to files, by table.",
+);
+pub(crate) static BYTES_WRITTEN: Family = Family::counter(
+    "intake_bytes_written_total",
+    "Bytes written to files, by table.",
+);
+pub(crate) static SEGMENTS_OPEN: Family =
+    Family::gauge("intake_segments_open", "Segments currently open.");
+pub(crate) static SEGMENTS_CLOSED: Family = Family::counter(
+    "intake_segments_closed_total",
+    "Segments closed into files, by table.",
+);
+pub(crate) static FILE_CLOSE_SECONDS: Histogram = Histogram::new(
+    "intake_file_close_seconds",
+    "Time spent writing a segment to its file.",
+    &[0.01, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0],
+);
+pub(crate) static UPLOADS: Family =
+    Family::counter("intake_uploads_total", "Upload attempts, by result.");
+pub(crate) static CHANNEL_DEPTH: Family = Family::gauge(
+    "intake_events_channel_depth",
+    "Events waiting to be processed.",
+);
+pub(crate) static LAG_BYTES: Family = Family::gauge(
+    "intake_replication_lag_bytes",
+    "Bytes between the server's WAL end and the flushed LSN.",
+);
+pub(crate) static LAG_SECONDS: Family = Family::gauge(
+    "intake_replication_lag_seconds",
+    "Seconds since the server sent the last flushed change, 0 when caught up.",
+);
+pub(crate) static RECONNECTS: Family = Family::counter(
+    "intake_reconnects_total",
+    "Reconnections to the source after a disconnection.",
+);
+pub(crate) static LAKE_COMMITS: Family = Family::counter(
+    "intake_lake_commits_total",
+    "Commit attempts to the lake, by table and result.",
+);
+
+static FAMILIES: [&Family; 12] = [
+    &EVENTS,
+    &EVENTS_SKIPPED,
+    &ROWS_WRITTEN,
+    &BYTES_WRITTEN,
+    &SEGMENTS_OPEN,
+    &SEGMENTS_CLOSED,
+    &UPLOADS,
+    &CHANNEL_DEPTH,
+    &LAG_BYTES,
+    &LAG_SECONDS,
+    &RECONNECTS,
+    &LAKE_COMMITS,
+];
+
+// Family of counters or gauges sharing a name. Values are keyed by their rendered labels.
+pub(crate) struct Family {
+    name: &'static str,
+    help: &'static str,
+    kind: &'static str,
+    values: Mutex<BTreeMap<String, f64>>,
+}
+
+impl Family {
+    const fn counter(name: &'static str, help: &'static str) -> Family {
+        Family {
+            name,
+            help,
+            kind: "counter",
+            values: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    const fn gauge(name: &'static str, help: &'static str) -> Family {
+        Family {
+            name,
+            help,
+            kind: "gauge",
+            values: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    pub(crate) fn add(&self, labels: &[(&str, &str)], value: f64) {
+        *self
+            .values
+            .lock()
+            .expect("metrics lock is poisoned")
+            .entry(labels_of(labels))
+            .or_default() += value;
+    }
+
+    pub(crate) fn set(&self, labels: &[(&str, &str)], value: f64) {
+        self.values
+            .lock()
+            .expect("metrics lock is poisoned")
+            .insert(labels_of(labels), value);
+    }
+
+    fn render(&self, output: &mut String) {
+        writeln!(output, "# HELP {} {}", self.name, self.help).unwrap();
+        writeln!(output, "# TYPE {} {}", self.name, self.kind).unwrap();
+
+        for (labels, value) in self.values.lock().expect("metrics lock is poisoned").iter() {
+            writeln!(output, "{}{} {}", self.name, labels, value).unwrap();
+        }
+    }
+}
+
+// Histogram counts observations in cumulative buckets.
+pub(crate) struct Histogram {
+    name: &'static str,
+    help: &'static str,
+    buckets: &'static [f64],
+    values: Mutex<Observations>,
+}
+
+struct Observations {
+    counts: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    const fn new(name: &'static str, help: &'static str, buckets: &'static [f64]) -> Histogram {
+        Histogram {
+            name,
+            help,
+            buckets,
+            values: Mutex::new(Observations {
+                counts: Vec::new(),
+                sum: 0.0,
+                count: 0,
+            }),
+        }
+    }
+
+    pub(crate) fn observe(&self, value: f64) {
+        let mut observations = self.values.lock().expect("metrics lock is poisoned");
+        observations.counts.resize(self.buckets.len(), 0);
+
+        for (bucket, count) in self.buckets.iter().zip(observations.counts.iter_mut()) {
+            if value <= *bucket {
+                *count += 1;
+            }
+        }
+        observations.sum += value;
+        observations.count += 1;
+    }
+
+    fn render(&self, output: &mut String) {
+        let observations = self.values.lock().expect("metrics lock is poisoned");
+
+        writeln!(output, "# HELP {} {}", self.name, self.help).unwrap();
+        writeln!(output, "# TYPE {} histogram", self.name).unwrap();
+        for (index, bucket) in self.buckets.iter().enumerate() {
+            writeln!(
+                output,
+                "{}_bucket{{le=\"{}\"}} {}",
+                self.name,
+                bucket,
+                observations.counts.get(index).unwrap_or(&0)
+            )
+            .unwrap();
+        }
+        writeln!(
+            output,
+            "{}_bucket{{le=\"+Inf\"}} {}",
+            self.name, observations.count
+        )
+        .unwrap();
+        writeln!(output, "{}_sum {}", self.name, observations.sum).unwrap();
+        writeln!(output, "{}_count {}", self.name, observations.count).unwrap();
+    }
+}
+
+// Render every metric in the Prometheus text format.
+pub(crate) fn render() -> String {
+    let mut output = String::new();
+
+    for family in FAMILIES.iter() {
+        family.render(&mut output);
+    }
+    FILE_CLOSE_SECONDS.render(&mut output);
+
+    output
+}
+
+fn labels_of(labels: &[(&str, &str)]) -> String {
+    if labels.is_empty() {
+        return String::new();
+    }
+
+    let labels: Vec<String> = labels
+        .iter()
+        .map(|(name, value)| {
+            format!(
+                "{}=\"{}\"",
+                name,
+                value
+                    .replace('\\', "\\\\")
+                    .replace('"', "\\\"")
+                    .replace('\n', "\\n")
+            )
+        })
+        .collect();
+
+    format!("{{{}}}", labels.join(","))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Family, Histogram};
+
+    #[test]
+    fn renders_families_and_histograms() {
+        let events = Family::counter("events_total", "Events.");
+        events.add(&[("table", "public.users"), ("operation", "insert")], 1.0);
+        events.add(&[("table", "public.users"), ("operation", "insert")], 2.0);
+        events.add(&[("table", "say \"hi\""), ("operation", "delete")], 1.0);
+
+        let mut output = String::new();
+        events.render(&mut output);
+        assert_eq!(
+            output,
+            "# HELP events_total Events.\n\
+             # TYPE events_total counter\n\
+             events_total{table=\"public.users\",operation=\"insert\"} 3\n\
+             events_total{table=\"say \\\"hi\\\"\",operation=\"delete\"} 1\n"
+        );
+
+        let latency = Histogram::new("latency_seconds", "Latency.", &[0.1, 1.0]);
+        latency.observe(0.05);
+        latency.observe(0.5);
+
+        let mut output = String::new();
+        latency.render(&mut output);
+        assert!(output.contains("latency_seconds_bucket{le=\"0.1\"} 1\n"));
+        assert!(output.contains("latency_seconds_bucket{le=\"1\"} 2\n"));
+        assert!(output.contains("latency_seconds_bucket{le=\"+Inf\"} 2\n"));
+        assert!(output.contains("latency_seconds_count 2\n"));
+    }
+}
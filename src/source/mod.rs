@@ -1,21 +1,26 @@
-use crate::events::Event;
-use std::sync::mpsc;
+use crate::check::Outcome;
+use crate::config;
+use crate::events::{watermark::Watermark, Event};
+use std::sync::Arc;
 use thiserror::Error;
-use tokio::sync::mpsc::Sender;
-use yaml_rust::Yaml;
+use tokio::sync::mpsc::{self, Sender};
 
 mod postgresql;
 
 #[derive(Error, Debug)]
 pub(crate) enum Error {
-    #[error("configuration error: `{0}`")]
-    ConfigError(String),
-
     #[error("connection error: `{0}`")]
     ConnectionError(String),
 
     #[error("parse error: `{0}`")]
     ParseError(String),
+
+    #[error("state error: `{0}`")]
+    StateError(String),
+
+    // Another instance saved the state, this one has to stop replicating.
+    #[error("state conflict: `{0}`")]
+    ConflictError(String),
 }
 
 impl From<tokio_postgres::Error> for Error {
@@ -33,6 +38,13 @@ impl From<serde_json::Error> for Error {
 #[async_trait::async_trait]
 pub(crate) trait Client {
     async fn connect(&mut self, sender: Sender<Event>);
+
+    // Stop consuming the replication stream. Events already received are still sent.
+    async fn stop(&mut self);
+
+    // Save the state and acknowledge it to the source. Only called once every event
+    // sent was written.
+    async fn acknowledge(&mut self);
 }
 
 pub(crate) struct Driver<T: Client> {
@@ -43,21 +55,87 @@ impl<T: Client> Driver<T> {
     pub(crate) async fn connect(&mut self, sender: Sender<Event>) {
         self.client.connect(sender).await;
     }
+
+    pub(crate) async fn stop(&mut self) {
+        self.client.stop().await;
+    }
+
+    pub(crate) async fn acknowledge(&mut self) {
+        self.client.acknowledge().await;
+    }
 }
 
+// The state of the source can be kept in the destination of the storage block, so
+// both are given the whole configuration. Sources acknowledge the watermark, what was
+// received but isn't durable yet is replicated again after a restart.
 pub(crate) async fn initialize(
-    config: &Yaml,
-) -> Result<(Driver<impl Client>, mpsc::Receiver<Error>), Error> {
-    let (sender, receiver) = mpsc::channel();
-    let driver = match config["driver"].as_str().expect(
-        "source.driver should be a string. Possible values: http://github.com/intake/wiki/sources",
-    ) {
-        "postgresql" => {
-            let client = postgresql::initialize(config, sender).await;
+    config: &config::Config,
+    watermark: Arc<Watermark>,
+) -> Result<(Driver<impl Client>, mpsc::UnboundedReceiver<Error>), Error> {
+    let (sender, receiver) = mpsc::unbounded_channel();
+    let driver = match &config.source {
+        config::Source::Postgresql(source) => {
+            let client =
+                postgresql::initialize(source, config.storage.as_ref(), sender, watermark).await?;
             Driver { client }
         }
-        invalid => return Err(Error::ConfigError(format!("invalid driver: {}", invalid))),
     };
 
     Ok((driver, receiver))
 }
+
+// Wait until this instance leads when an election is configured. Without an
+// election, every instance leads.
+pub(crate) async fn elect(config: &config::Config) -> Result<(), Error> {
+    let election = match config.election.as_ref() {
+        Some(election) => election,
+        None => return Ok(()),
+    };
+
+    match &config.source {
+        config::Source::Postgresql(source) => {
+            postgresql::election::elect(source, config.storage.as_ref(), election).await
+        }
+    }
+}
+
+// Verify that the source can be replicated, without replicating it.
+pub(crate) async fn check(config: &config::Source) -> Vec<Outcome> {
+    match config {
+        config::Source::Postgresql(config) => postgresql::check(config).await,
+    }
+}
+
+// Inspect and repair the replication state of the source.
+#[derive(clap::Subcommand, Debug)]
+pub(crate) enum StateCommand {
+    /// Print the replication state and the progress of every table.
+    Show,
+    /// Restart the replication from an LSN, ie. 0/16B3748.
+    SetLsn {
+        #[clap(value_parser)]
+        lsn: String,
+        /// Skip the checks against the server's slot.
+        #[clap(long, value_parser)]
+        force: bool,
+    },
+    /// Forget the replicated LSNs, the replication restarts from the slot's position.
+    Reset,
+    /// Drop the replication slot of the state, or the given one.
+    DropSlot {
+        #[clap(long, value_parser)]
+        slot: Option<String>,
+        /// Drop a slot that isn't the configured one, the state's one or named intake_*.
+        #[clap(long, value_parser)]
+        force: bool,
+    },
+}
+
+// Run the state command and return its report.
+pub(crate) async fn state(config: &config::Config, command: StateCommand) -> Result<String, Error> {
+    match &config.source {
+        config::Source::Postgresql(source) => {
+            postgresql::manage::run(source, config.storage.as_ref(), &config.output, command).await
+        }
+    }
+}
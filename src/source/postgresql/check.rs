@@ -0,0 +1,190 @@
+use crate::check::Outcome;
+use crate::config;
+use tokio_postgres::{Client, NoTls, SimpleQueryMessage};
+
+// Verify that the database can be replicated with wal2json. The slot created to
+// check that the plugin is available is temporary and dropped with the connection.
+pub(crate) async fn check(config: &config::Postgresql) -> Vec<Outcome> {
+    let client = match tokio_postgres::connect(&config.url, NoTls).await {
+        Ok((client, connection)) => {
+            tokio::spawn(async move {
+                let _ = connection.await;
+            });
+            client
+        }
+        Err(e) => {
+            return vec![Outcome::new(
+                "source.connection",
+                Err(format!(
+                    "could not connect to {}: {}",
+                    config::redact(&config.url),
+                    e
+                )),
+            )]
+        }
+    };
+
+    vec![
+        Outcome::new("source.connection", identify(&client).await),
+        Outcome::new("source.privileges", privileges(&client).await),
+        Outcome::new("source.wal_level", wal_level(&client).await),
+        Outcome::new("source.slots", slots(&client).await),
+        Outcome::new("source.plugin", plugin(&client).await),
+        Outcome::new("source.tables", tables(&client).await),
+    ]
+}
+
+// Return the values of every row returned by the query.
+async fn rows(client: &Client, query: &str) -> Result<Vec<Vec<String>>, String> {
+    let messages = client
+        .simple_query(query)
+        .await
+        .map_err(|e| e.to_string())?;
+
+    Ok(messages
+        .into_iter()
+        .filter_map(|message| match message {
+            SimpleQueryMessage::Row(row) => Some(
+                (0..row.len())
+                    .map(|i| row.get(i).unwrap_or_default().to_string())
+                    .collect(),
+            ),
+            _ => None,
+        })
+        .collect())
+}
+
+// Return the first value returned by the query.
+async fn value(client: &Client, query: &str) -> Result<String, String> {
+    rows(client, query)
+        .await?
+        .into_iter()
+        .next()
+        .and_then(|row| row.into_iter().next())
+        .ok_or_else(|| format!("{} returned nothing", query))
+}
+
+async fn identify(client: &Client) -> Result<String, String> {
+    let messages = client
+        .simple_query("IDENTIFY_SYSTEM")
+        .await
+        .map_err(|e| format!("not a replication connection: {}", e))?;
+
+    for message in messages {
+        if let SimpleQueryMessage::Row(row) = message {
+            // Only logical replication connections are bound to a database.
+            return match row.get("dbname") {
+                Some(database) if !database.is_empty() => Ok(format!(
+                    "connected to {} on system {}",
+                    database,
+                    row.get("systemid").unwrap_or_default()
+                )),
+                _ => Err("not bound to a database, add replication=database to the url".into()),
+            };
+        }
+    }
+
+    Err("IDENTIFY_SYSTEM did not return any row".into())
+}
+
+async fn privileges(client: &Client) -> Result<String, String> {
+    let allowed = value(
+        client,
+        "SELECT rolsuper OR rolreplication FROM pg_roles WHERE rolname = current_user",
+    )
+    .await?;
+
+    match allowed.as_str() {
+        "t" => Ok("the role can replicate".into()),
+        _ => Err("the role needs the REPLICATION attribute".into()),
+    }
+}
+
+async fn wal_level(client: &Client) -> Result<String, String> {
+    match value(client, "SHOW wal_level").await?.as_str() {
+        "logical" => Ok("wal_level is logical".into()),
+        level => Err(format!("wal_level is {}, it should be logical", level)),
+    }
+}
+
+async fn slots(client: &Client) -> Result<String, String> {
+    let number = |value: String| {
+        value
+            .parse::<i64>()
+            .map_err(|e| format!("invalid number {}: {}", value, e))
+    };
+
+    let max_slots = number(value(client, "SHOW max_replication_slots").await?)?;
+    let slots = number(value(client, "SELECT count(*) FROM pg_replication_slots").await?)?;
+    let max_senders = number(value(client, "SHOW max_wal_senders").await?)?;
+    let senders = number(value(client, "SELECT count(*) FROM pg_stat_replication").await?)?;
+
+    if slots >= max_slots {
+        return Err(format!(
+            "all {} replication slots are used, raise max_replication_slots",
+            max_slots
+        ));
+    }
+
+    // This connection is already a WAL sender.
+    if senders > max_senders {
+        return Err(format!(
+            "all {} WAL senders are used, raise max_wal_senders",
+            max_senders
+        ));
+    }
+
+    Ok(format!(
+        "{} of {} replication slots are free",
+        max_slots - slots,
+        max_slots
+    ))
+}
+
+async fn plugin(client: &Client) -> Result<String, String> {
+    let slot = format!("intake_check_{}", uuid::Uuid::new_v4().simple());
+
+    client
+        .simple_query(&format!(
+            "CREATE_REPLICATION_SLOT {} TEMPORARY LOGICAL wal2json",
+            slot
+        ))
+        .await
+        .map(|_| "wal2json is available".to_string())
+        .map_err(|e| format!("could not create a wal2json slot: {}", e))
+}
+
+// Updates and deletes are replicated with the replica identity of their table, which
+// is its primary key unless configured otherwise.
+async fn tables(client: &Client) -> Result<String, String> {
+    let tables = rows(
+        client,
+        "SELECT n.nspname || '.' || c.relname, c.relreplident, \
+            EXISTS (SELECT 1 FROM pg_index i WHERE i.indrelid = c.oid AND i.indisprimary) \
+         FROM pg_class c JOIN pg_namespace n ON n.oid = c.relnamespace \
+         WHERE c.relkind IN ('r', 'p') \
+            AND n.nspname NOT IN ('pg_catalog', 'information_schema') \
+            AND n.nspname NOT LIKE 'pg_toast%' \
+         ORDER BY 1",
+    )
+    .await?;
+
+    if tables.is_empty() {
+        return Err("the database has no tables".into());
+    }
+
+    let missing: Vec<&str> = tables
+        .iter()
+        .filter(|table| table[1] == "n" || (table[1] == "d" && table[2] != "t"))
+        .map(|table| table[0].as_str())
+        .collect();
+
+    if !missing.is_empty() {
+        return Err(format!(
+            "updates and deletes of {} can't be replicated, they have no primary key or replica identity",
+            missing.join(", ")
+        ));
+    }
+
+    Ok(format!("{} tables can be replicated", tables.len()))
+}
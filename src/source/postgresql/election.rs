@@ -0,0 +1,242 @@
+use super::errors::StateUpdateError;
+use super::store::{self, StateStore};
+use crate::config;
+use crate::source::Error;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::sync::Mutex;
+use tokio_postgres::{Client, NoTls, SimpleQueryMessage};
+
+// Elector campaigns for the leadership on behalf of this instance.
+#[async_trait::async_trait]
+trait Elector: Send + Sync {
+    // Become the leader, or remain it. Return whether this instance leads.
+    async fn campaign(&self) -> Result<bool, Error>;
+
+    // How long the leadership outlives the last successful campaign.
+    fn term(&self) -> Duration;
+}
+
+// Wait until this instance is elected. The leadership is then renewed in the
+// background and the process stops as soon as it can't be sure it still leads, so
+// two instances never replicate the slot at the same time.
+pub(crate) async fn elect(
+    config: &config::Postgresql,
+    storage: Option<&config::Storage>,
+    election: &config::Election,
+) -> Result<(), Error> {
+    let candidate = candidate();
+    let (elector, interval): (Arc<dyn Elector>, u64) = match election {
+        config::Election::Advisory(settings) => (
+            Arc::new(Advisory::new(
+                settings.url.as_deref().unwrap_or(&config.url),
+                settings.key,
+            )),
+            settings.interval,
+        ),
+        config::Election::Lease(settings) => (
+            Arc::new(Lease {
+                store: store::lease(config, storage).await?,
+                candidate: candidate.clone(),
+                ttl: settings.ttl,
+            }),
+            settings.interval,
+        ),
+    };
+    let interval = Duration::from_secs(interval);
+
+    log::info!("Standing by until {} is elected", candidate);
+    loop {
+        match elector.campaign().await {
+            Ok(true) => break,
+            Ok(false) => {}
+            Err(e) => log::warn!("Could not campaign for the leadership: {}", e),
+        }
+        tokio::time::sleep(interval).await;
+    }
+    log::info!("{} was elected", candidate);
+
+    tokio::spawn(async move {
+        let mut renewed = Instant::now();
+
+        loop {
+            tokio::time::sleep(interval).await;
+
+            match elector.campaign().await {
+                Ok(true) => renewed = Instant::now(),
+                // Stop before the next campaign could happen after the term.
+                Err(e) if renewed.elapsed() + interval < elector.term() => {
+                    log::warn!("Could not renew the leadership, retrying: {}", e)
+                }
+                Err(e) => {
+                    log::error!("Lost the leadership: {}", e);
+                    std::process::exit(1);
+                }
+                Ok(false) => {
+                    log::error!("Lost the leadership to another instance");
+                    std::process::exit(1);
+                }
+            }
+        }
+    });
+
+    Ok(())
+}
+
+// Name of this instance in the election, the hostname is the pod's name on Kubernetes.
+fn candidate() -> String {
+    format!(
+        "{}:{}",
+        std::env::var("HOSTNAME").unwrap_or_else(|_| "intake".into()),
+        std::process::id()
+    )
+}
+
+// Advisory holds a session advisory lock, released by the server as soon as the
+// connection that holds it is closed.
+struct Advisory {
+    url: String,
+    key: i64,
+    client: Mutex<Option<Client>>,
+}
+
+impl Advisory {
+    fn new(url: &str, key: i64) -> Advisory {
+        Advisory {
+            url: url.to_string(),
+            key,
+            client: Mutex::new(None),
+        }
+    }
+
+    async fn connect(&self) -> Result<Client, Error> {
+        let (client, connection) =
+            tokio_postgres::connect(&self.url, NoTls)
+                .await
+                .map_err(|e| {
+                    Error::ConnectionError(format!(
+                        "could not connect to {}: {}",
+                        config::redact(&self.url),
+                        e
+                    ))
+                })?;
+
+        tokio::spawn(async move {
+            let _ = connection.await;
+        });
+
+        Ok(client)
+    }
+}
+
+#[async_trait::async_trait]
+impl Elector for Advisory {
+    async fn campaign(&self) -> Result<bool, Error> {
+        let mut client = self.client.lock().await;
+
+        // The lock is held for as long as the connection is open.
+        if let Some(held) = client.as_ref() {
+            if held.is_closed() {
+                return Err(Error::ConnectionError(
+                    "the connection holding the lock was closed".into(),
+                ));
+            }
+            held.simple_query("SELECT 1").await?;
+            return Ok(true);
+        }
+
+        let candidate = self.connect().await?;
+        for message in candidate
+            .simple_query(&format!("SELECT pg_try_advisory_lock({})", self.key))
+            .await?
+        {
+            if let SimpleQueryMessage::Row(row) = message {
+                if row.get(0) == Some("t") {
+                    *client = Some(candidate);
+                    return Ok(true);
+                }
+            }
+        }
+
+        Ok(false)
+    }
+
+    fn term(&self) -> Duration {
+        Duration::ZERO
+    }
+}
+
+// Lease is renewed by the leader in the store of the state. The store only saves
+// the lease when no one else saved it since it was loaded, so only one candidate
+// wins a campaign. Expiration relies on the clocks of the candidates being in sync.
+struct Lease {
+    store: Arc<dyn StateStore>,
+    candidate: String,
+    ttl: u64,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Term {
+    holder: String,
+    // Seconds since the epoch.
+    expires_at: i64,
+}
+
+#[async_trait::async_trait]
+impl Elector for Lease {
+    async fn campaign(&self) -> Result<bool, Error> {
+        let now = chrono::Utc::now().timestamp();
+
+        if let Some(content) = self.store.load().await? {
+            let term: Term = serde_json::from_slice(&content)?;
+            if term.holder != self.candidate && term.expires_at > now {
+                return Ok(false);
+            }
+        }
+
+        let term = Term {
+            holder: self.candidate.clone(),
+            expires_at: now + self.ttl as i64,
+        };
+        match self.store.save(serde_json::to_vec(&term)?).await {
+            Ok(()) => Ok(true),
+            Err(StateUpdateError::ConflictError(_)) => Ok(false),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn term(&self) -> Duration {
+        Duration::from_secs(self.ttl)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::store::Object;
+    use super::{Elector, Lease};
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn leases_elect_one_candidate() {
+        let root = std::env::temp_dir().join(format!("intake-lease-{}", uuid::Uuid::new_v4()));
+        let expeditor = crate::storage::local(&root);
+        let lease = |candidate: &str, ttl| Lease {
+            store: Arc::new(Object::new(expeditor.clone(), "_state/intake.lease")),
+            candidate: candidate.to_string(),
+            ttl,
+        };
+
+        let first = lease("first", 0);
+        let second = lease("second", 30);
+        assert!(first.campaign().await.unwrap());
+        assert!(first.campaign().await.unwrap());
+
+        // The lease of the first candidate expired as soon as it was renewed.
+        assert!(second.campaign().await.unwrap());
+        assert!(!first.campaign().await.unwrap());
+        assert!(second.campaign().await.unwrap());
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
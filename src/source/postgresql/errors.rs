@@ -6,4 +6,28 @@ use thiserror::Error;
 pub(crate) enum StateUpdateError {
     #[error("could not convert slice into primitive value")]
     ParseError(#[from] TryFromSliceError),
+
+    #[error("could not read the state: {0}")]
+    FileError(#[from] std::io::Error),
+
+    #[error("invalid state: {0}")]
+    FormatError(#[from] serde_json::Error),
+
+    #[error("state doesn't match its checksum: {0}")]
+    ChecksumError(String),
+
+    #[error("state version {0} is not supported")]
+    VersionError(u32),
+
+    #[error("state doesn't match the slot: {0}")]
+    SlotError(String),
+
+    #[error("no state was saved at {0}")]
+    MissingError(String),
+
+    #[error("could not save the state: {0}")]
+    StoreError(String),
+
+    #[error("state was changed concurrently: {0}")]
+    ConflictError(String),
 }
@@ -1,17 +1,46 @@
-use crate::events::{Event, Value};
+use crate::events::{Event, Key, Position, Value, Values};
 use crate::source::Error;
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 use serde_json::Value as JSONValue;
 
-pub(crate) fn from_json(payload: &[u8]) -> Result<Vec<Event>, Error> {
+// Parse a wal2json payload into events. All the events share the same position
+// as they are part of the same transaction.
+pub(crate) fn from_json(payload: &[u8], lsn: u64) -> Result<Vec<Event>, Error> {
     let mutations: Mutations = serde_json::from_slice(payload)?;
-    Ok(mutations.mutations.into_iter().collect())
+    let position = Position {
+        lsn,
+        timestamp: mutations.timestamp()?,
+    };
+
+    Ok(mutations
+        .mutations
+        .into_iter()
+        .map(|mutation| mutation.into_event(position))
+        .collect())
 }
 
 #[derive(Deserialize, Serialize, Debug)]
 struct Mutations {
     #[serde(rename = "change")]
     mutations: Vec<Mutation>,
+
+    // Only present when the replication is started with `include-timestamp`.
+    #[serde(default)]
+    timestamp: Option<String>,
+}
+
+impl Mutations {
+    // wal2json formats the commit timestamp the same way PostgreSQL does
+    // for `timestamptz`, ie. `2022-03-07 09:30:00.123456+00`.
+    fn timestamp(&self) -> Result<Option<DateTime<Utc>>, Error> {
+        match self.timestamp.as_ref() {
+            Some(timestamp) => DateTime::parse_from_str(timestamp, "%Y-%m-%d %H:%M:%S%.f%#z")
+                .map(|t| Some(t.with_timezone(&Utc)))
+                .map_err(|e| Error::ParseError(format!("invalid timestamp {}: {}", timestamp, e))),
+            None => Ok(None),
+        }
+    }
 }
 
 #[derive(Deserialize, Serialize, Debug)]
@@ -19,47 +48,136 @@ struct Mutations {
 #[serde(rename_all = "camelCase")]
 enum Mutation {
     Insert {
+        schema: String,
+        table: String,
+        #[serde(rename = "columnnames")]
+        columns: Vec<String>,
+        #[serde(rename = "columnvalues")]
+        values: Vec<JSONValue>,
+        #[serde(rename = "columntypes")]
+        types: Vec<String>,
+        #[serde(default)]
+        pk: Option<PrimaryKey>,
+    },
+    Update {
+        schema: String,
+        table: String,
         #[serde(rename = "columnnames")]
         columns: Vec<String>,
         #[serde(rename = "columnvalues")]
         values: Vec<JSONValue>,
         #[serde(rename = "columntypes")]
         types: Vec<String>,
+        #[serde(default)]
+        pk: Option<PrimaryKey>,
+        // Only present when the update changed the replica identity, or when
+        // the table's replica identity is FULL.
+        #[serde(default, rename = "oldkeys")]
+        keys: Option<Keys>,
+    },
+    Delete {
+        schema: String,
+        table: String,
+        #[serde(rename = "oldkeys")]
+        keys: Keys,
+        #[serde(default)]
+        pk: Option<PrimaryKey>,
     },
-    Update,
-    Delete,
 }
 
-impl From<Mutation> for Event {
-    fn from(mutation: Mutation) -> Self {
-        use std::collections::HashMap;
+// Primary key of the table, present when the replication is started with `include-pk`.
+#[derive(Deserialize, Serialize, Debug)]
+struct PrimaryKey {
+    #[serde(rename = "pknames")]
+    columns: Vec<String>,
+}
 
-        match mutation {
+// Replica identity of the row that was deleted.
+#[derive(Deserialize, Serialize, Debug)]
+struct Keys {
+    #[serde(rename = "keynames")]
+    columns: Vec<String>,
+    #[serde(rename = "keyvalues")]
+    values: Vec<JSONValue>,
+    #[serde(rename = "keytypes")]
+    types: Vec<String>,
+}
+
+impl Mutation {
+    fn into_event(self, position: Position) -> Event {
+        match self {
             Mutation::Insert {
+                schema,
+                table,
+                columns,
+                values,
+                types,
+                pk,
+            } => Event::Insert(
+                format!("{}.{}", schema, table),
+                map(columns, &types, &values),
+                key(pk, None),
+                position,
+            ),
+            Mutation::Update {
+                schema,
+                table,
                 columns,
                 values,
                 types,
+                pk,
+                keys,
+            } => Event::Update(
+                format!("{}.{}", schema, table),
+                map(columns, &types, &values),
+                key(
+                    pk,
+                    keys.map(|keys| map(keys.columns, &keys.types, &keys.values)),
+                ),
+                position,
+            ),
+            Mutation::Delete {
+                schema,
+                table,
+                keys,
+                pk,
             } => {
-                let mut map = HashMap::new();
-                for (i, column) in columns.into_iter().enumerate() {
-                    map.insert(column, Value::from((&types[i], &values[i])));
-                }
-                Event::Insert("test".into(), map)
+                // The replica identity is the primary key unless configured otherwise.
+                let key = Key {
+                    columns: pk
+                        .map(|pk| pk.columns)
+                        .unwrap_or_else(|| keys.columns.clone()),
+                    previous: None,
+                };
+
+                Event::Delete(
+                    format!("{}.{}", schema, table),
+                    map(keys.columns, &keys.types, &keys.values),
+                    key,
+                    position,
+                )
             }
-            _ => Self::default(),
         }
     }
 }
 
-impl FromIterator<Mutation> for Vec<Event> {
-    fn from_iter<T: IntoIterator<Item = Mutation>>(mutations: T) -> Self {
-        let mut events = Vec::new();
-        for m in mutations {
-            events.push(m.into());
-        }
+fn key(pk: Option<PrimaryKey>, previous: Option<Values>) -> Key {
+    Key {
+        columns: pk.map(|pk| pk.columns).unwrap_or_default(),
+        previous,
+    }
+}
 
-        events
+// Null values are left out of the map.
+fn map(columns: Vec<String>, types: &[String], values: &[JSONValue]) -> Values {
+    let mut map = Values::new();
+    for (i, column) in columns.into_iter().enumerate() {
+        if !values[i].is_null() {
+            map.insert(column, Value::from((&types[i], &values[i])));
+        }
     }
+
+    map
 }
 
 impl From<(&String, &JSONValue)> for Value {
@@ -0,0 +1,328 @@
+use super::state;
+use super::store::{self, StateStore};
+use crate::config;
+use crate::events::{manifest, provenance};
+use crate::source::{Error, StateCommand};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use tokio_postgres::{Client, NoTls, SimpleQueryMessage};
+
+impl From<super::errors::StateUpdateError> for Error {
+    fn from(e: super::errors::StateUpdateError) -> Self {
+        match e {
+            super::errors::StateUpdateError::ConflictError(_) => {
+                Error::ConflictError(e.to_string())
+            }
+            _ => Error::StateError(e.to_string()),
+        }
+    }
+}
+
+// Replication slot, as the server sees it.
+struct Slot {
+    plugin: String,
+    active: bool,
+    restart: Option<u64>,
+    confirmed: Option<u64>,
+}
+
+// Progress of a table, from the files listed in the manifests.
+#[derive(Default)]
+struct Progress<'a> {
+    files: usize,
+    rows: i64,
+    lsn: u64,
+    closed_at: &'a str,
+}
+
+// Run a state command and return what should be printed. Commands that change
+// the state or the server refuse to run while intake replicates with the slot.
+pub(crate) async fn run(
+    config: &config::Postgresql,
+    storage: Option<&config::Storage>,
+    output: &config::Output,
+    command: StateCommand,
+) -> Result<String, Error> {
+    let store = store::initialize(config, storage).await?;
+
+    match command {
+        StateCommand::Show => show(config, store.as_ref(), output).await,
+        StateCommand::SetLsn { lsn, force } => set_lsn(config, store.as_ref(), &lsn, force).await,
+        StateCommand::Reset => reset(config, store.as_ref()).await,
+        StateCommand::DropSlot { slot, force } => {
+            drop_slot(config, store.as_ref(), slot, force).await
+        }
+    }
+}
+
+async fn show(
+    config: &config::Postgresql,
+    store: &dyn StateStore,
+    output: &config::Output,
+) -> Result<String, Error> {
+    let state = state::read(store).await?;
+    let mut report = state.to_string();
+
+    // The server is optional, the state is still worth showing when it's unreachable.
+    match connect(config).await {
+        Ok(client) => match slot(&client, state.slot()).await? {
+            Some(slot) => {
+                writeln!(
+                    report,
+                    "Server slot:      {} ({}), restart {}, confirmed {}",
+                    if slot.active { "active" } else { "inactive" },
+                    slot.plugin,
+                    describe(slot.restart),
+                    describe(slot.confirmed)
+                )
+                .unwrap();
+            }
+            None => writeln!(report, "Server slot:      missing").unwrap(),
+        },
+        Err(e) => writeln!(report, "Server slot:      unknown, {}", e).unwrap(),
+    }
+
+    let entries = manifest::read(&output.directory, &output.manifest)
+        .map_err(|e| Error::StateError(e.to_string()))?;
+    let replaced: Vec<&str> = entries
+        .iter()
+        .flat_map(|entry| entry.replaces.iter().map(String::as_str))
+        .collect();
+
+    let mut tables: BTreeMap<&str, Progress> = BTreeMap::new();
+    for entry in entries.iter() {
+        let progress = tables.entry(entry.table.as_str()).or_default();
+        // Compacted files hold the rows of the files they replace.
+        if !replaced.contains(&entry.path.as_str()) {
+            progress.files += 1;
+            progress.rows += entry.rows;
+        }
+        progress.lsn = progress
+            .lsn
+            .max(provenance::parse_lsn(&entry.lsn_end).unwrap_or(0));
+        progress.closed_at = progress.closed_at.max(entry.closed_at.as_str());
+    }
+
+    if tables.is_empty() {
+        writeln!(report, "\nNo files were written yet.").unwrap();
+    } else {
+        writeln!(
+            report,
+            "\nTable, files, rows, last LSN, last file closed at:"
+        )
+        .unwrap();
+    }
+    for (table, progress) in tables {
+        writeln!(
+            report,
+            "  {}  {}  {}  {}  {}",
+            table,
+            progress.files,
+            progress.rows,
+            provenance::lsn(progress.lsn),
+            progress.closed_at
+        )
+        .unwrap();
+    }
+
+    Ok(report)
+}
+
+// The server only streams changes after the slot's confirmed LSN and can't stream
+// changes that weren't written yet, so the LSN must be between the two. Moving
+// backward replicates changes again, moving forward skips changes.
+async fn set_lsn(
+    config: &config::Postgresql,
+    store: &dyn StateStore,
+    lsn: &str,
+    force: bool,
+) -> Result<String, Error> {
+    let mut state = state::read(store).await?;
+    let target = provenance::parse_lsn(lsn)
+        .ok_or_else(|| Error::StateError(format!("invalid LSN {}, expected X/X", lsn)))?;
+
+    let client = connect(config).await?;
+    match slot(&client, state.slot()).await? {
+        Some(slot) if slot.active => return Err(active(state.slot())),
+        Some(slot) => {
+            if let Some(confirmed) = slot.confirmed.filter(|confirmed| target < *confirmed) {
+                if !force {
+                    return Err(Error::StateError(format!(
+                        "{} is before the slot's confirmed LSN {}, the server can't stream changes before it. Use --force to set it regardless",
+                        lsn,
+                        provenance::lsn(confirmed)
+                    )));
+                }
+            }
+        }
+        None if !force => {
+            return Err(Error::StateError(format!(
+                "slot {} doesn't exist on the server, {} can't be verified. Use --force to set it regardless",
+                state.slot(),
+                lsn
+            )))
+        }
+        None => {}
+    }
+
+    let current = value(&client, "SELECT pg_current_wal_lsn()").await?;
+    if let Some(current) = provenance::parse_lsn(&current) {
+        if target > current && !force {
+            return Err(Error::StateError(format!(
+                "{} is after the server's current LSN {}. Use --force to set it regardless",
+                lsn,
+                provenance::lsn(current)
+            )));
+        }
+    }
+
+    let previous = state.lsn();
+    state.set_lsn(target);
+    state::persist(store, &state).await?;
+
+    Ok(format!(
+        "Moved {} from {} to {}, {}.",
+        state.slot(),
+        provenance::lsn(previous),
+        provenance::lsn(target),
+        if target < previous {
+            "changes in between will be replicated again"
+        } else {
+            "changes in between will be skipped"
+        }
+    ))
+}
+
+async fn reset(config: &config::Postgresql, store: &dyn StateStore) -> Result<String, Error> {
+    let mut state = state::read(store).await?;
+
+    let client = connect(config).await?;
+    if let Some(slot) = slot(&client, state.slot()).await? {
+        if slot.active {
+            return Err(active(state.slot()));
+        }
+    }
+
+    state.reset();
+    state::persist(store, &state).await?;
+    Ok(format!(
+        "Reset {}, replication restarts from the slot's position.",
+        state.slot()
+    ))
+}
+
+// Only wal2json slots can be dropped, as those are the ones intake creates. Other
+// tools can use wal2json too: unless forced, only the configured slot, the slot of
+// the state and the temporary slots of intake, named intake_*, are dropped.
+async fn drop_slot(
+    config: &config::Postgresql,
+    store: &dyn StateStore,
+    name: Option<String>,
+    force: bool,
+) -> Result<String, Error> {
+    let name = match name {
+        Some(name) => name,
+        None => state::read(store).await?.slot().to_string(),
+    };
+
+    let owned = name.starts_with("intake_")
+        || config.slot.as_deref() == Some(name.as_str())
+        || matches!(state::read(store).await, Ok(state) if state.slot() == name);
+    if !owned && !force {
+        return Err(Error::StateError(format!(
+            "slot {} isn't the configured slot nor the slot of the state, use --force to drop it",
+            name
+        )));
+    }
+
+    let client = connect(config).await?;
+    match slot(&client, &name).await? {
+        Some(slot) if slot.active => Err(active(&name)),
+        Some(slot) if slot.plugin != "wal2json" => Err(Error::StateError(format!(
+            "slot {} uses {}, it wasn't created by intake",
+            name, slot.plugin
+        ))),
+        Some(_) => {
+            client
+                .simple_query(&format!(
+                    "SELECT pg_drop_replication_slot({})",
+                    literal(&name)
+                ))
+                .await?;
+            Ok(format!("Dropped slot {}.", name))
+        }
+        None => Err(Error::StateError(format!("slot {} doesn't exist", name))),
+    }
+}
+
+async fn connect(config: &config::Postgresql) -> Result<Client, Error> {
+    let (client, connection) = tokio_postgres::connect(&config.url, NoTls)
+        .await
+        .map_err(|e| {
+            Error::ConnectionError(format!(
+                "could not connect to {}: {}",
+                config::redact(&config.url),
+                e
+            ))
+        })?;
+
+    tokio::spawn(async move {
+        let _ = connection.await;
+    });
+
+    Ok(client)
+}
+
+async fn slot(client: &Client, name: &str) -> Result<Option<Slot>, Error> {
+    let query = format!(
+        "SELECT plugin, active, restart_lsn, confirmed_flush_lsn FROM pg_replication_slots WHERE slot_name = {}",
+        literal(name)
+    );
+
+    for message in client.simple_query(&query).await? {
+        if let SimpleQueryMessage::Row(row) = message {
+            return Ok(Some(Slot {
+                plugin: row.get(0).unwrap_or_default().to_string(),
+                active: row.get(1) == Some("t"),
+                restart: row.get(2).and_then(provenance::parse_lsn),
+                confirmed: row.get(3).and_then(provenance::parse_lsn),
+            }));
+        }
+    }
+
+    Ok(None)
+}
+
+// Whether the slot exists on the server.
+pub(crate) async fn exists(client: &Client, name: &str) -> Result<bool, Error> {
+    Ok(slot(client, name).await?.is_some())
+}
+
+async fn value(client: &Client, query: &str) -> Result<String, Error> {
+    for message in client.simple_query(query).await? {
+        if let SimpleQueryMessage::Row(row) = message {
+            return Ok(row.get(0).unwrap_or_default().to_string());
+        }
+    }
+
+    Err(Error::ConnectionError(format!(
+        "{} returned nothing",
+        query
+    )))
+}
+
+fn active(slot: &str) -> Error {
+    Error::StateError(format!(
+        "slot {} is in use, stop intake before changing its state",
+        slot
+    ))
+}
+
+fn describe(lsn: Option<u64>) -> String {
+    lsn.map(provenance::lsn).unwrap_or_else(|| "none".into())
+}
+
+// Quote a string for a SQL query.
+pub(crate) fn literal(value: &str) -> String {
+    format!("'{}'", value.replace('\'', "''"))
+}
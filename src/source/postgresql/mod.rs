@@ -1,64 +1,187 @@
-use crate::events::Event;
+use crate::config;
+use crate::events::{provenance, watermark::Watermark, Event, Origin};
+use crate::health;
+use crate::metrics;
 use crate::source::Error;
+use errors::StateUpdateError;
 use futures::{future, ready, Sink, StreamExt};
 use std::pin::Pin;
-use std::sync::mpsc;
 use std::sync::{Arc, Mutex};
-use tokio::sync::mpsc::Sender;
+use std::time::Duration;
+use store::StateStore;
+use tokio::sync::mpsc::{Sender, UnboundedSender};
+use tokio::sync::Notify;
+use tokio::task::JoinHandle;
 use tokio_postgres::NoTls;
-use tokio_postgres::{Client, CopyBothDuplex, SimpleQueryRow};
-use yaml_rust::Yaml;
+use tokio_postgres::{Client, CopyBothDuplex};
 
+mod check;
+pub(crate) mod election;
 mod errors;
 mod event;
+pub(crate) mod manage;
 mod state;
+mod store;
 
-pub(crate) struct Connection(Client, Arc<Mutex<state::State>>);
+pub(crate) use check::check;
 
-pub(crate) async fn initialize(config: &Yaml, sender: mpsc::Sender<Error>) -> Connection {
-    let state = state::retrieve(config["state"].as_str().expect("state to be a string"));
-    println!("State: {:?}", &state);
+// Interval between two checkpoints of the state. The server is only told about the
+// LSN of the last checkpoint, it keeps the WAL after it until the next one.
+const CHECKPOINT: Duration = Duration::from_secs(10);
 
-    let url = config["url"].as_str().unwrap();
+pub(crate) struct Connection {
+    client: Client,
+    state: Arc<Mutex<state::State>>,
+    store: Arc<dyn StateStore>,
+    // Whether the slot outlives the connection.
+    persistent: bool,
+    failure: UnboundedSender<Error>,
+    watermark: Arc<Watermark>,
+
+    stop: Arc<Notify>,
+    acknowledge: Arc<Notify>,
+    ingest: Option<JoinHandle<()>>,
+}
 
-    let (client, connection) = tokio_postgres::connect(url, NoTls).await.unwrap();
-    println!("Spawning connection monitoring");
+pub(crate) async fn initialize(
+    config: &config::Postgresql,
+    storage: Option<&config::Storage>,
+    sender: UnboundedSender<Error>,
+    watermark: Arc<Watermark>,
+) -> Result<Connection, Error> {
+    let (client, connection) = tokio_postgres::connect(&config.url, NoTls)
+        .await
+        .map_err(|e| {
+            Error::ConnectionError(format!(
+                "could not connect to {}: {}",
+                config::redact(&config.url),
+                e
+            ))
+        })?;
+    log::debug!("Spawning connection monitoring");
 
+    let failure = sender.clone();
     tokio::spawn(async move {
         if let Err(e) = connection.await {
             sender.send(Error::from(e)).unwrap();
         }
     });
 
-    Connection(client, Arc::new(Mutex::new(state)))
+    let (slot, persistent) = match config.slot.as_ref() {
+        Some(slot) => (slot.clone(), true),
+        None => (format!("intake_{}", std::process::id()), false),
+    };
+    let exists = persistent && manage::exists(&client, &slot).await?;
+
+    let store = store::initialize(config, storage).await?;
+    let state = state::retrieve(store.as_ref(), &slot, persistent, exists).await?;
+    log::info!("State: {}", config::redact(&format!("{:?}", &state)));
+
+    Ok(Connection {
+        client,
+        state: Arc::new(Mutex::new(state)),
+        store,
+        persistent,
+        failure,
+        watermark,
+        stop: Arc::new(Notify::new()),
+        acknowledge: Arc::new(Notify::new()),
+        ingest: None,
+    })
 }
 
 impl Connection {
-    async fn start_replication(&mut self, row: &SimpleQueryRow, sender: Sender<Event>) {
-        let slot = row.get("slot_name").unwrap().to_string();
-        let lsn = row.get("consistent_point").unwrap().to_string();
+    // Identify the database intake is connected to. The result is embedded in every
+    // file so it's possible to know where the data came from.
+    async fn identify(&self) -> Result<Origin, Error> {
+        use tokio_postgres::SimpleQueryMessage;
+
+        for message in self.client.simple_query("IDENTIFY_SYSTEM").await? {
+            if let SimpleQueryMessage::Row(row) = message {
+                return Ok(Origin {
+                    system: row.get("systemid").unwrap_or_default().to_string(),
+                    timeline: row.get("timeline").unwrap_or_default().to_string(),
+                    database: row.get("dbname").unwrap_or_default().to_string(),
+                });
+            }
+        }
+
+        Err(Error::ConnectionError(
+            "IDENTIFY_SYSTEM did not return any row".into(),
+        ))
+    }
 
-        let query = format!("START_REPLICATION SLOT {} LOGICAL {}", slot, lsn);
+    async fn start_replication(&mut self, slot: &str, lsn: &str, sender: Sender<Event>) {
+        let query = format!(
+            "START_REPLICATION SLOT {} LOGICAL {} (\"include-timestamp\" '1', \"include-pk\" '1')",
+            slot, lsn
+        );
         let duplex_stream = self
-            .0
+            .client
             .copy_both_simple::<bytes::Bytes>(&query)
             .await
             .unwrap();
 
-        tokio::spawn(Self::ingest(
+        self.ingest = Some(tokio::spawn(Self::ingest(
             Box::pin(duplex_stream),
-            self.1.clone(),
+            self.state.clone(),
+            self.store.clone(),
             sender,
-        ));
+            self.failure.clone(),
+            self.watermark.clone(),
+            self.stop.clone(),
+            self.acknowledge.clone(),
+        )));
     }
 
+    // The state is saved every CHECKPOINT and when the replication stops, with the
+    // watermark as the flushed and applied positions. Status updates only report what
+    // was saved. A state saved by another instance stops the
+    // replication: the failure is reported and the stream is left without a status
+    // update.
     async fn ingest(
         mut stream: Pin<Box<CopyBothDuplex<bytes::Bytes>>>,
         state: Arc<Mutex<state::State>>,
+        store: Arc<dyn StateStore>,
         sender: Sender<Event>,
+        failure: UnboundedSender<Error>,
+        watermark: Arc<Watermark>,
+        stop: Arc<Notify>,
+        acknowledge: Arc<Notify>,
     ) {
+        let health = health::REGISTRY.track(health::REPLICATION);
+        let mut saved = state
+            .lock()
+            .expect("could not aquire lock for state")
+            .clone();
+        let mut checkpoints = tokio::time::interval(CHECKPOINT);
+        checkpoints.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
         loop {
-            let event_res_opt = stream.as_mut().next().await;
+            let event_res_opt = tokio::select! {
+                message = stream.as_mut().next() => message,
+                _ = checkpoints.tick() => {
+                    match checkpoint(&state, &watermark, store.as_ref(), &mut saved).await {
+                        Ok(()) => keepalive(&mut stream, &saved).await,
+                        Err(StateUpdateError::ConflictError(e)) => {
+                            let _ = failure.send(Error::ConflictError(e));
+                            break;
+                        }
+                        Err(e) => log::warn!("Could not save the state: {}", e),
+                    }
+                    continue;
+                }
+                _ = stop.notified() => {
+                    // The stream is kept open to send the last status update.
+                    acknowledge.notified().await;
+
+                    match checkpoint(&state, &watermark, store.as_ref(), &mut saved).await {
+                        Ok(()) => keepalive(&mut stream, &saved).await,
+                        Err(e) => log::warn!("Could not save the state: {}", e),
+                    }
+                    break;
+                }
+            };
             if event_res_opt.is_none() {
                 break;
             }
@@ -67,10 +190,12 @@ impl Connection {
                 continue;
             }
             let event = event_res.unwrap();
+            health.beat();
 
             if event[0] == b'w' {
                 let wal = &event[1..25];
                 let data = &event[25..];
+                let lsn = u64::from_be_bytes(wal[0..8].try_into().unwrap());
 
                 state
                     .lock()
@@ -78,34 +203,59 @@ impl Connection {
                     .start(wal)
                     .unwrap();
 
-                let events = event::from_json(data).unwrap();
+                let events = event::from_json(data, lsn).unwrap();
                 let mut iterator = events.into_iter();
                 while let Some(event) = iterator.next() {
                     sender.send(event).await.unwrap();
                 }
+                sender.send(Event::Received(lsn)).await.unwrap();
 
-                state
-                    .lock()
-                    .expect("could not aquire lock for state")
-                    .done(wal)
-                    .unwrap();
+                let end = u64::from_be_bytes(wal[8..16].try_into().unwrap());
+                record_lag(&state.lock().expect("could not aquire lock for state"), end);
             }
             // type: keepalive message
             else if event[0] == b'k' {
+                let end = u64::from_be_bytes(event[1..9].try_into().unwrap());
+                record_lag(&state.lock().expect("could not aquire lock for state"), end);
+
                 let last_byte = event.last().unwrap();
                 let timeout_imminent = last_byte == &1;
                 if timeout_imminent {
-                    keepalive(&mut stream, state.clone()).await;
+                    keepalive(&mut stream, &saved).await;
                 }
             }
         }
     }
 }
 
-async fn keepalive(
-    stream: &mut Pin<Box<CopyBothDuplex<bytes::Bytes>>>,
-    state: Arc<Mutex<state::State>>,
-) {
+// Move the state to the watermark and save it when it moved since the last checkpoint.
+async fn checkpoint(
+    state: &Mutex<state::State>,
+    watermark: &Watermark,
+    store: &dyn StateStore,
+    saved: &mut state::State,
+) -> Result<(), StateUpdateError> {
+    let current = {
+        let mut state = state.lock().expect("could not aquire lock for state");
+        state.durable(watermark.lsn());
+        state.clone()
+    };
+    if current.lsn() == saved.lsn() {
+        return Ok(());
+    }
+
+    state::persist(store, &current).await?;
+    *saved = current;
+    Ok(())
+}
+
+fn record_lag(state: &state::State, end: u64) {
+    let (bytes, seconds) = state.lag(end);
+    metrics::LAG_BYTES.set(&[], bytes as f64);
+    metrics::LAG_SECONDS.set(&[], seconds);
+}
+
+async fn keepalive(stream: &mut Pin<Box<CopyBothDuplex<bytes::Bytes>>>, state: &state::State) {
     use bytes::Bytes;
     use std::task::Poll;
     use std::time::{SystemTime, UNIX_EPOCH};
@@ -121,22 +271,18 @@ async fn keepalive(
 
     let mut data_to_send: Vec<u8> = vec![114]; // "r" in ascii
 
-    {
-        let state = state.lock().expect("unable to obtain lock on state");
-
-        // see here for format details: https://www.postgresql.org/docs/10/protocol-replication.html
-        data_to_send.extend_from_slice(&state.last_flushed());
-        data_to_send.extend_from_slice(&state.last_flushed());
-        data_to_send.extend_from_slice(&state.last_applied());
-        data_to_send.extend_from_slice(&time_since_2000.to_be_bytes());
-        data_to_send.extend_from_slice(&[1]);
-    }
+    // see here for format details: https://www.postgresql.org/docs/10/protocol-replication.html
+    data_to_send.extend_from_slice(&state.last_flushed());
+    data_to_send.extend_from_slice(&state.last_flushed());
+    data_to_send.extend_from_slice(&state.last_applied());
+    data_to_send.extend_from_slice(&time_since_2000.to_be_bytes());
+    data_to_send.extend_from_slice(&[1]);
 
     let buf = Bytes::from(data_to_send);
 
     let mut next_step = 1;
     future::poll_fn(|cx| loop {
-        println!("Doing step:{}", next_step);
+        log::debug!("Doing step:{}", next_step);
         match next_step {
             1 => {
                 ready!(stream.as_mut().poll_ready(cx)).unwrap();
@@ -154,24 +300,52 @@ async fn keepalive(
     })
     .await;
 
-    println!("Sent response to keepalive message/warning!:{:x?}", buf);
+    log::debug!("Sent response to keepalive message/warning!:{:x?}", buf);
 }
 
 #[async_trait::async_trait]
 impl super::Client for Connection {
+    async fn stop(&mut self) {
+        self.stop.notify_one();
+    }
+
+    async fn acknowledge(&mut self) {
+        self.acknowledge.notify_one();
+
+        if let Some(ingest) = self.ingest.take() {
+            if let Err(e) = ingest.await {
+                log::warn!("Could not acknowledge the state: {}", e);
+            }
+        }
+    }
+
     async fn connect(&mut self, sender: Sender<Event>) {
         use tokio_postgres::SimpleQueryMessage;
 
+        let origin = self.identify().await.unwrap();
+        sender.send(Event::Connected(origin)).await.unwrap();
+
+        let (slot, lsn) = {
+            let state = self.state.lock().expect("could not obtain lock for state");
+            (state.slot().to_string(), state.lsn())
+        };
+
+        // A persistent slot keeps its position on the server, the replication resumes
+        // from the state or, when the state was reset, from the slot's position.
+        if self.persistent && manage::exists(&self.client, &slot).await.unwrap() {
+            self.start_replication(&slot, &provenance::lsn(lsn), sender)
+                .await;
+            return;
+        }
+
         let query = format!(
-            "CREATE_REPLICATION_SLOT {} TEMPORARY LOGICAL wal2json",
-            self.1
-                .lock()
-                .expect("could not obtain lock for state")
-                .slot()
+            "CREATE_REPLICATION_SLOT {} {}LOGICAL wal2json",
+            slot,
+            if self.persistent { "" } else { "TEMPORARY " }
         );
-        println!("Query: {}", &query);
+        log::debug!("Query: {}", config::redact(&query));
 
-        let mut rows = self.0.simple_query(&query).await.unwrap();
+        let mut rows = self.client.simple_query(&query).await.unwrap();
 
         // There should only be 1 row that is returned for the replication
         // information. However, postgres will usually return more than 1;
@@ -190,9 +364,20 @@ impl super::Client for Connection {
 
         for row in rows {
             match row {
-                SimpleQueryMessage::Row(r) => self.start_replication(&r, sender.clone()).await,
-                SimpleQueryMessage::CommandComplete(u) => println!("Bytes written: {}", u),
-                _ => println!("Unknown message"),
+                SimpleQueryMessage::Row(r) => {
+                    let lsn = r.get("consistent_point").unwrap().to_string();
+                    let created = {
+                        let mut state = self.state.lock().expect("could not obtain lock for state");
+                        state.created(&lsn);
+                        state.clone()
+                    };
+                    state::persist(self.store.as_ref(), &created)
+                        .await
+                        .unwrap_or_else(|e| panic!("{}", e));
+                    self.start_replication(&slot, &lsn, sender.clone()).await
+                }
+                SimpleQueryMessage::CommandComplete(u) => log::debug!("Bytes written: {}", u),
+                _ => log::debug!("Unknown message"),
             }
         }
     }
@@ -1,17 +1,33 @@
 use super::errors::StateUpdateError;
+use super::store::StateStore;
+use crate::events::provenance;
+use chrono::{DateTime, Duration, TimeZone, Utc};
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::fmt;
 
-#[derive(Serialize, Deserialize, Debug, Default)]
+const VERSION: u32 = 2;
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
 pub(crate) struct State {
     slot: String,
     last_consistent_point: String,
     wal: LastKnownWalState,
 
     #[serde(skip_serializing, default)]
-    path: String,
+    location: String,
+}
+
+// Envelope of the state on disk. The checksum covers the state so a state that
+// was damaged is never resumed from.
+#[derive(Serialize, Deserialize)]
+struct Envelope {
+    version: u32,
+    checksum: String,
+    state: Value,
 }
 
-#[derive(Serialize, Deserialize, Debug, Default)]
+#[derive(Serialize, Deserialize, Debug, Default, Clone)]
 pub(crate) struct LastKnownWalState {
     start: i64,
     flushed: i64,
@@ -41,45 +57,242 @@ impl State {
         Ok(())
     }
 
-    pub(crate) fn done(&mut self, _data: &[u8]) -> Result<(), StateUpdateError> {
-        self.wal.flushed = self.wal.start;
-        self.wal.applied = self.wal.start;
+    // Record that every change up to lsn is durable, the position never moves back.
+    pub(crate) fn durable(&mut self, lsn: u64) {
+        if lsn as i64 > self.wal.flushed {
+            self.wal.flushed = lsn as i64;
+            self.wal.applied = lsn as i64;
+        }
+    }
+
+    // Record the position of a slot that was just created.
+    pub(crate) fn created(&mut self, consistent_point: &str) {
+        self.last_consistent_point = consistent_point.to_string();
+    }
+
+    // LSN of the last change that was replicated.
+    pub(crate) fn lsn(&self) -> u64 {
+        self.wal.flushed as u64
+    }
+
+    // Restart the replication from lsn.
+    pub(crate) fn set_lsn(&mut self, lsn: u64) {
+        self.wal.start = lsn as i64;
+        self.wal.flushed = lsn as i64;
+        self.wal.applied = lsn as i64;
+    }
+
+    // Forget what was replicated, the replication restarts from the slot's position.
+    pub(crate) fn reset(&mut self) {
+        self.wal = LastKnownWalState::default();
+        self.last_consistent_point = String::new();
+    }
+
+    // Bytes and seconds the state is behind end, the server's WAL end. Seconds are
+    // counted from when the server sent the last change, 0 when caught up.
+    pub(crate) fn lag(&self, end: u64) -> (u64, f64) {
+        let bytes = end.saturating_sub(self.lsn());
+        let seconds = match self.clock() {
+            Some(clock) if bytes > 0 => (Utc::now() - clock).num_milliseconds() as f64 / 1000.0,
+            _ => 0.0,
+        };
+
+        (bytes, seconds.max(0.0))
+    }
+
+    // Time of the server when it sent the last change, the clock is in microseconds
+    // since 2000-01-01.
+    fn clock(&self) -> Option<DateTime<Utc>> {
+        if self.wal.clock == 0 {
+            return None;
+        }
 
-        persist(self.path.as_str(), &self)
+        Some(
+            Utc.with_ymd_and_hms(2000, 1, 1, 0, 0, 0).unwrap()
+                + Duration::microseconds(self.wal.clock),
+        )
     }
 }
 
-pub(crate) fn retrieve(path: &str) -> State {
-    use std::fs::File;
-    use std::io::prelude::*;
+impl fmt::Display for State {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "State:            {}", self.location)?;
+        writeln!(f, "Slot:             {}", self.slot)?;
+        writeln!(f, "Consistent point: {}", self.last_consistent_point)?;
+        writeln!(
+            f,
+            "Started:          {}",
+            provenance::lsn(self.wal.start as u64)
+        )?;
+        writeln!(
+            f,
+            "Flushed:          {}",
+            provenance::lsn(self.wal.flushed as u64)
+        )?;
+        writeln!(
+            f,
+            "Applied:          {}",
+            provenance::lsn(self.wal.applied as u64)
+        )?;
+        match self.clock() {
+            Some(clock) => writeln!(f, "Server clock:     {}", clock.to_rfc3339()),
+            None => writeln!(f, "Server clock:     unknown"),
+        }
+    }
+}
 
-    match File::open(path) {
-        Ok(mut f) => {
-            let mut buffer = Vec::new();
-            f.read_to_end(&mut buffer).unwrap();
-            State {
-                path: path.to_string(),
-                ..serde_json::from_slice(&buffer).unwrap()
+// Return the state saved in store, without creating it. States written by a
+// previous version are migrated, states that don't match their checksum are refused.
+pub(crate) async fn read(store: &dyn StateStore) -> Result<State, StateUpdateError> {
+    let location = store.location();
+    let content = store
+        .load()
+        .await?
+        .ok_or_else(|| StateUpdateError::MissingError(location.clone()))?;
+    let content: Value = serde_json::from_slice(&content)?;
+
+    // The first version was the state itself, without an envelope.
+    let (version, state) = match content.get("version") {
+        Some(_) => {
+            let envelope: Envelope = serde_json::from_value(content)?;
+            let checksum = checksum(&envelope.state)?;
+            if checksum != envelope.checksum {
+                return Err(StateUpdateError::ChecksumError(format!(
+                    "{} has checksum {}, expected {}",
+                    location, checksum, envelope.checksum
+                )));
             }
+            (envelope.version, envelope.state)
         }
-        Err(_) => {
-            let s = State {
-                path: path.to_string(),
-                slot: "test1".to_string(),
+        None => (1, content),
+    };
+
+    Ok(State {
+        location,
+        ..serde_json::from_value(migrate(version, state)?)?
+    })
+}
+
+// Return the state of slot saved in store. A missing state is only created when
+// there's nothing to lose: a persistent slot that already exists on the server has
+// a position that only the state knows how to resume from.
+//
+// Temporary slots don't survive a connection so their state is adopted by the
+// slot of the new connection.
+pub(crate) async fn retrieve(
+    store: &dyn StateStore,
+    slot: &str,
+    persistent: bool,
+    exists: bool,
+) -> Result<State, StateUpdateError> {
+    let mut state = match read(store).await {
+        Ok(state) => state,
+        Err(StateUpdateError::MissingError(location)) => {
+            if persistent && exists {
+                return Err(StateUpdateError::SlotError(format!(
+                    "{} is missing but slot {} exists on the server. Restore the state, or drop the slot with `intake state drop-slot` to start over",
+                    location, slot
+                )));
+            }
+
+            State {
+                location,
                 ..State::default()
-            };
-            persist(path, &s).unwrap();
-            s
+            }
         }
+        Err(e) => return Err(e),
+    };
+
+    if state.slot != slot {
+        if persistent && !state.slot.is_empty() {
+            return Err(StateUpdateError::SlotError(format!(
+                "{} belongs to slot {}, not {}",
+                state.location, state.slot, slot
+            )));
+        }
+        state.slot = slot.to_string();
+    }
+
+    persist(store, &state).await?;
+    Ok(state)
+}
+
+// Save the state in store.
+pub(crate) async fn persist(store: &dyn StateStore, state: &State) -> Result<(), StateUpdateError> {
+    let state = serde_json::to_value(state)?;
+    let envelope = Envelope {
+        version: VERSION,
+        checksum: checksum(&state)?,
+        state,
+    };
+
+    store.save(serde_json::to_vec_pretty(&envelope)?).await
+}
+
+// Bring a state written by a previous version to the current format.
+fn migrate(version: u32, state: Value) -> Result<Value, StateUpdateError> {
+    match version {
+        // Only the envelope changed.
+        1 => Ok(state),
+        VERSION => Ok(state),
+        _ => Err(StateUpdateError::VersionError(version)),
     }
 }
 
-fn persist(path: &str, state: &State) -> Result<(), StateUpdateError> {
-    use std::fs::File;
-    use std::io::Write;
+fn checksum(state: &Value) -> Result<String, StateUpdateError> {
+    Ok(format!(
+        "{:08x}",
+        crc32c::crc32c(&serde_json::to_vec(state)?)
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::store::File;
+    use super::StateUpdateError;
+
+    #[tokio::test]
+    async fn migrates_and_verifies_states() {
+        let path = std::env::temp_dir().join(format!("intake-state-{}", uuid::Uuid::new_v4()));
+        let path = path.to_str().unwrap();
+        let store = File::new(path);
 
-    let mut file = File::create(path).unwrap();
-    let data = serde_json::to_vec(state).unwrap();
-    file.write(data.as_slice()).unwrap();
-    Ok(())
+        // A state written before states were versioned.
+        std::fs::write(
+            path,
+            r#"{"slot":"intake","last_consistent_point":"0/16B3748","wal":{"start":10,"flushed":10,"applied":10,"clock":0}}"#,
+        )
+        .unwrap();
+
+        let state = super::retrieve(&store, "intake", true, true).await.unwrap();
+        assert_eq!(state.lsn(), 10);
+        assert_eq!(super::read(&store).await.unwrap().lsn(), 10);
+
+        assert!(matches!(
+            super::retrieve(&store, "other", true, true).await,
+            Err(StateUpdateError::SlotError(_))
+        ));
+
+        let content = std::fs::read_to_string(path).unwrap();
+        std::fs::write(path, content.replace("10", "20")).unwrap();
+        assert!(matches!(
+            super::read(&store).await,
+            Err(StateUpdateError::ChecksumError(_))
+        ));
+
+        std::fs::remove_file(path).unwrap();
+        assert!(matches!(
+            super::retrieve(&store, "intake", true, true).await,
+            Err(StateUpdateError::SlotError(_))
+        ));
+        assert_eq!(
+            super::retrieve(&store, "intake", true, false)
+                .await
+                .unwrap()
+                .lsn(),
+            0
+        );
+
+        std::fs::remove_file(path).unwrap();
+    }
 }
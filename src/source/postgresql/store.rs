@@ -0,0 +1,369 @@
+use super::errors::StateUpdateError;
+use super::manage::literal;
+use crate::config;
+use crate::events::output;
+use crate::storage::{self, Expeditor};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::sync::Arc;
+use tokio_postgres::{Client, NoTls, SimpleQueryMessage};
+
+impl From<storage::Error> for StateUpdateError {
+    fn from(e: storage::Error) -> Self {
+        StateUpdateError::StoreError(e.to_string())
+    }
+}
+
+impl From<tokio_postgres::Error> for StateUpdateError {
+    fn from(e: tokio_postgres::Error) -> Self {
+        StateUpdateError::StoreError(e.to_string())
+    }
+}
+
+// StateStore keeps the encoded replication state. The stores that can be shared by
+// multiple instances only save a state when the state they hold is the last one
+// saved, so an instance that was replaced can't overwrite the state of the new one.
+#[async_trait::async_trait]
+pub(crate) trait StateStore: Send + Sync {
+    // Return the last state saved, or None when no state was saved yet.
+    async fn load(&self) -> Result<Option<Vec<u8>>, StateUpdateError>;
+
+    // Replace the state. Fails with a ConflictError when another instance saved a
+    // state since this one was loaded or saved.
+    async fn save(&self, content: Vec<u8>) -> Result<(), StateUpdateError>;
+
+    // Where the state is kept, for messages.
+    fn location(&self) -> String;
+}
+
+// Return the StateStore of the source's `state` block.
+pub(crate) async fn initialize(
+    config: &config::Postgresql,
+    storage: Option<&config::Storage>,
+) -> Result<Arc<dyn StateStore>, StateUpdateError> {
+    open(config, storage, "").await
+}
+
+// Return the store of the election's lease, kept next to the state.
+pub(crate) async fn lease(
+    config: &config::Postgresql,
+    storage: Option<&config::Storage>,
+) -> Result<Arc<dyn StateStore>, StateUpdateError> {
+    open(config, storage, ".lease").await
+}
+
+async fn open(
+    config: &config::Postgresql,
+    storage: Option<&config::Storage>,
+    suffix: &str,
+) -> Result<Arc<dyn StateStore>, StateUpdateError> {
+    match &config.state {
+        config::StateStore::File(store) => {
+            Ok(Arc::new(File::new(&format!("{}{}", store.path, suffix))))
+        }
+        config::StateStore::Postgresql(store) => {
+            let url = store.url.as_deref().unwrap_or(&config.url);
+            let name = format!("{}{}", store.name, suffix);
+            Ok(Arc::new(Table::initialize(url, store, &name).await?))
+        }
+        config::StateStore::Storage(store) => {
+            let expeditor = storage::initialize(storage)
+                .await?
+                .ok_or_else(|| StateUpdateError::StoreError("no storage is configured".into()))?;
+            let key = format!("{}{}", store.key.trim_end_matches('/'), suffix);
+            Ok(Arc::new(Object::new(expeditor, &key)))
+        }
+    }
+}
+
+// File keeps the state on local disk. The file is replaced atomically.
+pub(crate) struct File {
+    path: PathBuf,
+}
+
+impl File {
+    pub(crate) fn new(path: &str) -> File {
+        File {
+            path: PathBuf::from(path),
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl StateStore for File {
+    async fn load(&self) -> Result<Option<Vec<u8>>, StateUpdateError> {
+        match tokio::fs::read(&self.path).await {
+            Ok(content) => Ok(Some(content)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn save(&self, content: Vec<u8>) -> Result<(), StateUpdateError> {
+        Ok(output::write(&self.path, &content)?)
+    }
+
+    fn location(&self) -> String {
+        self.path.to_string_lossy().to_string()
+    }
+}
+
+// Table keeps the state in a row of a Postgres table, created when it doesn't
+// exist. Every save increments the generation of the row and only applies to the
+// generation this instance holds.
+pub(crate) struct Table {
+    client: Client,
+    table: String,
+    name: String,
+    location: String,
+    generation: AtomicI64,
+}
+
+impl Table {
+    async fn initialize(
+        url: &str,
+        config: &config::TableStore,
+        name: &str,
+    ) -> Result<Table, StateUpdateError> {
+        let (client, connection) = tokio_postgres::connect(url, NoTls).await.map_err(|e| {
+            StateUpdateError::StoreError(format!(
+                "could not connect to {}: {}",
+                config::redact(url),
+                e
+            ))
+        })?;
+
+        tokio::spawn(async move {
+            if let Err(e) = connection.await {
+                log::warn!("State store disconnected: {}", e);
+            }
+        });
+
+        client
+            .simple_query(&format!(
+                "CREATE TABLE IF NOT EXISTS {} (\
+                    name text PRIMARY KEY, \
+                    generation bigint NOT NULL, \
+                    content text NOT NULL, \
+                    saved_at timestamptz NOT NULL DEFAULT now())",
+                config.table
+            ))
+            .await?;
+
+        Ok(Table {
+            client,
+            table: config.table.clone(),
+            name: name.to_string(),
+            location: format!("{} ({})", config.table, name),
+            generation: AtomicI64::new(0),
+        })
+    }
+
+    // Run a statement and return the number of rows it changed.
+    async fn execute(&self, query: &str) -> Result<u64, StateUpdateError> {
+        for message in self.client.simple_query(query).await? {
+            if let SimpleQueryMessage::CommandComplete(rows) = message {
+                return Ok(rows);
+            }
+        }
+
+        Ok(0)
+    }
+}
+
+#[async_trait::async_trait]
+impl StateStore for Table {
+    async fn load(&self) -> Result<Option<Vec<u8>>, StateUpdateError> {
+        let query = format!(
+            "SELECT generation, content FROM {} WHERE name = {}",
+            self.table,
+            literal(&self.name)
+        );
+
+        for message in self.client.simple_query(&query).await? {
+            if let SimpleQueryMessage::Row(row) = message {
+                let generation = row
+                    .get(0)
+                    .and_then(|generation| generation.parse().ok())
+                    .unwrap_or(0);
+                self.generation.store(generation, Ordering::SeqCst);
+                return Ok(row.get(1).map(|content| content.as_bytes().to_vec()));
+            }
+        }
+
+        Ok(None)
+    }
+
+    async fn save(&self, content: Vec<u8>) -> Result<(), StateUpdateError> {
+        let content =
+            String::from_utf8(content).map_err(|e| StateUpdateError::StoreError(e.to_string()))?;
+        let generation = self.generation.load(Ordering::SeqCst);
+
+        let query = match generation {
+            0 => format!(
+                "INSERT INTO {} (name, generation, content) VALUES ({}, 1, {}) ON CONFLICT (name) DO NOTHING",
+                self.table,
+                literal(&self.name),
+                literal(&content)
+            ),
+            _ => format!(
+                "UPDATE {} SET generation = generation + 1, content = {}, saved_at = now() WHERE name = {} AND generation = {}",
+                self.table,
+                literal(&content),
+                literal(&self.name),
+                generation
+            ),
+        };
+
+        if self.execute(&query).await? != 1 {
+            return Err(StateUpdateError::ConflictError(format!(
+                "{} was saved by another instance",
+                self.location
+            )));
+        }
+
+        self.generation.store(generation + 1, Ordering::SeqCst);
+        Ok(())
+    }
+
+    fn location(&self) -> String {
+        self.location.clone()
+    }
+}
+
+// Object keeps the state in the destination of the storage block. Every save
+// creates the next generation of the state at `{key}/{generation}.json`, which
+// fails when another instance created it first. An instance that fell further
+// behind can still create its next generation once it was removed, so the save
+// then looks for a newer generation and fails if there is one. Only the instance
+// whose save went through removes the previous generations. Loading returns the
+// latest generation.
+pub(crate) struct Object {
+    expeditor: Arc<dyn Expeditor>,
+    key: String,
+    generation: AtomicU64,
+}
+
+impl Object {
+    pub(crate) fn new(expeditor: Arc<dyn Expeditor>, key: &str) -> Object {
+        Object {
+            expeditor,
+            key: key.trim_matches('/').to_string(),
+            generation: AtomicU64::new(0),
+        }
+    }
+
+    fn generation_key(&self, generation: u64) -> String {
+        format!("{}/{:020}.json", self.key, generation)
+    }
+
+    // Return the generations that are saved, in order.
+    async fn generations(&self) -> Result<Vec<u64>, StateUpdateError> {
+        let prefix = format!("{}/", self.key);
+        let mut generations: Vec<u64> = self
+            .expeditor
+            .list(&prefix)
+            .await?
+            .iter()
+            .filter_map(|key| {
+                key.strip_prefix(&prefix)?
+                    .strip_suffix(".json")?
+                    .parse()
+                    .ok()
+            })
+            .collect();
+        generations.sort_unstable();
+
+        Ok(generations)
+    }
+}
+
+#[async_trait::async_trait]
+impl StateStore for Object {
+    async fn load(&self) -> Result<Option<Vec<u8>>, StateUpdateError> {
+        // Older generations are left to the next save, another instance may still
+        // be saving.
+        let latest = match self.generations().await?.pop() {
+            Some(latest) => latest,
+            None => return Ok(None),
+        };
+
+        let content = self.expeditor.get(&self.generation_key(latest)).await?;
+        self.generation.store(latest, Ordering::SeqCst);
+        Ok(content)
+    }
+
+    async fn save(&self, content: Vec<u8>) -> Result<(), StateUpdateError> {
+        let generation = self.generation.load(Ordering::SeqCst) + 1;
+        let conflict = || {
+            StateUpdateError::ConflictError(format!(
+                "{} was saved by another instance",
+                self.location()
+            ))
+        };
+
+        if !self
+            .expeditor
+            .create(&self.generation_key(generation), content)
+            .await?
+        {
+            return Err(conflict());
+        }
+
+        // The instance that saved a newer generation keeps it until it saves the
+        // next one, so it's listed if there is one.
+        let generations = self.generations().await?;
+        if generations.last() != Some(&generation) {
+            self.expeditor
+                .delete(&self.generation_key(generation))
+                .await?;
+            return Err(conflict());
+        }
+        self.generation.store(generation, Ordering::SeqCst);
+
+        // Previous generations, and those left behind by an interrupted save.
+        for previous in generations.into_iter().filter(|g| *g < generation) {
+            self.expeditor
+                .delete(&self.generation_key(previous))
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    fn location(&self) -> String {
+        format!("{}/{}", self.expeditor.location(), self.key)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Object, StateStore, StateUpdateError};
+
+    #[tokio::test]
+    async fn objects_refuse_stale_saves() {
+        let root = std::env::temp_dir().join(format!("intake-store-{}", uuid::Uuid::new_v4()));
+        let expeditor = crate::storage::local(&root);
+
+        let first = Object::new(expeditor.clone(), "_state/intake");
+        assert!(first.load().await.unwrap().is_none());
+        first.save(b"one".to_vec()).await.unwrap();
+        first.save(b"two".to_vec()).await.unwrap();
+
+        let second = Object::new(expeditor.clone(), "_state/intake");
+        assert_eq!(second.load().await.unwrap().unwrap(), b"two");
+        second.save(b"three".to_vec()).await.unwrap();
+        second.save(b"four".to_vec()).await.unwrap();
+
+        // The first instance still holds the second generation, whose next one was
+        // already removed.
+        assert!(matches!(
+            first.save(b"five".to_vec()).await,
+            Err(StateUpdateError::ConflictError(_))
+        ));
+        assert_eq!(second.load().await.unwrap().unwrap(), b"four");
+        assert_eq!(expeditor.list("_state/").await.unwrap().len(), 1);
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
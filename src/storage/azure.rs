@@ -0,0 +1,472 @@
+use crate::config;
+use crate::storage::{Checksum, Error, Expeditor, Prefix};
+use reqwest::{Client, Method, RequestBuilder, StatusCode, Url};
+use std::collections::BTreeMap;
+use std::path::Path;
+
+const VERSION: &str = "2021-08-06";
+
+// Azure ships files to a container of an Azure Blob Storage account, using the REST API.
+//
+//   storage:
+//     driver: azure
+//     container: intake
+//     prefix: replicated
+//     account: intake
+//     key: base64==                     # shared key, or
+//     sas: sv=2021-08-06&ss=b&sig=...   # SAS token, or
+//     connection_string: DefaultEndpointsProtocol=https;AccountName=...;AccountKey=...
+//     endpoint: http://127.0.0.1:10000/devstoreaccount1  # optional, ie. Azurite
+pub(crate) struct Azure {
+    client: Client,
+    endpoint: String,
+    account: String,
+    container: String,
+    prefix: Prefix,
+    credentials: Credentials,
+}
+
+enum Credentials {
+    SharedKey(Vec<u8>),
+    Sas(String),
+}
+
+pub(crate) fn initialize(config: &config::Azure) -> Result<Azure, Error> {
+    let mut settings = BTreeMap::new();
+    if let Some(connection) = config.connection_string.as_ref() {
+        for pair in connection.split(';').filter(|pair| !pair.is_empty()) {
+            let (key, value) = pair.split_once('=').ok_or_else(|| {
+                Error::ConfigError(format!("invalid storage.connection_string entry: {}", pair))
+            })?;
+            settings.insert(key.to_string(), value.to_string());
+        }
+    }
+
+    let account = config
+        .account
+        .clone()
+        .or_else(|| settings.get("AccountName").cloned())
+        .ok_or_else(|| {
+            Error::ConfigError("storage needs an account or a connection_string".into())
+        })?;
+
+    let endpoint = config
+        .endpoint
+        .clone()
+        .or_else(|| settings.get("BlobEndpoint").cloned())
+        .unwrap_or_else(|| {
+            let protocol = settings
+                .get("DefaultEndpointsProtocol")
+                .map(String::as_str)
+                .unwrap_or("https");
+            let suffix = settings
+                .get("EndpointSuffix")
+                .map(String::as_str)
+                .unwrap_or("core.windows.net");
+            format!("{}://{}.blob.{}", protocol, account, suffix)
+        });
+
+    let key = config
+        .key
+        .clone()
+        .or_else(|| settings.get("AccountKey").cloned());
+    let sas = config
+        .sas
+        .clone()
+        .or_else(|| settings.get("SharedAccessSignature").cloned());
+
+    let credentials = match (key, sas) {
+        (Some(key), _) => {
+            use base64::Engine;
+
+            let key = base64::engine::general_purpose::STANDARD
+                .decode(key)
+                .map_err(|e| Error::ConfigError(format!("storage.key is not base64: {}", e)))?;
+            Credentials::SharedKey(key)
+        }
+        (None, Some(sas)) => Credentials::Sas(sas.trim_start_matches('?').to_string()),
+        (None, None) => {
+            return Err(Error::ConfigError(
+                "storage needs either a key, a sas or a connection_string".into(),
+            ))
+        }
+    };
+
+    Ok(Azure {
+        client: Client::new(),
+        endpoint: endpoint.trim_end_matches('/').to_string(),
+        account,
+        container: config.container.clone(),
+        prefix: Prefix::from(config.prefix.as_str()),
+        credentials,
+    })
+}
+
+impl Azure {
+    // Build a signed request for the blob at key, or for the container when key is None.
+    // Headers can only be `Content-MD5`, `If-None-Match` or `x-ms-*` headers, as they're
+    // part of the signature.
+    fn request(
+        &self,
+        method: Method,
+        key: Option<&str>,
+        query: &[(&str, &str)],
+        headers: &[(&str, String)],
+        length: usize,
+    ) -> Result<RequestBuilder, Error> {
+        let mut url = Url::parse(&self.endpoint)
+            .map_err(|e| Error::ConfigError(format!("invalid storage.endpoint: {}", e)))?;
+
+        {
+            let mut segments = url
+                .path_segments_mut()
+                .map_err(|_| Error::ConfigError("invalid storage.endpoint".into()))?;
+            segments.pop_if_empty().push(&self.container);
+            if let Some(key) = key {
+                segments.extend(self.prefix.join(key).split('/'));
+            }
+        }
+
+        for (name, value) in query.iter() {
+            url.query_pairs_mut().append_pair(name, value);
+        }
+
+        let date = chrono::Utc::now()
+            .format("%a, %d %b %Y %H:%M:%S GMT")
+            .to_string();
+
+        let authorization = match &self.credentials {
+            Credentials::SharedKey(key) => {
+                Some(self.sign(key, &method, &url, query, headers, &date, length))
+            }
+            Credentials::Sas(sas) => {
+                let query = match url.query() {
+                    Some(query) => format!("{}&{}", query, sas),
+                    None => sas.clone(),
+                };
+                url.set_query(Some(&query));
+                None
+            }
+        };
+
+        let mut request = self
+            .client
+            .request(method, url)
+            .header("x-ms-date", date)
+            .header("x-ms-version", VERSION);
+
+        if let Some(authorization) = authorization {
+            request = request.header("Authorization", authorization);
+        }
+
+        for (name, value) in headers.iter() {
+            request = request.header(*name, value);
+        }
+
+        Ok(request)
+    }
+
+    // Shared Key authorization, as described in
+    // https://learn.microsoft.com/en-us/rest/api/storageservices/authorize-with-shared-key
+    #[allow(clippy::too_many_arguments)]
+    fn sign(
+        &self,
+        key: &[u8],
+        method: &Method,
+        url: &Url,
+        query: &[(&str, &str)],
+        headers: &[(&str, String)],
+        date: &str,
+        length: usize,
+    ) -> String {
+        use base64::Engine;
+        use hmac::{Hmac, Mac};
+        use sha2::Sha256;
+
+        let length = if length > 0 {
+            length.to_string()
+        } else {
+            String::new()
+        };
+
+        let header = |name: &str| {
+            headers
+                .iter()
+                .find(|(header, _)| *header == name)
+                .map(|(_, value)| value.as_str())
+                .unwrap_or("")
+        };
+
+        let mut canonical: Vec<String> = headers
+            .iter()
+            .filter(|(name, _)| name.starts_with("x-ms-"))
+            .map(|(name, value)| format!("{}:{}", name, value))
+            .chain([
+                format!("x-ms-date:{}", date),
+                format!("x-ms-version:{}", VERSION),
+            ])
+            .collect();
+        canonical.sort();
+
+        let mut resource = format!("/{}{}", self.account, url.path());
+        let mut parameters: Vec<(String, &str)> = query
+            .iter()
+            .map(|(name, value)| (name.to_lowercase(), *value))
+            .collect();
+        parameters.sort();
+        for (name, value) in parameters {
+            resource.push_str(&format!("\n{}:{}", name, value));
+        }
+
+        let content_type = if method == Method::PUT {
+            "application/octet-stream"
+        } else {
+            ""
+        };
+
+        let signature = format!(
+            "{}\n\n\n{}\n{}\n{}\n\n\n\n{}\n\n\n{}\n{}",
+            method.as_str(),
+            length,
+            header("Content-MD5"),
+            content_type,
+            header("If-None-Match"),
+            canonical.join("\n"),
+            resource
+        );
+
+        let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC accepts keys of any length");
+        mac.update(signature.as_bytes());
+        let signature =
+            base64::engine::general_purpose::STANDARD.encode(mac.finalize().into_bytes());
+
+        format!("SharedKey {}:{}", self.account, signature)
+    }
+}
+
+#[async_trait::async_trait]
+impl Expeditor for Azure {
+    // Azure verifies the MD5 of the blob, the other digests are kept as metadata.
+    async fn upload(&self, path: &Path, key: &str, checksum: &Checksum) -> Result<(), Error> {
+        let body = tokio::fs::read(path).await?;
+        let headers = [
+            ("Content-MD5", checksum.md5_base64()),
+            ("x-ms-blob-type", "BlockBlob".to_string()),
+            ("x-ms-meta-crc32c", checksum.crc32c_hex()),
+            ("x-ms-meta-sha256", checksum.sha256_hex()),
+        ];
+
+        self.request(Method::PUT, Some(key), &[], &headers, body.len())?
+            .header("Content-Type", "application/octet-stream")
+            .body(body)
+            .send()
+            .await?
+            .error_for_status()?;
+
+        Ok(())
+    }
+
+    async fn exists(&self, key: &str) -> Result<bool, Error> {
+        let response = self
+            .request(Method::HEAD, Some(key), &[], &[], 0)?
+            .send()
+            .await?;
+
+        match response.status() {
+            StatusCode::NOT_FOUND => Ok(false),
+            _ => response
+                .error_for_status()
+                .map(|_| true)
+                .map_err(Error::from),
+        }
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), Error> {
+        let response = self
+            .request(Method::DELETE, Some(key), &[], &[], 0)?
+            .send()
+            .await?;
+
+        match response.status() {
+            StatusCode::NOT_FOUND => Ok(()),
+            _ => response.error_for_status().map(|_| ()).map_err(Error::from),
+        }
+    }
+
+    async fn list(&self, prefix: &str) -> Result<Vec<String>, Error> {
+        let prefix = self.prefix.join(prefix);
+        let mut keys = Vec::new();
+        let mut marker = String::new();
+
+        loop {
+            let mut query = vec![
+                ("comp", "list"),
+                ("prefix", prefix.as_str()),
+                ("restype", "container"),
+            ];
+            if !marker.is_empty() {
+                query.push(("marker", marker.as_str()));
+            }
+
+            let body = self
+                .request(Method::GET, None, &query, &[], 0)?
+                .send()
+                .await?
+                .error_for_status()?
+                .text()
+                .await?;
+
+            keys.extend(
+                elements(&body, "Name")
+                    .into_iter()
+                    .map(|name| self.prefix.strip(&name).to_string()),
+            );
+
+            marker = elements(&body, "NextMarker")
+                .into_iter()
+                .next()
+                .unwrap_or_default();
+            if marker.is_empty() {
+                break;
+            }
+        }
+
+        Ok(keys)
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, Error> {
+        let response = self
+            .request(Method::GET, Some(key), &[], &[], 0)?
+            .send()
+            .await?;
+
+        match response.status() {
+            StatusCode::NOT_FOUND => Ok(None),
+            _ => Ok(Some(response.error_for_status()?.bytes().await?.to_vec())),
+        }
+    }
+
+    async fn put(&self, key: &str, content: Vec<u8>) -> Result<(), Error> {
+        let headers = [("x-ms-blob-type", "BlockBlob".to_string())];
+
+        self.request(Method::PUT, Some(key), &[], &headers, content.len())?
+            .header("Content-Type", "application/octet-stream")
+            .body(content)
+            .send()
+            .await?
+            .error_for_status()?;
+
+        Ok(())
+    }
+
+    async fn create(&self, key: &str, content: Vec<u8>) -> Result<bool, Error> {
+        let headers = [
+            ("If-None-Match", "*".to_string()),
+            ("x-ms-blob-type", "BlockBlob".to_string()),
+        ];
+
+        let response = self
+            .request(Method::PUT, Some(key), &[], &headers, content.len())?
+            .header("Content-Type", "application/octet-stream")
+            .body(content)
+            .send()
+            .await?;
+
+        match response.status() {
+            StatusCode::CONFLICT | StatusCode::PRECONDITION_FAILED => Ok(false),
+            _ => response
+                .error_for_status()
+                .map(|_| true)
+                .map_err(Error::from),
+        }
+    }
+
+    fn location(&self) -> String {
+        self.prefix.location(&format!(
+            "abfss://{}@{}.dfs.core.windows.net",
+            self.container, self.account
+        ))
+    }
+}
+
+// Extract the text of every element with the given tag. The responses of the
+// List Blobs operation are simple enough that a full XML parser is not needed.
+fn elements(body: &str, tag: &str) -> Vec<String> {
+    let open = format!("<{}>", tag);
+    let close = format!("</{}>", tag);
+    let mut values = Vec::new();
+    let mut rest = body;
+
+    while let Some(start) = rest.find(&open) {
+        rest = &rest[start + open.len()..];
+        match rest.find(&close) {
+            Some(end) => {
+                values.push(unescape(&rest[..end]));
+                rest = &rest[end + close.len()..];
+            }
+            None => break,
+        }
+    }
+
+    values
+}
+
+fn unescape(value: &str) -> String {
+    value
+        .replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&apos;", "'")
+        .replace("&amp;", "&")
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::storage::{Checksum, Expeditor};
+
+    #[test]
+    fn extract_elements() {
+        let body = "<EnumerationResults><Blobs><Blob><Name>a&amp;b.parquet</Name></Blob>\
+            <Blob><Name>c.parquet</Name></Blob></Blobs><NextMarker /></EnumerationResults>";
+
+        assert_eq!(
+            super::elements(body, "Name"),
+            vec!["a&b.parquet".to_string(), "c.parquet".to_string()]
+        );
+        assert!(super::elements(body, "NextMarker").is_empty());
+    }
+
+    // Runs against Azurite with a container named `intake`:
+    //   docker run -p 10000:10000 mcr.microsoft.com/azure-storage/azurite azurite-blob --blobHost 0.0.0.0
+    //   cargo test -- --ignored
+    #[tokio::test]
+    #[ignore]
+    async fn round_trip_against_azurite() {
+        let config = serde_yaml::from_str(
+            "
+            container: intake
+            prefix: tests/
+            connection_string: DefaultEndpointsProtocol=http;AccountName=devstoreaccount1;AccountKey=Eby8vdM02xNOcqFlqUwJPLlmEtlCDXJ1OUzFT50uSRZ6IFsuFq2UVErCz4I6tq/K1SZFPTOtr/KBHBeksoGMGw==;BlobEndpoint=http://127.0.0.1:10000/devstoreaccount1;
+            ",
+        )
+        .unwrap();
+
+        let azure = super::initialize(&config).unwrap();
+        let path = std::env::temp_dir().join("intake-azure-round-trip.parquet");
+        std::fs::write(&path, b"PAR1").unwrap();
+
+        let checksum = Checksum::of(&path).unwrap();
+        azure
+            .upload(&path, "round-trip/file.parquet", &checksum)
+            .await
+            .unwrap();
+        assert!(azure.exists("round-trip/file.parquet").await.unwrap());
+        assert_eq!(
+            azure.list("round-trip/").await.unwrap(),
+            vec!["round-trip/file.parquet".to_string()]
+        );
+
+        azure.delete("round-trip/file.parquet").await.unwrap();
+        assert!(!azure.exists("round-trip/file.parquet").await.unwrap());
+    }
+}
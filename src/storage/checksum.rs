@@ -0,0 +1,121 @@
+use base64::Engine;
+use md5::Md5;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::io::Write;
+use std::path::Path;
+
+// Checksum of a file's content, computed while the file is written so what reaches
+// the storage can be verified against what intake produced.
+//
+// The digests cover what each destination can verify on its own: S3 checks SHA-256
+// and CRC32C, GCS checks CRC32C and MD5 and Azure checks MD5.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Checksum {
+    pub md5: [u8; 16],
+    pub crc32c: u32,
+    pub sha256: [u8; 32],
+}
+
+impl Checksum {
+    // Compute the checksum of the file at path by reading it entirely.
+    pub(crate) fn of(path: &Path) -> Result<Checksum, std::io::Error> {
+        let mut writer = Writer::new(std::io::sink());
+        std::io::copy(&mut std::fs::File::open(path)?, &mut writer)?;
+
+        Ok(writer.checksum())
+    }
+
+    pub(crate) fn sha256_hex(&self) -> String {
+        hex(&self.sha256)
+    }
+
+    pub(crate) fn crc32c_hex(&self) -> String {
+        format!("{:08x}", self.crc32c)
+    }
+
+    pub(crate) fn md5_base64(&self) -> String {
+        base64::engine::general_purpose::STANDARD.encode(self.md5)
+    }
+
+    pub(crate) fn sha256_base64(&self) -> String {
+        base64::engine::general_purpose::STANDARD.encode(self.sha256)
+    }
+
+    // CRC32C encoded the way S3 and GCS expect it: big-endian, then base64.
+    pub(crate) fn crc32c_base64(&self) -> String {
+        base64::engine::general_purpose::STANDARD.encode(self.crc32c.to_be_bytes())
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+// Writer computes the checksum of everything written through it.
+pub(crate) struct Writer<W: Write> {
+    inner: W,
+    md5: Md5,
+    crc32c: u32,
+    sha256: Sha256,
+}
+
+impl<W: Write> Writer<W> {
+    pub(crate) fn new(inner: W) -> Writer<W> {
+        Writer {
+            inner,
+            md5: Md5::new(),
+            crc32c: 0,
+            sha256: Sha256::new(),
+        }
+    }
+
+    pub(crate) fn get_ref(&self) -> &W {
+        &self.inner
+    }
+
+    pub(crate) fn checksum(self) -> Checksum {
+        Checksum {
+            md5: self.md5.finalize().into(),
+            crc32c: self.crc32c,
+            sha256: self.sha256.finalize().into(),
+        }
+    }
+}
+
+impl<W: Write> Write for Writer<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        let buf = &buf[..written];
+
+        self.md5.update(buf);
+        self.crc32c = crc32c::crc32c_append(self.crc32c, buf);
+        self.sha256.update(buf);
+
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    #[test]
+    fn known_digests() {
+        let mut writer = super::Writer::new(Vec::new());
+        writer.write_all(b"123456789").unwrap();
+        assert_eq!(writer.get_ref().as_slice(), b"123456789");
+
+        let checksum = writer.checksum();
+        assert_eq!(checksum.crc32c_hex(), "e3069283");
+        assert_eq!(checksum.md5_base64(), "JfnnlDI7RTiF9RgfG2JNCw==");
+        assert_eq!(
+            checksum.sha256_hex(),
+            "15e2b0d3c33891ebb0f1ef609ec419420c20e320ce94c65fbc8c3312448eb225"
+        );
+    }
+}
@@ -0,0 +1,318 @@
+use crate::config;
+use crate::storage::{Checksum, Error, Expeditor, Prefix};
+use std::fs::File;
+use std::path::{Path, PathBuf};
+
+// Filesystem ships files to a destination directory, usually a mounted volume
+// (NFS, SMB, etc.) read by another system. The output directory then acts as a
+// staging area.
+//
+//   storage:
+//     driver: filesystem
+//     directory: /mnt/lake
+//     prefix: replicated
+//
+// Files are first hard linked, or copied when the destination is on another
+// filesystem, to a hidden temporary file next to their destination. The temporary
+// file is fsynced, verified against the checksum of the file and renamed so readers
+// of the destination only see complete files.
+pub(crate) struct Filesystem {
+    directory: PathBuf,
+    prefix: Prefix,
+}
+
+pub(crate) fn initialize(config: &config::Filesystem) -> Result<Filesystem, Error> {
+    let directory = config.directory.clone();
+    if !directory.is_dir() {
+        return Err(Error::ConfigError(format!(
+            "storage.directory {:?} is not a directory",
+            directory
+        )));
+    }
+
+    Ok(Filesystem::new(
+        directory,
+        Prefix::from(config.prefix.as_str()),
+    ))
+}
+
+impl Filesystem {
+    pub(crate) fn new(directory: PathBuf, prefix: Prefix) -> Filesystem {
+        Filesystem { directory, prefix }
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        self.directory.join(self.prefix.join(key))
+    }
+}
+
+#[async_trait::async_trait]
+impl Expeditor for Filesystem {
+    async fn upload(&self, path: &Path, key: &str, checksum: &Checksum) -> Result<(), Error> {
+        let source = path.to_path_buf();
+        let destination = self.path(key);
+        let checksum = *checksum;
+
+        tokio::task::spawn_blocking(move || transfer(&source, &destination, &checksum))
+            .await
+            .map_err(|e| Error::TransferError(e.to_string()))?
+    }
+
+    async fn exists(&self, key: &str) -> Result<bool, Error> {
+        Ok(tokio::fs::metadata(self.path(key)).await.is_ok())
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), Error> {
+        match tokio::fs::remove_file(self.path(key)).await {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e.into()),
+            _ => Ok(()),
+        }
+    }
+
+    async fn list(&self, prefix: &str) -> Result<Vec<String>, Error> {
+        let root = self.directory.join(self.prefix.join(""));
+        let prefix = prefix.to_string();
+
+        tokio::task::spawn_blocking(move || {
+            // Only walk the deepest directory the prefix points to.
+            let base = prefix.rsplit_once('/').map(|(base, _)| base).unwrap_or("");
+            let mut keys = Vec::new();
+            walk(&root, &root.join(base), &mut keys)?;
+            keys.retain(|key| key.starts_with(&prefix));
+            Ok(keys)
+        })
+        .await
+        .map_err(|e| Error::TransferError(e.to_string()))?
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, Error> {
+        match tokio::fs::read(self.path(key)).await {
+            Ok(content) => Ok(Some(content)),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn put(&self, key: &str, content: Vec<u8>) -> Result<(), Error> {
+        let destination = self.path(key);
+
+        tokio::task::spawn_blocking(move || write(&destination, &content, true))
+            .await
+            .map_err(|e| Error::TransferError(e.to_string()))?
+            .map(|_| ())
+    }
+
+    async fn create(&self, key: &str, content: Vec<u8>) -> Result<bool, Error> {
+        let destination = self.path(key);
+
+        tokio::task::spawn_blocking(move || write(&destination, &content, false))
+            .await
+            .map_err(|e| Error::TransferError(e.to_string()))?
+    }
+
+    fn location(&self) -> String {
+        let directory =
+            std::fs::canonicalize(&self.directory).unwrap_or_else(|_| self.directory.clone());
+        self.prefix.location(&format!(
+            "file://{}",
+            directory.to_string_lossy().trim_end_matches('/')
+        ))
+    }
+}
+
+fn transfer(source: &Path, destination: &Path, checksum: &Checksum) -> Result<(), Error> {
+    let (parent, temporary) = temporary(destination)?;
+
+    let result = std::fs::hard_link(source, &temporary)
+        .or_else(|_| std::fs::copy(source, &temporary).map(|_| ()))
+        .and_then(|_| File::open(&temporary)?.sync_all())
+        .map_err(Error::from)
+        .and_then(|_| verify(&temporary, checksum))
+        .and_then(|_| std::fs::rename(&temporary, destination).map_err(Error::from));
+
+    if let Err(e) = result {
+        let _ = std::fs::remove_file(&temporary);
+        return Err(e);
+    }
+
+    File::open(parent)?.sync_all()?;
+    Ok(())
+}
+
+// Write content to a temporary file next to destination and move it in place. When
+// replace is false, the temporary file is hard linked instead of renamed, which fails
+// if the destination exists. Return whether the destination was written.
+fn write(destination: &Path, content: &[u8], replace: bool) -> Result<bool, Error> {
+    use std::io::Write;
+
+    let (parent, temporary) = temporary(destination)?;
+
+    let result = File::create(&temporary)
+        .and_then(|mut file| {
+            file.write_all(content)?;
+            file.sync_all()
+        })
+        .and_then(|_| {
+            if replace {
+                return std::fs::rename(&temporary, destination).map(|_| true);
+            }
+
+            match std::fs::hard_link(&temporary, destination) {
+                Ok(()) => Ok(true),
+                Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => Ok(false),
+                Err(e) => Err(e),
+            }
+        });
+
+    if !replace || result.is_err() {
+        let _ = std::fs::remove_file(&temporary);
+    }
+
+    let written = result?;
+    File::open(parent)?.sync_all()?;
+    Ok(written)
+}
+
+// Return the parent of destination, created if needed, and a unique temporary path in it.
+fn temporary(destination: &Path) -> Result<(&Path, PathBuf), Error> {
+    let parent = destination
+        .parent()
+        .ok_or_else(|| Error::FileError(format!("invalid destination {:?}", destination)))?;
+    std::fs::create_dir_all(parent)?;
+
+    let name = destination
+        .file_name()
+        .map(|name| name.to_string_lossy())
+        .unwrap_or_default();
+
+    Ok((
+        parent,
+        parent.join(format!(".{}.{}.intake-tmp", name, uuid::Uuid::new_v4())),
+    ))
+}
+
+fn verify(path: &Path, expected: &Checksum) -> Result<(), Error> {
+    let actual = Checksum::of(path)?;
+    if actual != *expected {
+        return Err(Error::IntegrityError(format!(
+            "{:?} has a SHA-256 of {} but {} was expected",
+            path,
+            actual.sha256_hex(),
+            expected.sha256_hex()
+        )));
+    }
+
+    Ok(())
+}
+
+// Collect the keys of all the files under directory, relative to root. Temporary files are skipped.
+fn walk(root: &Path, directory: &Path, keys: &mut Vec<String>) -> Result<(), Error> {
+    let entries = match std::fs::read_dir(directory) {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e.into()),
+    };
+
+    for entry in entries {
+        let entry = entry?;
+        let path = entry.path();
+
+        if entry.file_type()?.is_dir() {
+            walk(root, &path, keys)?;
+        } else if !entry.file_name().to_string_lossy().starts_with('.') {
+            let relative = path.strip_prefix(root).unwrap_or(&path);
+            let key: Vec<String> = relative
+                .components()
+                .map(|c| c.as_os_str().to_string_lossy().to_string())
+                .collect();
+            keys.push(key.join("/"));
+        }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::storage::{Checksum, Expeditor, Prefix};
+
+    #[tokio::test]
+    async fn upload_list_and_delete() {
+        let root = std::env::temp_dir().join(format!("intake-fs-{}", uuid::Uuid::new_v4()));
+        std::fs::create_dir_all(&root).unwrap();
+
+        let filesystem = super::Filesystem {
+            directory: root.clone(),
+            prefix: Prefix::from("replicated"),
+        };
+
+        let staging = root.join("staging.parquet");
+        std::fs::write(&staging, b"PAR1").unwrap();
+        let checksum = Checksum::of(&staging).unwrap();
+
+        filesystem
+            .upload(&staging, "public/users/file.parquet", &checksum)
+            .await
+            .unwrap();
+
+        assert!(staging.exists());
+        assert_eq!(
+            std::fs::read(root.join("replicated/public/users/file.parquet")).unwrap(),
+            b"PAR1"
+        );
+        assert_eq!(
+            filesystem.list("public/").await.unwrap(),
+            vec!["public/users/file.parquet".to_string()]
+        );
+
+        let mut corrupted = checksum;
+        corrupted.sha256[0] ^= 1;
+        assert!(filesystem
+            .upload(&staging, "public/users/corrupted.parquet", &corrupted)
+            .await
+            .is_err());
+        assert!(!filesystem
+            .exists("public/users/corrupted.parquet")
+            .await
+            .unwrap());
+
+        filesystem
+            .delete("public/users/file.parquet")
+            .await
+            .unwrap();
+        assert!(!filesystem
+            .exists("public/users/file.parquet")
+            .await
+            .unwrap());
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn create_only_once() {
+        let root = std::env::temp_dir().join(format!("intake-fs-{}", uuid::Uuid::new_v4()));
+        let filesystem = super::Filesystem::new(root.clone(), Prefix::default());
+
+        assert!(filesystem
+            .create("metadata/v1.json", b"first".to_vec())
+            .await
+            .unwrap());
+        assert!(!filesystem
+            .create("metadata/v1.json", b"second".to_vec())
+            .await
+            .unwrap());
+        assert_eq!(
+            filesystem.get("metadata/v1.json").await.unwrap(),
+            Some(b"first".to_vec())
+        );
+        assert_eq!(filesystem.get("metadata/v2.json").await.unwrap(), None);
+
+        // Temporary files never show up.
+        assert_eq!(
+            filesystem.list("metadata/").await.unwrap(),
+            vec!["metadata/v1.json".to_string()]
+        );
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
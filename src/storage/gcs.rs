@@ -0,0 +1,400 @@
+use crate::config;
+use crate::storage::{Checksum, Error, Expeditor, Prefix};
+use reqwest::{Client, StatusCode, Url};
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use tokio::sync::Mutex;
+
+const DEFAULT_ENDPOINT: &str = "https://storage.googleapis.com";
+const METADATA_TOKEN: &str =
+    "http://metadata.google.internal/computeMetadata/v1/instance/service-accounts/default/token";
+const SCOPE: &str = "https://www.googleapis.com/auth/devstorage.read_write";
+
+// Google Cloud Storage ships files using the JSON API.
+//
+//   storage:
+//     driver: gcs
+//     bucket: intake
+//     prefix: replicated
+//     credentials: /etc/intake/service-account.json  # optional
+//     endpoint: http://localhost:4443                # optional, ie. fake-gcs-server
+//
+// When credentials point to a service account JSON file, access tokens are requested
+// with a signed JWT. Otherwise, they are retrieved from the GCE metadata server, unless
+// a custom endpoint is set, in which case requests are not authenticated (emulators).
+pub(crate) struct Gcs {
+    client: Client,
+    endpoint: String,
+    bucket: String,
+    prefix: Prefix,
+    credentials: Credentials,
+    token: Mutex<Option<(String, Instant)>>,
+}
+
+enum Credentials {
+    ServiceAccount(ServiceAccount),
+    Metadata,
+    Anonymous,
+}
+
+#[derive(Deserialize)]
+struct ServiceAccount {
+    client_email: String,
+    private_key: String,
+    token_uri: String,
+}
+
+#[derive(Serialize)]
+struct Claims<'a> {
+    iss: &'a str,
+    scope: &'a str,
+    aud: &'a str,
+    iat: u64,
+    exp: u64,
+}
+
+#[derive(Deserialize)]
+struct Token {
+    access_token: String,
+    expires_in: u64,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Objects {
+    #[serde(default)]
+    items: Vec<Object>,
+    next_page_token: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct Object {
+    name: String,
+}
+
+pub(crate) fn initialize(config: &config::Gcs) -> Result<Gcs, Error> {
+    let endpoint = config.endpoint.as_deref();
+
+    let credentials = match (config.credentials.as_ref(), endpoint) {
+        (Some(path), _) => {
+            let content = std::fs::read(path).map_err(|e| {
+                Error::ConfigError(format!("could not read storage.credentials: {}", e))
+            })?;
+            let account = serde_json::from_slice(&content).map_err(|e| {
+                Error::ConfigError(format!(
+                    "storage.credentials is not a service account: {}",
+                    e
+                ))
+            })?;
+            Credentials::ServiceAccount(account)
+        }
+        (None, Some(_)) => Credentials::Anonymous,
+        (None, None) => Credentials::Metadata,
+    };
+
+    Ok(Gcs {
+        client: Client::new(),
+        endpoint: endpoint
+            .unwrap_or(DEFAULT_ENDPOINT)
+            .trim_end_matches('/')
+            .to_string(),
+        bucket: config.bucket.clone(),
+        prefix: Prefix::from(config.prefix.as_str()),
+        credentials,
+        token: Mutex::new(None),
+    })
+}
+
+impl Gcs {
+    // Return the URL of the object, or of the bucket's objects if key is None.
+    fn url(&self, base: &str, key: Option<&str>) -> Result<Url, Error> {
+        let mut url = Url::parse(&self.endpoint)
+            .map_err(|e| Error::ConfigError(format!("invalid storage.endpoint: {}", e)))?;
+
+        {
+            let mut segments = url
+                .path_segments_mut()
+                .map_err(|_| Error::ConfigError("invalid storage.endpoint".into()))?;
+            segments.pop_if_empty().extend(base.split('/'));
+            segments.extend(&["b", self.bucket.as_str(), "o"]);
+            if let Some(key) = key {
+                segments.push(&self.prefix.join(key));
+            }
+        }
+
+        Ok(url)
+    }
+
+    async fn authorization(&self) -> Result<Option<String>, Error> {
+        let mut token = self.token.lock().await;
+
+        if let Some((value, expires_at)) = token.as_ref() {
+            if Instant::now() < *expires_at {
+                return Ok(Some(format!("Bearer {}", value)));
+            }
+        }
+
+        let response = match &self.credentials {
+            Credentials::Anonymous => return Ok(None),
+            Credentials::Metadata => {
+                self.client
+                    .get(METADATA_TOKEN)
+                    .header("Metadata-Flavor", "Google")
+                    .send()
+                    .await?
+            }
+            Credentials::ServiceAccount(account) => {
+                let assertion = assertion(account)?;
+                self.client
+                    .post(&account.token_uri)
+                    .form(&[
+                        ("grant_type", "urn:ietf:params:oauth:grant-type:jwt-bearer"),
+                        ("assertion", assertion.as_str()),
+                    ])
+                    .send()
+                    .await?
+            }
+        };
+
+        let fresh: Token = response.error_for_status()?.json().await?;
+
+        // Refresh a minute before the token actually expires.
+        let lifetime = Duration::from_secs(fresh.expires_in.saturating_sub(60));
+        let value = format!("Bearer {}", fresh.access_token);
+        *token = Some((fresh.access_token, Instant::now() + lifetime));
+
+        Ok(Some(value))
+    }
+
+    // Return a simple upload of content to key. When only_new is set, the upload
+    // fails with a precondition error if the object already exists.
+    fn media(
+        &self,
+        key: &str,
+        content: Vec<u8>,
+        only_new: bool,
+    ) -> Result<reqwest::RequestBuilder, Error> {
+        let mut url = self.url("upload/storage/v1", None)?;
+        url.query_pairs_mut()
+            .append_pair("uploadType", "media")
+            .append_pair("name", &self.prefix.join(key));
+        if only_new {
+            url.query_pairs_mut().append_pair("ifGenerationMatch", "0");
+        }
+
+        Ok(self
+            .client
+            .post(url)
+            .header("Content-Type", "application/octet-stream")
+            .body(content))
+    }
+
+    async fn send(&self, request: reqwest::RequestBuilder) -> Result<reqwest::Response, Error> {
+        let request = match self.authorization().await? {
+            Some(authorization) => request.header("Authorization", authorization),
+            None => request,
+        };
+
+        Ok(request.send().await?)
+    }
+}
+
+fn assertion(account: &ServiceAccount) -> Result<String, Error> {
+    use jsonwebtoken::{encode, Algorithm, EncodingKey, Header};
+
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+
+    let claims = Claims {
+        iss: &account.client_email,
+        scope: SCOPE,
+        aud: &account.token_uri,
+        iat: now,
+        exp: now + 3600,
+    };
+
+    let key = EncodingKey::from_rsa_pem(account.private_key.as_bytes())
+        .map_err(|e| Error::ConfigError(format!("invalid service account key: {}", e)))?;
+
+    encode(&Header::new(Algorithm::RS256), &claims, &key)
+        .map_err(|e| Error::ConfigError(format!("could not sign service account JWT: {}", e)))
+}
+
+#[async_trait::async_trait]
+impl Expeditor for Gcs {
+    // Objects are sent with a multipart upload so their metadata, including the
+    // digests GCS verifies before creating the object, is set in the same request.
+    async fn upload(&self, path: &Path, key: &str, checksum: &Checksum) -> Result<(), Error> {
+        let content = tokio::fs::read(path).await?;
+        let mut url = self.url("upload/storage/v1", None)?;
+        url.query_pairs_mut().append_pair("uploadType", "multipart");
+
+        let metadata = serde_json::json!({
+            "name": self.prefix.join(key),
+            "contentType": "application/octet-stream",
+            "crc32c": checksum.crc32c_base64(),
+            "md5Hash": checksum.md5_base64(),
+            "metadata": {
+                "sha256": checksum.sha256_hex(),
+            },
+        });
+
+        let boundary = format!("intake-{}", uuid::Uuid::new_v4().simple());
+        let mut body = format!(
+            "--{0}\r\nContent-Type: application/json; charset=UTF-8\r\n\r\n{1}\r\n\
+            --{0}\r\nContent-Type: application/octet-stream\r\n\r\n",
+            boundary, metadata
+        )
+        .into_bytes();
+        body.extend_from_slice(&content);
+        body.extend_from_slice(format!("\r\n--{}--\r\n", boundary).as_bytes());
+
+        let request = self
+            .client
+            .post(url)
+            .header(
+                "Content-Type",
+                format!("multipart/related; boundary={}", boundary),
+            )
+            .body(body);
+
+        self.send(request).await?.error_for_status()?;
+        Ok(())
+    }
+
+    async fn exists(&self, key: &str) -> Result<bool, Error> {
+        let url = self.url("storage/v1", Some(key))?;
+        let response = self.send(self.client.get(url)).await?;
+
+        match response.status() {
+            StatusCode::NOT_FOUND => Ok(false),
+            _ => response
+                .error_for_status()
+                .map(|_| true)
+                .map_err(Error::from),
+        }
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), Error> {
+        let url = self.url("storage/v1", Some(key))?;
+        let response = self.send(self.client.delete(url)).await?;
+
+        match response.status() {
+            StatusCode::NOT_FOUND => Ok(()),
+            _ => response.error_for_status().map(|_| ()).map_err(Error::from),
+        }
+    }
+
+    async fn list(&self, prefix: &str) -> Result<Vec<String>, Error> {
+        let mut keys = Vec::new();
+        let mut token: Option<String> = None;
+
+        loop {
+            let mut url = self.url("storage/v1", None)?;
+            url.query_pairs_mut()
+                .append_pair("prefix", &self.prefix.join(prefix));
+            if let Some(token) = token.as_ref() {
+                url.query_pairs_mut().append_pair("pageToken", token);
+            }
+
+            let objects: Objects = self
+                .send(self.client.get(url))
+                .await?
+                .error_for_status()?
+                .json()
+                .await?;
+
+            keys.extend(
+                objects
+                    .items
+                    .iter()
+                    .map(|object| self.prefix.strip(&object.name).to_string()),
+            );
+
+            match objects.next_page_token {
+                Some(next) => token = Some(next),
+                None => break,
+            }
+        }
+
+        Ok(keys)
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, Error> {
+        let mut url = self.url("storage/v1", Some(key))?;
+        url.query_pairs_mut().append_pair("alt", "media");
+        let response = self.send(self.client.get(url)).await?;
+
+        match response.status() {
+            StatusCode::NOT_FOUND => Ok(None),
+            _ => Ok(Some(response.error_for_status()?.bytes().await?.to_vec())),
+        }
+    }
+
+    async fn put(&self, key: &str, content: Vec<u8>) -> Result<(), Error> {
+        let request = self.media(key, content, false)?;
+        self.send(request).await?.error_for_status()?;
+        Ok(())
+    }
+
+    async fn create(&self, key: &str, content: Vec<u8>) -> Result<bool, Error> {
+        let request = self.media(key, content, true)?;
+        let response = self.send(request).await?;
+
+        match response.status() {
+            StatusCode::PRECONDITION_FAILED => Ok(false),
+            _ => response
+                .error_for_status()
+                .map(|_| true)
+                .map_err(Error::from),
+        }
+    }
+
+    fn location(&self) -> String {
+        self.prefix.location(&format!("gs://{}", self.bucket))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::storage::{Checksum, Expeditor};
+
+    // Runs against fake-gcs-server with a bucket named `intake`:
+    //   docker run -p 4443:4443 fsouza/fake-gcs-server -scheme http -public-host localhost:4443
+    //   INTAKE_GCS_ENDPOINT=http://localhost:4443 cargo test -- --ignored
+    #[tokio::test]
+    #[ignore]
+    async fn round_trip_against_fake_gcs_server() {
+        let endpoint =
+            std::env::var("INTAKE_GCS_ENDPOINT").unwrap_or("http://localhost:4443".into());
+        let config = serde_yaml::from_str(&format!(
+            "
+            bucket: intake
+            prefix: tests/
+            endpoint: {}
+            ",
+            endpoint
+        ))
+        .unwrap();
+
+        let gcs = super::initialize(&config).unwrap();
+        let path = std::env::temp_dir().join("intake-gcs-round-trip.parquet");
+        std::fs::write(&path, b"PAR1").unwrap();
+
+        let checksum = Checksum::of(&path).unwrap();
+        gcs.upload(&path, "round-trip/file.parquet", &checksum)
+            .await
+            .unwrap();
+        assert!(gcs.exists("round-trip/file.parquet").await.unwrap());
+        assert_eq!(
+            gcs.list("round-trip/").await.unwrap(),
+            vec!["round-trip/file.parquet".to_string()]
+        );
+
+        gcs.delete("round-trip/file.parquet").await.unwrap();
+        assert!(!gcs.exists("round-trip/file.parquet").await.unwrap());
+    }
+}
@@ -3,4 +3,160 @@
 //
 // Its main focus is downloading and uploading files as requested by other sub-system.
 
-pub(crate) trait Expeditor {}
+use crate::config;
+use checksum::Checksum;
+use std::path::Path;
+use std::sync::Arc;
+use thiserror::Error;
+
+mod azure;
+pub(crate) mod checksum;
+mod filesystem;
+mod gcs;
+pub(crate) mod queue;
+mod s3;
+
+#[derive(Error, Debug)]
+pub(crate) enum Error {
+    #[error("configuration error: `{0}`")]
+    ConfigError(String),
+
+    #[error("transfer error: `{0}`")]
+    TransferError(String),
+
+    #[error("file error: `{0}`")]
+    FileError(String),
+
+    #[error("integrity error: `{0}`")]
+    IntegrityError(String),
+}
+
+impl From<std::io::Error> for Error {
+    fn from(e: std::io::Error) -> Self {
+        Error::FileError(e.to_string())
+    }
+}
+
+impl From<reqwest::Error> for Error {
+    fn from(e: reqwest::Error) -> Self {
+        Error::TransferError(e.to_string())
+    }
+}
+
+// Expeditor ships files to a remote destination. Keys are always relative to
+// the prefix configured for the destination, using `/` as a separator.
+#[async_trait::async_trait]
+pub(crate) trait Expeditor: Send + Sync {
+    // Upload the local file at path to key, replacing any object at that key. The
+    // destination verifies the content against the checksum and keeps it with the object.
+    async fn upload(&self, path: &Path, key: &str, checksum: &Checksum) -> Result<(), Error>;
+
+    async fn exists(&self, key: &str) -> Result<bool, Error>;
+
+    // Delete the object at key. Deleting a key that doesn't exist is not an error.
+    async fn delete(&self, key: &str) -> Result<(), Error>;
+
+    // List all the keys that start with prefix.
+    async fn list(&self, prefix: &str) -> Result<Vec<String>, Error>;
+
+    // Return the content of the object at key, or None if it doesn't exist.
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, Error>;
+
+    // Write content to key, replacing any object at that key.
+    async fn put(&self, key: &str, content: Vec<u8>) -> Result<(), Error>;
+
+    // Write content to key only if no object exists at that key. Return false when
+    // the object already exists. The check and the write are atomic.
+    async fn create(&self, key: &str, content: Vec<u8>) -> Result<bool, Error>;
+
+    // URI of the destination's root, ie. `s3://bucket/prefix`. Joined with a key,
+    // it's the location other systems use to read the object.
+    fn location(&self) -> String;
+
+    // Abort the incomplete uploads left behind by a previous process, except the ones
+    // for the pending keys which will be resumed. Return the number of uploads aborted.
+    async fn abort_incomplete(&self, _pending: &[String]) -> Result<usize, Error> {
+        Ok(0)
+    }
+}
+
+// Return the Expeditor configured in the `storage` block of the config. When
+// no storage is configured, files stay on local disk and None is returned.
+pub(crate) async fn initialize(
+    config: Option<&config::Storage>,
+) -> Result<Option<Arc<dyn Expeditor>>, Error> {
+    match config {
+        Some(config::Storage::S3(config)) => Ok(Some(Arc::new(s3::initialize(config).await?))),
+        Some(config::Storage::Filesystem(config)) => {
+            Ok(Some(Arc::new(filesystem::initialize(config)?)))
+        }
+        Some(config::Storage::Gcs(config)) => Ok(Some(Arc::new(gcs::initialize(config)?))),
+        Some(config::Storage::Azure(config)) => Ok(Some(Arc::new(azure::initialize(config)?))),
+        None => Ok(None),
+    }
+}
+
+// Return an Expeditor for a local directory. Used by the systems that need to
+// publish objects when no storage is configured.
+pub(crate) fn local(directory: &Path) -> Arc<dyn Expeditor> {
+    Arc::new(filesystem::Filesystem::new(
+        directory.to_path_buf(),
+        Prefix::default(),
+    ))
+}
+
+// Prefix is prepended to every key an Expeditor handles.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Prefix(String);
+
+impl From<&str> for Prefix {
+    fn from(prefix: &str) -> Self {
+        Prefix(prefix.trim_matches('/').to_string())
+    }
+}
+
+impl Prefix {
+    pub(crate) fn join(&self, key: &str) -> String {
+        if self.0.is_empty() {
+            return key.to_string();
+        }
+
+        format!("{}/{}", self.0, key.trim_start_matches('/'))
+    }
+
+    // Return the location of the root under base, ie. `s3://bucket/prefix`.
+    pub(crate) fn location(&self, base: &str) -> String {
+        if self.0.is_empty() {
+            return base.to_string();
+        }
+
+        format!("{}/{}", base, self.0)
+    }
+
+    // Remove the prefix from a full key. Keys that aren't under the prefix's directory
+    // are returned as is.
+    pub(crate) fn strip<'a>(&self, key: &'a str) -> &'a str {
+        if self.0.is_empty() {
+            return key;
+        }
+
+        key.strip_prefix(self.0.as_str())
+            .and_then(|key| key.strip_prefix('/'))
+            .unwrap_or(key)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Prefix;
+
+    #[test]
+    fn strip_whole_directories() {
+        let prefix = Prefix::from("foo/");
+
+        assert_eq!(prefix.strip("foo/bar/x"), "bar/x");
+        assert_eq!(prefix.strip("foobar/x"), "foobar/x");
+        assert_eq!(prefix.strip("other/x"), "other/x");
+        assert_eq!(Prefix::default().strip("foo/x"), "foo/x");
+    }
+}
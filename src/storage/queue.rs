@@ -0,0 +1,428 @@
+use crate::config;
+use crate::events::output;
+use crate::health;
+use crate::metrics;
+use crate::storage::checksum::Checksum;
+use crate::storage::{Error, Expeditor};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{mpsc, Mutex, Notify};
+use uuid::Uuid;
+
+// Queue uploads files with an Expeditor in the background. Each job is persisted
+// on disk before it is handed to a worker and only removed once the Expeditor
+// confirmed the upload, so pending uploads survive a restart.
+//
+// Failed uploads are retried forever with an exponential backoff. Local files are
+// kept until their upload succeeded which means they accumulate when the destination
+// is unavailable: once the files waiting to be uploaded reach `max_bytes`, enqueuing
+// blocks until some uploads complete, which in turn slows down ingestion.
+//
+// A job fails when its local file is gone before it was ever uploaded. The job is then
+// kept as `{id}.failed` in the directory, and waiting for its key returns an error.
+//
+//   storage:
+//     queue:
+//       directory: .intake-queue
+//       workers: 4
+//       max_bytes: 10737418240
+//       backoff: 1        # seconds before the first retry
+//       max_backoff: 300  # seconds between retries, at most
+pub(crate) struct Queue {
+    directory: PathBuf,
+    expeditor: Arc<dyn Expeditor>,
+    sender: mpsc::UnboundedSender<Job>,
+    max_bytes: u64,
+    backoff: Duration,
+    max_backoff: Duration,
+
+    pending: std::sync::Mutex<Pending>,
+    released: Notify,
+
+    // Uploads of the same key are serialized so a file that is uploaded multiple
+    // times, like a manifest, always ends up with its latest content.
+    locks: Mutex<HashMap<String, Arc<Mutex<()>>>>,
+}
+
+#[derive(Default)]
+struct Pending {
+    bytes: u64,
+    jobs: usize,
+
+    // Number of jobs waiting for each key.
+    keys: HashMap<String, usize>,
+
+    // Keys whose upload failed, with the reason.
+    failed: HashMap<String, String>,
+}
+
+impl Pending {
+    fn add(&mut self, key: &str, bytes: u64) {
+        self.bytes += bytes;
+        self.jobs += 1;
+        *self.keys.entry(key.to_string()).or_default() += 1;
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+struct Job {
+    id: Uuid,
+    path: PathBuf,
+    key: String,
+    bytes: u64,
+
+    // Checksum of the file when it was written. When missing, it's computed
+    // from the local file right before the upload.
+    #[serde(default)]
+    checksum: Option<Checksum>,
+
+    // Whether the local file is removed once uploaded.
+    remove: bool,
+}
+
+// Start the queue's workers and resume the jobs persisted by a previous process.
+pub(crate) async fn start(
+    config: &config::Queue,
+    expeditor: Arc<dyn Expeditor>,
+) -> Result<Arc<Queue>, Error> {
+    let directory = config.directory.clone();
+    tokio::fs::create_dir_all(&directory).await?;
+
+    let (sender, receiver) = mpsc::unbounded_channel();
+    let queue = Arc::new(Queue {
+        directory,
+        expeditor,
+        sender,
+        max_bytes: config.max_bytes,
+        backoff: Duration::from_secs(config.backoff),
+        max_backoff: Duration::from_secs(config.max_backoff),
+        pending: std::sync::Mutex::new(Pending::default()),
+        released: Notify::new(),
+        locks: Mutex::new(HashMap::new()),
+    });
+
+    let receiver = Arc::new(Mutex::new(receiver));
+    for _ in 0..config.workers {
+        tokio::spawn(work(queue.clone(), receiver.clone()));
+    }
+
+    let (jobs, failed) = recover(&queue.directory)?;
+    if !jobs.is_empty() {
+        log::info!("Resuming {} pending uploads.", jobs.len());
+    }
+    for job in failed {
+        log::warn!(
+            "Upload of {:?} to {} failed in a previous run.",
+            job.path,
+            job.key
+        );
+        queue
+            .pending
+            .lock()
+            .unwrap()
+            .failed
+            .insert(job.key.clone(), missing(&job));
+    }
+
+    // Nothing was handed to the workers yet so every other incomplete upload is orphaned.
+    let pending: Vec<String> = jobs.iter().map(|job| job.key.clone()).collect();
+    match queue.expeditor.abort_incomplete(&pending).await {
+        Ok(0) => {}
+        Ok(aborted) => log::info!("Aborted {} orphaned uploads.", aborted),
+        Err(e) => log::warn!("Could not abort orphaned uploads: {}", e),
+    }
+
+    for job in jobs {
+        queue.pending.lock().unwrap().add(&job.key, job.bytes);
+        queue
+            .sender
+            .send(job)
+            .map_err(|e| Error::TransferError(e.to_string()))?;
+    }
+
+    Ok(queue)
+}
+
+impl Queue {
+    pub(crate) fn expeditor(&self) -> Arc<dyn Expeditor> {
+        self.expeditor.clone()
+    }
+
+    // Persist an upload of the file at path to key and hand it over to the workers.
+    // This waits for uploads to complete if the local disk usage is above the limit.
+    pub(crate) async fn enqueue(
+        &self,
+        path: PathBuf,
+        key: String,
+        checksum: Option<Checksum>,
+        remove: bool,
+    ) -> Result<(), Error> {
+        let bytes = tokio::fs::metadata(&path).await?.len();
+        self.reserve(&key, bytes).await;
+
+        let job = Job {
+            id: Uuid::new_v4(),
+            path,
+            key,
+            bytes,
+            checksum,
+            remove,
+        };
+
+        if let Err(e) = self.persist(&job).await {
+            self.release(&job.key, bytes);
+            return Err(e);
+        }
+
+        self.sender
+            .send(job)
+            .map_err(|e| Error::TransferError(e.to_string()))
+    }
+
+    // Wait until the local disk usage allows bytes to be added. A job is always
+    // accepted when nothing is pending, regardless of its size.
+    async fn reserve(&self, key: &str, bytes: u64) {
+        loop {
+            let released = self.released.notified();
+
+            {
+                let mut pending = self.pending.lock().unwrap();
+                if pending.jobs == 0 || pending.bytes + bytes <= self.max_bytes {
+                    pending.add(key, bytes);
+                    return;
+                }
+            }
+
+            log::info!("Local files waiting to be uploaded exceed the limit, waiting for uploads.");
+            released.await;
+        }
+    }
+
+    // Wait until every upload enqueued for key is completed. Return an error when
+    // the key can't be uploaded.
+    pub(crate) async fn uploaded(&self, key: &str) -> Result<(), Error> {
+        loop {
+            let released = self.released.notified();
+
+            {
+                let pending = self.pending.lock().unwrap();
+                if !pending.keys.contains_key(key) {
+                    return match pending.failed.get(key) {
+                        Some(reason) => Err(Error::FileError(reason.clone())),
+                        None => Ok(()),
+                    };
+                }
+            }
+
+            released.await;
+        }
+    }
+
+    // Wait until every upload enqueued so far is completed.
+    pub(crate) async fn drained(&self) {
+        loop {
+            let released = self.released.notified();
+
+            if self.pending.lock().unwrap().jobs == 0 {
+                return;
+            }
+
+            released.await;
+        }
+    }
+
+    fn release(&self, key: &str, bytes: u64) {
+        {
+            let mut pending = self.pending.lock().unwrap();
+            pending.bytes = pending.bytes.saturating_sub(bytes);
+            pending.jobs = pending.jobs.saturating_sub(1);
+
+            if let Some(count) = pending.keys.get_mut(key) {
+                *count -= 1;
+                if *count == 0 {
+                    pending.keys.remove(key);
+                }
+            }
+        }
+
+        self.released.notify_waiters();
+    }
+
+    fn job_path(&self, job: &Job) -> PathBuf {
+        self.directory.join(format!("{}.json", job.id))
+    }
+
+    fn failed_path(&self, job: &Job) -> PathBuf {
+        self.directory.join(format!("{}.failed", job.id))
+    }
+
+    async fn persist(&self, job: &Job) -> Result<(), Error> {
+        let path = self.job_path(job);
+        let content = serde_json::to_vec(job).map_err(|e| Error::FileError(e.to_string()))?;
+
+        tokio::task::spawn_blocking(move || output::write(&path, &content))
+            .await
+            .map_err(|e| Error::FileError(e.to_string()))??;
+
+        Ok(())
+    }
+
+    async fn lock(&self, key: &str) -> Arc<Mutex<()>> {
+        self.locks
+            .lock()
+            .await
+            .entry(key.to_string())
+            .or_default()
+            .clone()
+    }
+
+    async fn process(&self, job: Job) {
+        let lock = self.lock(&job.key).await;
+        let guard = lock.lock().await;
+
+        let mut delay = self.backoff;
+        let mut attempt = 1;
+        let mut failed = false;
+
+        loop {
+            // The file can be missing if a previous process uploaded and removed it
+            // but crashed before removing the job.
+            if !job.path.exists() {
+                match self.expeditor.exists(&job.key).await {
+                    Ok(true) => {}
+                    Ok(false) => {
+                        log::warn!("Upload failed: {}.", missing(&job));
+                        failed = true;
+                    }
+                    Err(e) => {
+                        log::warn!("Could not verify {}: {}", job.key, e);
+                        tokio::time::sleep(delay).await;
+                        delay = (delay * 2).min(self.max_backoff);
+                        continue;
+                    }
+                }
+                break;
+            }
+
+            let checksum = match job.checksum {
+                Some(checksum) => Ok(checksum),
+                None => {
+                    let path = job.path.clone();
+                    tokio::task::spawn_blocking(move || Checksum::of(&path))
+                        .await
+                        .map_err(|e| Error::FileError(e.to_string()))
+                        .and_then(|checksum| checksum.map_err(Error::from))
+                }
+            };
+
+            let result = match checksum {
+                Ok(checksum) => self.expeditor.upload(&job.path, &job.key, &checksum).await,
+                Err(e) => Err(e),
+            };
+
+            match result {
+                Ok(()) => {
+                    metrics::UPLOADS.add(&[("result", "success")], 1.0);
+                    health::REGISTRY.up(health::UPLOADS);
+                    self.pending.lock().unwrap().failed.remove(&job.key);
+                    if job.remove {
+                        if let Err(e) = tokio::fs::remove_file(&job.path).await {
+                            log::warn!("Could not remove {:?} after upload: {}", job.path, e);
+                        }
+                    }
+                    break;
+                }
+                Err(e) => {
+                    metrics::UPLOADS.add(&[("result", "failure")], 1.0);
+                    health::REGISTRY.down(health::UPLOADS, &format!("upload failed: {}", e));
+                    log::warn!(
+                        "Upload of {} failed (attempt {}): {}. Retrying in {:?}.",
+                        job.key,
+                        attempt,
+                        e,
+                        delay
+                    );
+                    tokio::time::sleep(delay).await;
+                    delay = (delay * 2).min(self.max_backoff);
+                    attempt += 1;
+                }
+            }
+        }
+
+        let result = if failed {
+            self.pending
+                .lock()
+                .unwrap()
+                .failed
+                .insert(job.key.clone(), missing(&job));
+            tokio::fs::rename(self.job_path(&job), self.failed_path(&job)).await
+        } else {
+            tokio::fs::remove_file(self.job_path(&job)).await
+        };
+        if let Err(e) = result {
+            log::warn!("Could not remove the upload job {}: {}", job.id, e);
+        }
+
+        drop(guard);
+        drop(lock);
+        self.locks
+            .lock()
+            .await
+            .retain(|_, lock| Arc::strong_count(lock) > 1);
+
+        self.release(&job.key, job.bytes);
+    }
+}
+
+async fn work(queue: Arc<Queue>, receiver: Arc<Mutex<mpsc::UnboundedReceiver<Job>>>) {
+    loop {
+        let job = receiver.lock().await.recv().await;
+        match job {
+            Some(job) => queue.process(job).await,
+            None => return,
+        }
+    }
+}
+
+fn missing(job: &Job) -> String {
+    format!(
+        "{:?} is missing and was never uploaded to {}",
+        job.path, job.key
+    )
+}
+
+// Return the pending and the failed jobs persisted in the directory. Partially written
+// jobs are discarded as the file they refer to was never considered enqueued.
+fn recover(directory: &Path) -> Result<(Vec<Job>, Vec<Job>), Error> {
+    let mut jobs = Vec::new();
+    let mut failed = Vec::new();
+
+    for entry in std::fs::read_dir(directory)? {
+        let path = entry?.path();
+        let name = path
+            .file_name()
+            .map(|name| name.to_string_lossy().to_string())
+            .unwrap_or_default();
+
+        if name.starts_with('.') {
+            std::fs::remove_file(&path)?;
+            continue;
+        }
+
+        let jobs = match path.extension().and_then(|ext| ext.to_str()) {
+            Some("json") => &mut jobs,
+            Some("failed") => &mut failed,
+            _ => continue,
+        };
+
+        match serde_json::from_slice(&std::fs::read(&path)?) {
+            Ok(job) => jobs.push(job),
+            Err(e) => log::warn!("Ignoring invalid upload job {:?}: {}", path, e),
+        }
+    }
+
+    Ok((jobs, failed))
+}
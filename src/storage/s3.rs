@@ -0,0 +1,641 @@
+use crate::config;
+use crate::storage::{Checksum, Error, Expeditor, Prefix};
+use aws_sdk_s3::error::{DisplayErrorContext, SdkError};
+use aws_sdk_s3::Client;
+use std::collections::HashMap;
+use std::path::Path;
+
+const MAX_PARTS: u64 = 10_000;
+
+// Multipart uploads started by intake are recorded under this key of the prefix, as
+// `{UPLOADS}/{upload id}` holding the key of the upload. Only recorded uploads are
+// ever resumed or aborted, the others in the bucket belong to other tools. The id is
+// only known once the upload is started, so an upload is recorded right after. One
+// started by an instance that crashed before recording it is left to the bucket's
+// lifecycle rule for incomplete multipart uploads.
+const UPLOADS: &str = "_intake/uploads";
+
+// S3 ships files to any S3-compatible object storage (AWS, MinIO, Ceph, R2, etc.)
+//
+//   storage:
+//     driver: s3
+//     bucket: intake
+//     prefix: replicated
+//     region: us-east-1
+//     endpoint: http://localhost:9000   # optional, for anything but AWS
+//     path_style: true                  # optional, required by most S3-compatible stores
+//     credentials:                      # optional, defaults to the AWS credentials chain
+//       access_key_id: minioadmin
+//       secret_access_key: minioadmin
+//     part_size: 16777216               # optional, in bytes. At least 5MiB
+//     concurrency: 4                    # optional, parts uploaded in parallel
+//
+// Files larger than part_size are sent with a multipart upload, reading each part
+// straight from disk. A multipart upload that fails midway is left in place and
+// resumed on the next attempt, even after a restart, by only sending the parts the
+// bucket doesn't have yet. This relies on keys never being reused for different
+// content, which holds for segments as their names are unique. Uploads that no
+// pending file will resume are aborted on start, as long as intake started them.
+pub(crate) struct S3 {
+    client: Client,
+    bucket: String,
+    prefix: Prefix,
+    part_size: u64,
+    concurrency: usize,
+}
+
+pub(crate) async fn initialize(config: &config::S3) -> Result<S3, Error> {
+    use aws_config::BehaviorVersion;
+    use aws_sdk_s3::config::{Builder, Credentials, Region};
+
+    let mut loader = aws_config::defaults(BehaviorVersion::latest());
+    if let Some(region) = config.region.as_ref() {
+        loader = loader.region(Region::new(region.clone()));
+    }
+
+    let mut builder = Builder::from(&loader.load().await);
+
+    if let Some(endpoint) = config.endpoint.as_ref() {
+        builder = builder.endpoint_url(endpoint);
+    }
+
+    if let Some(path_style) = config.path_style {
+        builder = builder.force_path_style(path_style);
+    }
+
+    if let Some(credentials) = config.credentials.as_ref() {
+        builder = builder.credentials_provider(Credentials::new(
+            &credentials.access_key_id,
+            &credentials.secret_access_key,
+            credentials.session_token.clone(),
+            None,
+            "intake",
+        ));
+    }
+
+    Ok(S3 {
+        client: Client::from_conf(builder.build()),
+        bucket: config.bucket.clone(),
+        prefix: Prefix::from(config.prefix.as_str()),
+        part_size: config.part_size,
+        concurrency: config.concurrency,
+    })
+}
+
+// Return the size of each part and the number of parts needed to upload size bytes.
+// The part size grows when needed to stay within the maximum number of parts S3 allows.
+fn layout(size: u64, part_size: u64) -> (u64, u64) {
+    let part_size = part_size.max((size + MAX_PARTS - 1) / MAX_PARTS);
+    (part_size, (size + part_size - 1) / part_size)
+}
+
+// Return the length of the part with the given number, starting at 1.
+fn part_length(number: u64, size: u64, part_size: u64, count: u64) -> u64 {
+    if number == count {
+        size - part_size * (count - 1)
+    } else {
+        part_size
+    }
+}
+
+// Part of a multipart upload the bucket acknowledged.
+struct Part {
+    length: u64,
+    etag: String,
+    crc32c: Option<String>,
+}
+
+impl S3 {
+    async fn put(&self, path: &Path, key: &str, checksum: &Checksum) -> Result<(), Error> {
+        use aws_sdk_s3::primitives::ByteStream;
+
+        let body = ByteStream::from_path(path)
+            .await
+            .map_err(|e| Error::FileError(e.to_string()))?;
+
+        self.client
+            .put_object()
+            .bucket(&self.bucket)
+            .key(key)
+            .content_md5(checksum.md5_base64())
+            .checksum_sha256(checksum.sha256_base64())
+            .metadata("sha256", checksum.sha256_hex())
+            .metadata("crc32c", checksum.crc32c_hex())
+            .body(body)
+            .send()
+            .await?;
+
+        Ok(())
+    }
+
+    // Multipart uploads are verified with a full object CRC32C: each part is checked
+    // by the bucket as it's received and the combination of all the parts is checked
+    // against the checksum of the file when the upload is completed.
+    async fn multipart(
+        &self,
+        path: &Path,
+        key: &str,
+        size: u64,
+        checksum: &Checksum,
+    ) -> Result<(), Error> {
+        use aws_sdk_s3::types::{
+            ChecksumAlgorithm, ChecksumType, CompletedMultipartUpload, CompletedPart,
+        };
+        use futures::{StreamExt, TryStreamExt};
+
+        let (part_size, count) = layout(size, self.part_size);
+
+        let (upload_id, mut uploaded) = match self.incomplete(key).await? {
+            Some(upload_id) => {
+                let parts = self.parts(key, &upload_id).await?;
+                (upload_id, parts)
+            }
+            None => {
+                let output = self
+                    .client
+                    .create_multipart_upload()
+                    .bucket(&self.bucket)
+                    .key(key)
+                    .checksum_algorithm(ChecksumAlgorithm::Crc32C)
+                    .checksum_type(ChecksumType::FullObject)
+                    .metadata("sha256", checksum.sha256_hex())
+                    .metadata("crc32c", checksum.crc32c_hex())
+                    .send()
+                    .await?;
+                let upload_id = output.upload_id().ok_or_else(|| {
+                    Error::TransferError(format!("no upload id returned for {}", key))
+                })?;
+                if let Err(e) =
+                    Expeditor::put(self, &marker(upload_id), key.as_bytes().to_vec()).await
+                {
+                    // Unrecorded, the upload would never be resumed nor aborted.
+                    let _ = self.abort(key, upload_id).await;
+                    return Err(e);
+                }
+                (upload_id.to_string(), HashMap::new())
+            }
+        };
+
+        // Parts that don't match the current layout are sent again.
+        uploaded.retain(|number, part| {
+            *number <= count && part.length == part_length(*number, size, part_size, count)
+        });
+        if !uploaded.is_empty() {
+            log::info!(
+                "Resuming upload of {}: {} of {} parts already uploaded.",
+                key,
+                uploaded.len(),
+                count
+            );
+        }
+
+        let missing: Vec<u64> = (1..=count)
+            .filter(|number| !uploaded.contains_key(number))
+            .collect();
+
+        let sent: Vec<(u64, Part)> = futures::stream::iter(missing)
+            .map(|number| {
+                let offset = part_size * (number - 1);
+                let length = part_length(number, size, part_size, count);
+                self.part(path, key, &upload_id, number, offset, length)
+            })
+            .buffer_unordered(self.concurrency)
+            .try_collect()
+            .await?;
+
+        let mut parts: Vec<(u64, Part)> = uploaded.into_iter().chain(sent).collect();
+        parts.sort_by_key(|(number, _)| *number);
+
+        let parts = parts
+            .into_iter()
+            .map(|(number, part)| {
+                CompletedPart::builder()
+                    .part_number(number as i32)
+                    .e_tag(part.etag)
+                    .set_checksum_crc32_c(part.crc32c)
+                    .build()
+            })
+            .collect();
+
+        self.client
+            .complete_multipart_upload()
+            .bucket(&self.bucket)
+            .key(key)
+            .upload_id(&upload_id)
+            .checksum_type(ChecksumType::FullObject)
+            .checksum_crc32_c(checksum.crc32c_base64())
+            .multipart_upload(
+                CompletedMultipartUpload::builder()
+                    .set_parts(Some(parts))
+                    .build(),
+            )
+            .send()
+            .await?;
+
+        // A marker left behind is removed with the orphaned uploads.
+        if let Err(e) = Expeditor::delete(self, &marker(&upload_id)).await {
+            log::warn!("Could not remove the record of upload {}: {}", upload_id, e);
+        }
+
+        Ok(())
+    }
+
+    async fn part(
+        &self,
+        path: &Path,
+        key: &str,
+        upload_id: &str,
+        number: u64,
+        offset: u64,
+        length: u64,
+    ) -> Result<(u64, Part), Error> {
+        use aws_sdk_s3::primitives::{ByteStream, Length};
+        use aws_sdk_s3::types::ChecksumAlgorithm;
+
+        let body = ByteStream::read_from()
+            .path(path)
+            .offset(offset)
+            .length(Length::Exact(length))
+            .build()
+            .await
+            .map_err(|e| Error::FileError(e.to_string()))?;
+
+        // The SDK computes the CRC32C of the part as it's sent.
+        let output = self
+            .client
+            .upload_part()
+            .bucket(&self.bucket)
+            .key(key)
+            .upload_id(upload_id)
+            .part_number(number as i32)
+            .checksum_algorithm(ChecksumAlgorithm::Crc32C)
+            .body(body)
+            .send()
+            .await?;
+
+        let etag = output.e_tag().ok_or_else(|| {
+            Error::TransferError(format!("no ETag returned for part {} of {}", number, key))
+        })?;
+
+        Ok((
+            number,
+            Part {
+                length,
+                etag: etag.to_string(),
+                crc32c: output.checksum_crc32_c().map(String::from),
+            },
+        ))
+    }
+
+    // Return every incomplete multipart upload under prefix, as (key, upload id).
+    async fn uploads(&self, prefix: &str) -> Result<Vec<(String, String)>, Error> {
+        let mut uploads = Vec::new();
+        let mut key_marker = None;
+        let mut upload_id_marker = None;
+
+        loop {
+            let output = self
+                .client
+                .list_multipart_uploads()
+                .bucket(&self.bucket)
+                .prefix(prefix)
+                .set_key_marker(key_marker)
+                .set_upload_id_marker(upload_id_marker)
+                .send()
+                .await?;
+
+            for upload in output.uploads() {
+                if let (Some(key), Some(id)) = (upload.key(), upload.upload_id()) {
+                    uploads.push((key.to_string(), id.to_string()));
+                }
+            }
+
+            if output.is_truncated() != Some(true) {
+                break;
+            }
+
+            key_marker = output.next_key_marker().map(String::from);
+            upload_id_marker = output.next_upload_id_marker().map(String::from);
+        }
+
+        Ok(uploads)
+    }
+
+    // Return the id of an incomplete multipart upload intake started for key, if any.
+    // Extra uploads it started for the same key are aborted so only one of them is
+    // ever resumed. Uploads it didn't record are left alone.
+    async fn incomplete(&self, key: &str) -> Result<Option<String>, Error> {
+        let mut recorded = Vec::new();
+        for (upload, id) in self.uploads(key).await? {
+            if upload == key && Expeditor::exists(self, &marker(&id)).await? {
+                recorded.push(id);
+            }
+        }
+
+        let mut ids = recorded.into_iter();
+        let resumed = ids.next();
+        for id in ids {
+            self.abort(key, &id).await?;
+        }
+
+        Ok(resumed)
+    }
+
+    // Return the parts already uploaded, indexed by part number.
+    async fn parts(&self, key: &str, upload_id: &str) -> Result<HashMap<u64, Part>, Error> {
+        let mut parts = HashMap::new();
+        let mut marker = None;
+
+        loop {
+            let output = self
+                .client
+                .list_parts()
+                .bucket(&self.bucket)
+                .key(key)
+                .upload_id(upload_id)
+                .set_part_number_marker(marker)
+                .send()
+                .await?;
+
+            for part in output.parts() {
+                if let (Some(number), Some(length), Some(etag)) =
+                    (part.part_number(), part.size(), part.e_tag())
+                {
+                    parts.insert(
+                        number as u64,
+                        Part {
+                            length: length as u64,
+                            etag: etag.to_string(),
+                            crc32c: part.checksum_crc32_c().map(String::from),
+                        },
+                    );
+                }
+            }
+
+            if output.is_truncated() != Some(true) {
+                break;
+            }
+
+            marker = output.next_part_number_marker().map(String::from);
+        }
+
+        Ok(parts)
+    }
+
+    async fn abort(&self, key: &str, upload_id: &str) -> Result<(), Error> {
+        self.client
+            .abort_multipart_upload()
+            .bucket(&self.bucket)
+            .key(key)
+            .upload_id(upload_id)
+            .send()
+            .await?;
+
+        Expeditor::delete(self, &marker(upload_id)).await
+    }
+}
+
+// Key of the record of a multipart upload started by intake, relative to the prefix.
+fn marker(upload_id: &str) -> String {
+    format!("{}/{}", UPLOADS, upload_id)
+}
+
+impl<E, R> From<SdkError<E, R>> for Error
+where
+    E: std::error::Error + 'static,
+    R: std::fmt::Debug,
+{
+    fn from(e: SdkError<E, R>) -> Self {
+        Error::TransferError(DisplayErrorContext(&e).to_string())
+    }
+}
+
+#[async_trait::async_trait]
+impl Expeditor for S3 {
+    async fn upload(&self, path: &Path, key: &str, checksum: &Checksum) -> Result<(), Error> {
+        let size = tokio::fs::metadata(path).await?.len();
+        let key = self.prefix.join(key);
+
+        if size <= self.part_size {
+            self.put(path, &key, checksum).await
+        } else {
+            self.multipart(path, &key, size, checksum).await
+        }
+    }
+
+    async fn get(&self, key: &str) -> Result<Option<Vec<u8>>, Error> {
+        let result = self
+            .client
+            .get_object()
+            .bucket(&self.bucket)
+            .key(self.prefix.join(key))
+            .send()
+            .await;
+
+        match result {
+            Ok(output) => {
+                let content = output
+                    .body
+                    .collect()
+                    .await
+                    .map_err(|e| Error::TransferError(e.to_string()))?;
+                Ok(Some(content.into_bytes().to_vec()))
+            }
+            Err(SdkError::ServiceError(e)) if e.err().is_no_such_key() => Ok(None),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn put(&self, key: &str, content: Vec<u8>) -> Result<(), Error> {
+        use aws_sdk_s3::primitives::ByteStream;
+
+        self.client
+            .put_object()
+            .bucket(&self.bucket)
+            .key(self.prefix.join(key))
+            .body(ByteStream::from(content))
+            .send()
+            .await?;
+
+        Ok(())
+    }
+
+    // Relies on conditional writes. A conflict with another conditional write
+    // in progress for the same key is reported as if the object existed.
+    async fn create(&self, key: &str, content: Vec<u8>) -> Result<bool, Error> {
+        use aws_sdk_s3::primitives::ByteStream;
+
+        let result = self
+            .client
+            .put_object()
+            .bucket(&self.bucket)
+            .key(self.prefix.join(key))
+            .if_none_match("*")
+            .body(ByteStream::from(content))
+            .send()
+            .await;
+
+        match result {
+            Ok(_) => Ok(true),
+            Err(SdkError::ServiceError(e)) if matches!(e.raw().status().as_u16(), 409 | 412) => {
+                Ok(false)
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    fn location(&self) -> String {
+        self.prefix.location(&format!("s3://{}", self.bucket))
+    }
+
+    // Only the uploads recorded by intake are considered.
+    async fn abort_incomplete(&self, pending: &[String]) -> Result<usize, Error> {
+        let mut aborted = 0;
+
+        for record in self.list(&format!("{}/", UPLOADS)).await? {
+            let id = record.rsplit('/').next().unwrap_or_default().to_string();
+            let key = match self.get(&record).await? {
+                Some(content) => String::from_utf8_lossy(&content).to_string(),
+                None => continue,
+            };
+
+            if pending
+                .iter()
+                .any(|pending| self.prefix.join(pending) == key)
+            {
+                continue;
+            }
+
+            // The upload can be gone already, completed or aborted, with its record left behind.
+            let live = self.uploads(&key).await?;
+            if live
+                .iter()
+                .any(|(upload, upload_id)| upload == &key && upload_id == &id)
+            {
+                self.abort(&key, &id).await?;
+                aborted += 1;
+            } else {
+                self.delete(&record).await?;
+            }
+        }
+
+        Ok(aborted)
+    }
+
+    async fn exists(&self, key: &str) -> Result<bool, Error> {
+        let result = self
+            .client
+            .head_object()
+            .bucket(&self.bucket)
+            .key(self.prefix.join(key))
+            .send()
+            .await;
+
+        match result {
+            Ok(_) => Ok(true),
+            Err(SdkError::ServiceError(e)) if e.err().is_not_found() => Ok(false),
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    async fn delete(&self, key: &str) -> Result<(), Error> {
+        self.client
+            .delete_object()
+            .bucket(&self.bucket)
+            .key(self.prefix.join(key))
+            .send()
+            .await?;
+
+        Ok(())
+    }
+
+    async fn list(&self, prefix: &str) -> Result<Vec<String>, Error> {
+        let mut keys = Vec::new();
+        let mut token = None;
+
+        loop {
+            let output = self
+                .client
+                .list_objects_v2()
+                .bucket(&self.bucket)
+                .prefix(self.prefix.join(prefix))
+                .set_continuation_token(token)
+                .send()
+                .await?;
+
+            for object in output.contents() {
+                if let Some(key) = object.key() {
+                    keys.push(self.prefix.strip(key).to_string());
+                }
+            }
+
+            match output.next_continuation_token() {
+                Some(next) => token = Some(next.to_string()),
+                None => break,
+            }
+        }
+
+        Ok(keys)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::storage::{Checksum, Expeditor};
+
+    // Runs against a local MinIO with a bucket named `intake`:
+    //   docker run -p 9000:9000 minio/minio server /data
+    //   INTAKE_S3_ENDPOINT=http://localhost:9000 cargo test -- --ignored
+    #[tokio::test]
+    #[ignore]
+    async fn round_trip_against_minio() {
+        let endpoint =
+            std::env::var("INTAKE_S3_ENDPOINT").unwrap_or("http://localhost:9000".into());
+        let config = serde_yaml::from_str(&format!(
+            "
+            bucket: intake
+            prefix: tests/
+            region: us-east-1
+            endpoint: {}
+            path_style: true
+            credentials:
+              access_key_id: minioadmin
+              secret_access_key: minioadmin
+            ",
+            endpoint
+        ))
+        .unwrap();
+
+        let s3 = super::initialize(&config).await.unwrap();
+        let path = std::env::temp_dir().join("intake-s3-round-trip.parquet");
+        std::fs::write(&path, b"PAR1").unwrap();
+
+        let checksum = Checksum::of(&path).unwrap();
+        s3.upload(&path, "round-trip/file.parquet", &checksum)
+            .await
+            .unwrap();
+        assert!(s3.exists("round-trip/file.parquet").await.unwrap());
+        assert_eq!(
+            s3.list("round-trip/").await.unwrap(),
+            vec!["round-trip/file.parquet".to_string()]
+        );
+
+        s3.delete("round-trip/file.parquet").await.unwrap();
+        assert!(!s3.exists("round-trip/file.parquet").await.unwrap());
+    }
+
+    #[test]
+    fn layout_of_parts() {
+        let mib = 1024 * 1024;
+
+        assert_eq!(super::layout(40 * mib, 16 * mib), (16 * mib, 3));
+        assert_eq!(super::part_length(3, 40 * mib, 16 * mib, 3), 8 * mib);
+        assert_eq!(super::layout(32 * mib, 16 * mib), (16 * mib, 2));
+        assert_eq!(super::part_length(2, 32 * mib, 16 * mib, 2), 16 * mib);
+
+        // Parts grow to stay within 10,000 parts.
+        let (part_size, count) = super::layout(200_000 * mib, 16 * mib);
+        assert_eq!(part_size, 20 * mib);
+        assert_eq!(count, 10_000);
+    }
+}